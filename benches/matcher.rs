@@ -0,0 +1,62 @@
+//! Matching cost per row, which should stay flat as the number of rows grows
+
+#[macro_use]
+extern crate criterion;
+extern crate serde_json;
+extern crate snapper;
+
+use criterion::{Criterion, Throughput, BenchmarkId, black_box};
+use snapper::prelude::*;
+
+/// A recipe with a MATCH field picking between many patterns
+fn recipe() -> Recipe {
+    let patterns: serde_json::Map<String, serde_json::Value> = (0..50)
+        .map(|i| (format!("^kind-{}-[a-z]+$", i), serde_json::json!({ "type": "REF", "config": { "type": format!("things_{}", i) } })))
+        .collect();
+
+    serde_json::from_value(serde_json::json!({
+        "primary_key": "id",
+        "ingredients": {
+            "kind": { "type": "VALUE", "config": {} },
+            "thing_id": { "type": "MATCH", "config": {
+                "field": "kind",
+                "matcher": { "field": "kind", "on": {}, "patterns": patterns, "default": null }
+            } }
+        }
+    })).unwrap()
+}
+
+fn rows(count: usize) -> Vec<Row> {
+    (0..count)
+        .map(|i| {
+            let mut row = Row::new();
+            row.insert(String::from("kind"), FieldValue::String(format!("kind-{}-abc", i % 50)));
+            row
+        })
+        .collect()
+}
+
+fn match_rows(c: &mut Criterion) {
+    let recipe = recipe();
+    let ingredient = recipe.ingredient("thing_id").unwrap();
+    let recipes = RecipeSet::new();
+    let mut group = c.benchmark_group("match_rows");
+
+    for &count in &[1_000, 10_000, 100_000] {
+        let rows = rows(count);
+
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &rows, |b, rows| {
+            b.iter(|| {
+                for row in rows {
+                    black_box(ingredient.get_deps(&FieldValue::Int(1), row, &recipes, true));
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, match_rows);
+criterion_main!(benches);
@@ -0,0 +1,589 @@
+//! PostgreSQL adapter, with every entity type being the table of the same name
+
+use contracts::*;
+use source::RowSource;
+use sink::RowSink;
+use script::Dialect;
+use recipe::PrimaryKey;
+use tools::id_to_field_value;
+use error::{Error, Result};
+use std::vec::Vec;
+use std::string::String;
+use std::cell::RefCell;
+use std::error;
+use std::convert::TryFrom;
+use postgres::{self, Client, GenericClient, Transaction};
+use postgres::types::{ToSql, FromSql, Type, IsNull, to_sql_checked};
+use bytes::{BytesMut, BufMut};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use uuid::Uuid;
+use serde_json;
+
+type BoxError = Box<dyn error::Error + Sync + Send>;
+
+impl From<postgres::Error> for Error {
+    fn from(e: postgres::Error) -> Error {
+        match e.as_db_error() {
+            Some(db) => Error::Database(db.message().to_string()),
+            None => Error::Database(e.to_string()),
+        }
+    }
+}
+
+/// Read rows from a PostgreSQL database
+pub struct PostgresSource<'a> {
+    client: RefCell<&'a mut Client>,
+}
+
+impl<'a> PostgresSource<'a> {
+    pub fn new(client: &'a mut Client) -> PostgresSource<'a> {
+        PostgresSource { client: RefCell::new(client) }
+    }
+}
+
+impl<'a> RowSource for PostgresSource<'a> {
+    fn fetch(&self, etype: &EntityType, primary_key: &str, id: &Id) -> Result<Option<Row>> {
+        let mut conditions = Row::new();
+        conditions.insert(primary_key.to_string(), id_to_field_value(id.clone()));
+
+        Ok(select(&mut **self.client.borrow_mut(), etype, &conditions)?.into_iter().next())
+    }
+
+    fn fetch_children(&self, etype: &EntityType, foreign_keys: &Row) -> Result<Vec<Row>> {
+        select(&mut **self.client.borrow_mut(), etype, foreign_keys)
+    }
+}
+
+/// Write rows to a PostgreSQL database in a transaction, rolled back unless committed
+pub struct PostgresSink<'a> {
+    tx: Transaction<'a>,
+}
+
+impl<'a> PostgresSink<'a> {
+    /// Start a transaction to write in
+    pub fn new(client: &'a mut Client) -> Result<PostgresSink<'a>> {
+        Ok(PostgresSink { tx: client.transaction()? })
+    }
+}
+
+impl<'a> RowSink for PostgresSink<'a> {
+    fn insert(&mut self, etype: &EntityType, primary_key: Option<&str>, row: &Row) -> Result<Option<FieldValue>> {
+        let (fields, params) = params(row);
+
+        let mut sql = if fields.is_empty() {
+            format!("INSERT INTO {} DEFAULT VALUES", quote(etype))
+        } else {
+            let columns: Vec<String> = fields.iter().map(|field| quote(field)).collect();
+            let placeholders: Vec<String> = (1..=fields.len()).map(|i| format!("${}", i)).collect();
+
+            format!("INSERT INTO {} ({}) VALUES ({})", quote(etype), columns.join(", "), placeholders.join(", "))
+        };
+
+        match primary_key {
+            Some(pk) => {
+                sql.push_str(&format!(" RETURNING {}", quote(pk)));
+
+                let assigned: PgField = self.tx.query_one(sql.as_str(), &refs(&params))?.try_get(0)?;
+
+                Ok(Some(assigned.0))
+            },
+            None => {
+                self.tx.execute(sql.as_str(), &refs(&params))?;
+
+                Ok(None)
+            },
+        }
+    }
+
+    /// Whether the key column has a default, such as a serial one, or is an identity column
+    fn generates_key(&mut self, etype: &EntityType, primary_key: &str) -> Result<bool> {
+        let generated = self.tx.query_opt(
+            "SELECT a.atthasdef OR a.attidentity <> '' FROM pg_attribute a \
+             WHERE a.attrelid = to_regclass($1) AND a.attname = $2 AND a.attnum > 0 AND NOT a.attisdropped",
+            &[&quote(etype), &primary_key],
+        )?;
+
+        Ok(generated.map(|row| row.get(0)).unwrap_or(false))
+    }
+
+    fn update(&mut self, etype: &EntityType, primary_key: &PrimaryKey, id: &Id, row: &Row) -> Result<()> {
+        let key = primary_key.key_row(id).ok_or_else(|| Error::UnresolvableId(etype.clone(), id.clone()))?;
+        let (key_fields, key_params) = params(&key);
+        let (fields, mut params) = params(row);
+
+        if fields.is_empty() {
+            return Ok(());
+        }
+
+        let assignments: Vec<String> = fields.iter()
+            .enumerate()
+            .map(|(i, field)| format!("{} = ${}", quote(field), i + 1))
+            .collect();
+
+        let conditions: Vec<String> = key_fields.iter()
+            .enumerate()
+            .map(|(i, field)| format!("{} = ${}", quote(field), fields.len() + i + 1))
+            .collect();
+
+        let sql = format!("UPDATE {} SET {} WHERE {}", quote(etype), assignments.join(", "), conditions.join(" AND "));
+
+        params.extend(key_params);
+
+        match self.tx.execute(sql.as_str(), &refs(&params))? {
+            0 => Err(Error::MissingRow(etype.clone(), id.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    fn commit(self) -> Result<()> {
+        Ok(self.tx.commit()?)
+    }
+}
+
+fn quote(name: &str) -> String {
+    Dialect::Postgres.quote(name)
+}
+
+/// The fields of a row, sorted, and their values as parameters
+fn params(row: &Row) -> (Vec<&String>, Vec<PgValue>) {
+    let mut fields: Vec<&String> = row.keys().collect();
+    fields.sort();
+
+    let params = fields.iter().map(|field| PgValue(row[*field].clone())).collect();
+
+    (fields, params)
+}
+
+fn refs(params: &[PgValue]) -> Vec<&(dyn ToSql + Sync)> {
+    params.iter().map(|p| p as &(dyn ToSql + Sync)).collect()
+}
+
+/// Select the rows of a table whose fields hold the given values
+fn select<C: GenericClient>(client: &mut C, etype: &EntityType, conditions: &Row) -> Result<Vec<Row>> {
+    let (fields, params) = params(conditions);
+
+    let filter: Vec<String> = fields.iter()
+        .enumerate()
+        .map(|(i, field)| format!("{} = ${}", quote(field), i + 1))
+        .collect();
+
+    let mut sql = format!("SELECT * FROM {}", quote(etype));
+    if !filter.is_empty() {
+        sql.push_str(" WHERE ");
+        sql.push_str(&filter.join(" AND "));
+    }
+
+    let mut rows = vec![];
+
+    for found in client.query(sql.as_str(), &refs(&params))? {
+        let mut row = Row::new();
+
+        for (i, column) in found.columns().iter().enumerate() {
+            let value: PgField = found.try_get(i)?;
+
+            row.insert(column.name().to_string(), value.0);
+        }
+
+        rows.push(row);
+    }
+
+    Ok(rows)
+}
+
+/// A column value read into a field value, by the type of the column
+struct PgField(FieldValue);
+
+impl<'a> FromSql<'a> for PgField {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> ::std::result::Result<PgField, BoxError> {
+        let value = match *ty {
+            Type::BOOL => FieldValue::Bool(bool::from_sql(ty, raw)?),
+            Type::INT2 => FieldValue::Int(i64::from(i16::from_sql(ty, raw)?)),
+            Type::INT4 => FieldValue::Int(i64::from(i32::from_sql(ty, raw)?)),
+            Type::INT8 => FieldValue::Int(i64::from_sql(ty, raw)?),
+            Type::FLOAT4 => FieldValue::Float(f64::from(f32::from_sql(ty, raw)?)),
+            Type::FLOAT8 => FieldValue::Float(f64::from_sql(ty, raw)?),
+            Type::NUMERIC => FieldValue::Decimal(decode_numeric(raw)?),
+            Type::UUID => FieldValue::String(Uuid::from_sql(ty, raw)?.to_string()),
+            Type::JSON | Type::JSONB => FieldValue::Json(serde_json::Value::from_sql(ty, raw)?),
+            Type::TIMESTAMPTZ => FieldValue::Timestamp(DateTime::<Utc>::from_sql(ty, raw)?.fixed_offset()),
+            Type::TIMESTAMP => FieldValue::Timestamp(NaiveDateTime::from_sql(ty, raw)?.and_utc().fixed_offset()),
+            Type::DATE => FieldValue::String(NaiveDate::from_sql(ty, raw)?.to_string()),
+            Type::BYTEA => FieldValue::Binary(Vec::<u8>::from_sql(ty, raw)?),
+            _ if <String as FromSql>::accepts(ty) => FieldValue::String(String::from_sql(ty, raw)?),
+            _ => return Err(format!("Unsupported column type {}", ty).into()),
+        };
+
+        Ok(PgField(value))
+    }
+
+    fn from_sql_null(_ty: &Type) -> ::std::result::Result<PgField, BoxError> {
+        Ok(PgField(FieldValue::Null))
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+}
+
+/// A field value written as whatever type its column has, where it fits
+#[derive(Debug)]
+struct PgValue(FieldValue);
+
+impl ToSql for PgValue {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> ::std::result::Result<IsNull, BoxError> {
+        match (&self.0, ty) {
+            (FieldValue::Null, _) => Ok(IsNull::Yes),
+            (FieldValue::Int(v), &Type::INT2) => i16::try_from(*v)?.to_sql(ty, out),
+            (FieldValue::Int(v), &Type::INT4) => i32::try_from(*v)?.to_sql(ty, out),
+            (FieldValue::Int(v), &Type::INT8) => v.to_sql(ty, out),
+            (FieldValue::Int(v), &Type::NUMERIC) => encode_numeric(&v.to_string(), out),
+            (FieldValue::Float(v), &Type::FLOAT4) => (*v as f32).to_sql(ty, out),
+            (FieldValue::Float(v), &Type::FLOAT8) => v.to_sql(ty, out),
+            (FieldValue::Decimal(s), &Type::NUMERIC) => encode_numeric(s, out),
+            (FieldValue::Decimal(s), &Type::INT8) => s.parse::<i64>()?.to_sql(ty, out),
+            (FieldValue::String(s), &Type::UUID) => Uuid::parse_str(s)?.to_sql(ty, out),
+            (FieldValue::String(s), &Type::JSON) | (FieldValue::String(s), &Type::JSONB) => {
+                serde_json::from_str::<serde_json::Value>(s)?.to_sql(ty, out)
+            },
+            (FieldValue::Timestamp(t), &Type::TIMESTAMP) => t.naive_utc().to_sql(ty, out),
+            (FieldValue::Timestamp(t), &Type::DATE) => t.date_naive().to_sql(ty, out),
+            (FieldValue::Timestamp(t), _) => t.to_sql_checked(ty, out),
+            (FieldValue::Bool(b), _) => b.to_sql_checked(ty, out),
+            (FieldValue::Binary(bytes), _) => bytes.to_sql_checked(ty, out),
+            (FieldValue::Json(json), _) => json.to_sql_checked(ty, out),
+            (FieldValue::String(s), _) => s.to_sql_checked(ty, out),
+            (value, _) => Err(format!("Can't write {:?} into a column of type {}", value, ty).into()),
+        }
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
+
+const NUMERIC_NEGATIVE: u16 = 0x4000;
+const NUMERIC_NAN: u16 = 0xc000;
+const NUMERIC_INFINITY: u16 = 0xd000;
+const NUMERIC_NEGATIVE_INFINITY: u16 = 0xf000;
+
+/// Read a numeric in PostgreSQL's binary format, base 10000 digits, into its textual form
+fn decode_numeric(raw: &[u8]) -> ::std::result::Result<String, BoxError> {
+    let word = |i: usize| -> ::std::result::Result<u16, BoxError> {
+        raw.get(i * 2..i * 2 + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(|| "Truncated numeric".into())
+    };
+
+    let ndigits = word(0)? as usize;
+    let weight = word(1)? as i16 as i64;
+    let sign = word(2)?;
+    let dscale = word(3)? as usize;
+    let digits = (0..ndigits).map(|i| word(4 + i)).collect::<::std::result::Result<Vec<u16>, BoxError>>()?;
+    let digit = |i: i64| if i >= 0 && (i as usize) < digits.len() { digits[i as usize] } else { 0 };
+
+    match sign {
+        NUMERIC_NAN => return Ok(String::from("NaN")),
+        NUMERIC_INFINITY => return Ok(String::from("Infinity")),
+        NUMERIC_NEGATIVE_INFINITY => return Ok(String::from("-Infinity")),
+        _ => {},
+    }
+
+    let mut text = String::new();
+
+    if sign == NUMERIC_NEGATIVE {
+        text.push('-');
+    }
+
+    if weight < 0 {
+        text.push('0');
+    } else {
+        text.push_str(&digit(0).to_string());
+
+        for i in 1..=weight {
+            text.push_str(&format!("{:04}", digit(i)));
+        }
+    }
+
+    if dscale > 0 {
+        let mut fraction = String::new();
+        let mut i = weight + 1;
+
+        while fraction.len() < dscale {
+            fraction.push_str(&format!("{:04}", digit(i)));
+            i += 1;
+        }
+
+        text.push('.');
+        text.push_str(&fraction[..dscale]);
+    }
+
+    Ok(text)
+}
+
+/// Write a decimal in its textual form as a numeric in PostgreSQL's binary format
+fn encode_numeric(text: &str, out: &mut BytesMut) -> ::std::result::Result<IsNull, BoxError> {
+    let (sign, unsigned) = match text.strip_prefix('-') {
+        Some(unsigned) => (NUMERIC_NEGATIVE, unsigned),
+        None => (0, text.strip_prefix('+').unwrap_or(text)),
+    };
+
+    let (integer, fraction) = match unsigned.find('.') {
+        Some(i) => (&unsigned[..i], &unsigned[i + 1..]),
+        None => (unsigned, ""),
+    };
+
+    if integer.is_empty() && fraction.is_empty() || !(integer.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit())) {
+        return Err(format!("{:?} isn't a decimal", text).into());
+    }
+
+    // Pad both parts to whole base 10000 digits
+    let integer = format!("{}{}", "0".repeat((4 - integer.len() % 4) % 4), integer);
+    let padded = format!("{}{}{}", integer, fraction, "0".repeat((4 - fraction.len() % 4) % 4));
+
+    let mut digits: Vec<u16> = padded.as_bytes()
+        .chunks(4)
+        .map(|chunk| chunk.iter().fold(0, |n, c| n * 10 + u16::from(c - b'0')))
+        .collect();
+    let mut weight = (integer.len() / 4) as i64 - 1;
+
+    while digits.first() == Some(&0) {
+        digits.remove(0);
+        weight -= 1;
+    }
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+
+    if digits.is_empty() {
+        weight = 0;
+    }
+
+    out.put_u16(digits.len() as u16);
+    out.put_i16(weight as i16);
+    out.put_u16(if digits.is_empty() { 0 } else { sign });
+    out.put_u16(fraction.len() as u16);
+
+    for d in digits {
+        out.put_u16(d);
+    }
+
+    Ok(IsNull::No)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use recipe::RecipeSet;
+    use tools::try_field_value_to_id;
+    use book_keeper::{BookKeeper, MemoryBookKeeper};
+    use source::collect;
+    use sink::apply;
+    use postgres::NoTls;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process::{self, Command};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static SERVERS: AtomicUsize = AtomicUsize::new(0);
+
+    /// The server `SNAPPER_POSTGRES_URL` points at, or a throwaway one in a temporary directory,
+    /// each test getting a schema of its own
+    ///
+    /// Tests needing a server are ignored by default, run them with `cargo test -- --ignored`.
+    struct TestServer {
+        dir: Option<PathBuf>,
+        url: String,
+        schema: String,
+    }
+
+    impl TestServer {
+        fn start() -> TestServer {
+            let n = SERVERS.fetch_add(1, Ordering::SeqCst);
+            let schema = format!("snapper_{}_{}", process::id(), n);
+
+            if let Ok(url) = env::var("SNAPPER_POSTGRES_URL") {
+                return TestServer { dir: None, url, schema };
+            }
+
+            let dir = env::temp_dir().join(format!("snapper-pg-{}-{}", process::id(), n));
+            let data = dir.join("data");
+            let server = TestServer {
+                url: format!("host={} user=postgres dbname=postgres", dir.display()),
+                dir: Some(dir.clone()),
+                schema,
+            };
+
+            // PostgreSQL refuses to run as root, so the server is run by the postgres user instead
+            let started = fs::create_dir_all(&dir).is_ok()
+                && (!is_root() || run(Command::new("chown").arg("postgres").arg(&dir)))
+                && run(pg_command("initdb").arg("-D").arg(&data).args(["-A", "trust", "-U", "postgres"]))
+                && run(pg_command("pg_ctl").arg("-D").arg(&data).arg("-l").arg(dir.join("log"))
+                    .arg("-o").arg(format!("-k {} -c listen_addresses=''", dir.display()))
+                    .args(["-w", "start"]));
+
+            assert!(started, "Could not start PostgreSQL, set SNAPPER_POSTGRES_URL or put initdb and pg_ctl on the path");
+
+            server
+        }
+
+        fn connect(&self) -> Client {
+            let mut client = Client::connect(&self.url, NoTls).unwrap();
+
+            client.batch_execute(&format!("CREATE SCHEMA IF NOT EXISTS {0}; SET search_path TO {0}", self.schema)).unwrap();
+
+            client
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            match self.dir {
+                Some(ref dir) => {
+                    run(pg_command("pg_ctl").arg("-D").arg(dir.join("data")).args(["-m", "immediate", "stop"]));
+                    let _ = fs::remove_dir_all(dir);
+                },
+                None => {
+                    if let Ok(mut client) = Client::connect(&self.url, NoTls) {
+                        let _ = client.batch_execute(&format!("DROP SCHEMA {} CASCADE", self.schema));
+                    }
+                },
+            }
+        }
+    }
+
+    fn is_root() -> bool {
+        Command::new("id").arg("-u").output().map(|o| o.stdout.trim_ascii() == b"0").unwrap_or(false)
+    }
+
+    /// A PostgreSQL program, run as the postgres user when running as root
+    fn pg_command(program: &str) -> Command {
+        if !is_root() {
+            return Command::new(program);
+        }
+
+        let mut command = Command::new("runuser");
+        command.args(["-u", "postgres", "--", program]);
+
+        command
+    }
+
+    fn run(command: &mut Command) -> bool {
+        command.output().map(|o| o.status.success()).unwrap_or(false)
+    }
+
+    fn numeric(text: &str) -> String {
+        let mut out = BytesMut::new();
+        encode_numeric(text, &mut out).unwrap();
+
+        decode_numeric(&out).unwrap()
+    }
+
+    #[test]
+    fn it_round_trips_numerics() {
+        assert_eq!("0", numeric("0"));
+        assert_eq!("0.00", numeric("0.00"));
+        assert_eq!("12.50", numeric("12.50"));
+        assert_eq!("-0.0001", numeric("-0.0001"));
+        assert_eq!("123456789.123456789", numeric("123456789.123456789"));
+        assert_eq!("18446744073709551615", numeric("18446744073709551615"));
+        assert_eq!("10000", numeric("+10000"));
+        assert!(encode_numeric("1e5", &mut BytesMut::new()).is_err());
+        assert!(encode_numeric("-", &mut BytesMut::new()).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs PostgreSQL"]
+    fn it_maps_column_types() {
+        let server = TestServer::start();
+        let mut client = server.connect();
+
+        client.batch_execute(r#"
+            CREATE TABLE things (id uuid PRIMARY KEY DEFAULT gen_random_uuid(), count bigint, price numeric(10, 2),
+                settings jsonb, enabled bool, created_at timestamptz, data bytea, name text);
+        "#).unwrap();
+
+        let mut row = Row::new();
+        row.insert(String::from("count"), FieldValue::Int(-5));
+        row.insert(String::from("price"), FieldValue::Decimal(String::from("12.5")));
+        row.insert(String::from("settings"), FieldValue::Json(serde_json::json!({ "a": [1, null] })));
+        row.insert(String::from("enabled"), FieldValue::Bool(true));
+        row.insert(String::from("created_at"), FieldValue::Timestamp(DateTime::parse_from_rfc3339("2018-01-02T03:04:05.5+02:00").unwrap()));
+        row.insert(String::from("data"), FieldValue::Binary(vec![0, 255]));
+        row.insert(String::from("name"), FieldValue::Null);
+
+        let mut sink = PostgresSink::new(&mut client).unwrap();
+        let id = try_field_value_to_id(&sink.insert(&String::from("things"), Some("id"), &row).unwrap().unwrap()).unwrap().unwrap();
+        sink.commit().unwrap();
+
+        let thing = PostgresSource::new(&mut client).fetch(&String::from("things"), "id", &id).unwrap().unwrap();
+
+        assert!(Uuid::parse_str(&id.to_string()).is_ok());
+        assert_eq!(FieldValue::String(id.to_string()), thing["id"]);
+        assert_eq!(FieldValue::Int(-5), thing["count"]);
+        assert_eq!(FieldValue::Decimal(String::from("12.50")), thing["price"]);
+        assert_eq!(FieldValue::Json(serde_json::json!({ "a": [1, null] })), thing["settings"]);
+        assert_eq!(FieldValue::Bool(true), thing["enabled"]);
+        assert_eq!(FieldValue::Timestamp(DateTime::parse_from_rfc3339("2018-01-02T01:04:05.5Z").unwrap()), thing["created_at"]);
+        assert_eq!(FieldValue::Binary(vec![0, 255]), thing["data"]);
+        assert_eq!(FieldValue::Null, thing["name"]);
+    }
+
+    #[test]
+    #[ignore = "needs PostgreSQL"]
+    fn it_snapshots_and_applies_a_subtree() {
+        let server = TestServer::start();
+        let mut client = server.connect();
+
+        client.batch_execute(r#"
+            CREATE TABLE users (user_no bigserial PRIMARY KEY, name text);
+            CREATE TABLE posts (id bigserial PRIMARY KEY, author_no bigint REFERENCES users (user_no));
+            CREATE TABLE tags (slug text PRIMARY KEY, n bigint GENERATED ALWAYS AS IDENTITY);
+            INSERT INTO users (name) VALUES ('Foo');
+            INSERT INTO posts (author_no) VALUES (1), (1);
+        "#).unwrap();
+
+        let recipes: RecipeSet = serde_json::from_str(r#"{
+            "users": { "primary_key": "user_no", "ingredients": { "name": { "type": "VALUE", "config": {} } } },
+            "posts": { "primary_key": "id", "ingredients": { "author_no": { "type": "REF", "config": { "type": "users" } } } }
+        }"#).unwrap();
+
+        let rows = collect(&recipes, &PostgresSource::new(&mut client), String::from("users"), Id::Int(1)).unwrap();
+        let ops = ::serialize(&recipes, rows, &MemoryBookKeeper::new()).unwrap();
+
+        assert_eq!(3, ops.len());
+
+        let mut sink = PostgresSink::new(&mut client).unwrap();
+        assert!(sink.generates_key(&String::from("users"), "user_no").unwrap());
+        assert!(sink.generates_key(&String::from("tags"), "n").unwrap());
+        assert!(!sink.generates_key(&String::from("tags"), "slug").unwrap());
+        drop(sink);
+
+        let books = MemoryBookKeeper::new();
+        apply(&recipes, &ops, PostgresSink::new(&mut client).unwrap(), &books).unwrap();
+
+        let user_no = books.resolve_id(String::from("users"), ops[0].id().unwrap(), false).unwrap();
+        let copies = client.query("SELECT id FROM posts WHERE author_no = $1", &[&2i64]).unwrap();
+
+        assert_eq!(Id::Int(2), user_no);
+        assert_eq!(2, copies.len());
+
+        let mut broken = ops.clone();
+        broken.push(Operation::new(OperationKind::Insert, String::from("posts"), Some(Id::Int(9)), {
+            let mut row = Row::new();
+            row.insert(String::from("author_no"), FieldValue::String(String::from("nope")));
+            row
+        }));
+
+        assert!(matches!(
+            apply(&recipes, &broken, PostgresSink::new(&mut client).unwrap(), &MemoryBookKeeper::new()),
+            Err(Error::DanglingReference(_, _))
+        ));
+
+        let count: i64 = client.query_one("SELECT COUNT(*) FROM users", &[]).unwrap().get(0);
+
+        assert_eq!(2, count);
+    }
+}
@@ -0,0 +1,508 @@
+//! SQLite adapter, with every entity type being the table of the same name
+
+use contracts::*;
+use source::RowSource;
+use sink::RowSink;
+use recipe::PrimaryKey;
+use tools::id_to_field_value;
+use error::{Error, Result};
+use std::vec::Vec;
+use std::string::String;
+use rusqlite::{self, Connection, Transaction, params_from_iter};
+use rusqlite::types::{Value as SqliteValue, ValueRef};
+use chrono::{DateTime, NaiveDateTime, SecondsFormat};
+use serde_json;
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Database(e.to_string())
+    }
+}
+
+/// Read rows from a SQLite database
+pub struct SqliteSource<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> SqliteSource<'a> {
+    pub fn new(conn: &'a Connection) -> SqliteSource<'a> {
+        SqliteSource { conn }
+    }
+
+    /// Select the rows of a table whose fields hold the given values
+    fn select(&self, etype: &EntityType, conditions: &Row) -> Result<Vec<Row>> {
+        let mut fields: Vec<&String> = conditions.keys().collect();
+        fields.sort();
+
+        let filter: Vec<String> = fields.iter()
+            .enumerate()
+            .map(|(i, field)| format!("{} = ?{}", quote(field), i + 1))
+            .collect();
+
+        let mut sql = format!("SELECT * FROM {}", quote(etype));
+        if !filter.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&filter.join(" AND "));
+        }
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        let columns: Vec<(String, Option<String>)> = stmt.columns().iter()
+            .map(|c| (c.name().to_string(), c.decl_type().map(String::from)))
+            .collect();
+
+        let mut found = stmt.query(params_from_iter(fields.iter().map(|field| to_sqlite_value(&conditions[*field]))))?;
+        let mut rows = vec![];
+
+        while let Some(found_row) = found.next()? {
+            let mut row = Row::new();
+
+            for (i, (name, decl_type)) in columns.iter().enumerate() {
+                row.insert(name.clone(), to_field_value(found_row.get_ref(i)?, decl_type.as_deref()));
+            }
+
+            rows.push(row);
+        }
+
+        Ok(rows)
+    }
+}
+
+impl<'a> RowSource for SqliteSource<'a> {
+    fn fetch(&self, etype: &EntityType, primary_key: &str, id: &Id) -> Result<Option<Row>> {
+        let mut conditions = Row::new();
+        conditions.insert(primary_key.to_string(), id_to_field_value(id.clone()));
+
+        Ok(self.select(etype, &conditions)?.into_iter().next())
+    }
+
+    fn fetch_children(&self, etype: &EntityType, foreign_keys: &Row) -> Result<Vec<Row>> {
+        self.select(etype, foreign_keys)
+    }
+}
+
+/// Write rows to a SQLite database in a transaction, rolled back unless committed
+pub struct SqliteSink<'a> {
+    tx: Transaction<'a>,
+}
+
+impl<'a> SqliteSink<'a> {
+    /// Start a transaction to write in
+    pub fn new(conn: &'a mut Connection) -> Result<SqliteSink<'a>> {
+        Ok(SqliteSink { tx: conn.transaction()? })
+    }
+}
+
+impl<'a> RowSink for SqliteSink<'a> {
+    fn insert(&mut self, etype: &EntityType, primary_key: Option<&str>, row: &Row) -> Result<Option<FieldValue>> {
+        let mut fields: Vec<&String> = row.keys().collect();
+        fields.sort();
+
+        let mut sql = if fields.is_empty() {
+            format!("INSERT INTO {} DEFAULT VALUES", quote(etype))
+        } else {
+            let columns: Vec<String> = fields.iter().map(|field| quote(field)).collect();
+            let placeholders: Vec<String> = (1..=fields.len()).map(|i| format!("?{}", i)).collect();
+
+            format!("INSERT INTO {} ({}) VALUES ({})", quote(etype), columns.join(", "), placeholders.join(", "))
+        };
+
+        let params = params_from_iter(fields.iter().map(|field| to_sqlite_value(&row[*field])));
+
+        match primary_key {
+            Some(pk) => {
+                sql.push_str(&format!(" RETURNING {}", quote(pk)));
+
+                let assigned = self.tx.query_row(&sql, params, |r| Ok(to_field_value(r.get_ref(0)?, None)))?;
+
+                Ok(Some(assigned))
+            },
+            None => {
+                self.tx.execute(&sql, params)?;
+
+                Ok(None)
+            },
+        }
+    }
+
+    /// Whether the key column has a default or is the rowid, as an `INTEGER PRIMARY KEY` on its own is
+    fn generates_key(&mut self, etype: &EntityType, primary_key: &str) -> Result<bool> {
+        let mut stmt = self.tx.prepare(&format!("PRAGMA table_info({})", quote(etype)))?;
+        let columns = stmt.query_map([], |r| Ok((
+            r.get::<_, String>(1)?,
+            r.get::<_, String>(2)?,
+            r.get_ref(4)? != ValueRef::Null,
+            r.get::<_, i64>(5)? > 0,
+        )))?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let keys = columns.iter().filter(|(_, _, _, key)| *key).count();
+
+        Ok(columns.iter().any(|(name, decl_type, default, key)| name == primary_key
+            && (*default || *key && keys == 1 && decl_type.eq_ignore_ascii_case("INTEGER"))))
+    }
+
+    fn update(&mut self, etype: &EntityType, primary_key: &PrimaryKey, id: &Id, row: &Row) -> Result<()> {
+        let mut fields: Vec<&String> = row.keys().collect();
+        fields.sort();
+
+        if fields.is_empty() {
+            return Ok(());
+        }
+
+        let key = primary_key.key_row(id).ok_or_else(|| Error::UnresolvableId(etype.clone(), id.clone()))?;
+        let mut key_fields: Vec<&String> = key.keys().collect();
+        key_fields.sort();
+
+        let assignments: Vec<String> = fields.iter()
+            .enumerate()
+            .map(|(i, field)| format!("{} = ?{}", quote(field), i + 1))
+            .collect();
+
+        let conditions: Vec<String> = key_fields.iter()
+            .enumerate()
+            .map(|(i, field)| format!("{} = ?{}", quote(field), fields.len() + i + 1))
+            .collect();
+
+        let sql = format!("UPDATE {} SET {} WHERE {}", quote(etype), assignments.join(", "), conditions.join(" AND "));
+
+        let params = fields.iter()
+            .map(|field| to_sqlite_value(&row[*field]))
+            .chain(key_fields.iter().map(|field| to_sqlite_value(&key[*field])));
+
+        match self.tx.execute(&sql, params_from_iter(params))? {
+            0 => Err(Error::MissingRow(etype.clone(), id.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    fn commit(self) -> Result<()> {
+        Ok(self.tx.commit()?)
+    }
+}
+
+/// Quote a table or column name
+pub fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Read a SQLite value, telling booleans, timestamps, decimals and JSON from plain values by
+/// the declared type of their column
+///
+/// SQLite stores decimals as reals where it can, so reals in decimal columns are read as decimals.
+pub fn to_field_value(value: ValueRef, decl_type: Option<&str>) -> FieldValue {
+    let decl_type = decl_type.map(|t| t.to_uppercase()).unwrap_or_default();
+    let decimal = decl_type.starts_with("DECIMAL") || decl_type.starts_with("NUMERIC");
+
+    match value {
+        ValueRef::Null => FieldValue::Null,
+        ValueRef::Integer(v) if decl_type.starts_with("BOOL") => FieldValue::Bool(v != 0),
+        ValueRef::Integer(v) => FieldValue::Int(v),
+        ValueRef::Real(v) if decimal => FieldValue::Decimal(v.to_string()),
+        ValueRef::Real(v) => FieldValue::Float(v),
+        ValueRef::Text(bytes) => {
+            let text = String::from_utf8_lossy(bytes).into_owned();
+
+            if decl_type.contains("TIMESTAMP") || decl_type.contains("DATETIME") {
+                if let Some(timestamp) = parse_timestamp(&text) {
+                    return FieldValue::Timestamp(timestamp);
+                }
+            } else if decimal {
+                return FieldValue::Decimal(text);
+            } else if decl_type.starts_with("JSON") {
+                if let Ok(json) = serde_json::from_str(&text) {
+                    return FieldValue::Json(json);
+                }
+            }
+
+            FieldValue::String(text)
+        },
+        ValueRef::Blob(bytes) => FieldValue::Binary(bytes.to_vec()),
+    }
+}
+
+/// Read an RFC 3339 timestamp, or one in SQLite's own `YYYY-MM-DD HH:MM:SS` format as UTC
+fn parse_timestamp(text: &str) -> Option<DateTime<chrono::FixedOffset>> {
+    DateTime::parse_from_rfc3339(text).ok()
+        .or_else(|| NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f").ok()
+            .map(|t| t.and_utc().fixed_offset()))
+}
+
+/// The SQLite value to store a field value as
+pub fn to_sqlite_value(value: &FieldValue) -> SqliteValue {
+    match value {
+        FieldValue::Null => SqliteValue::Null,
+        FieldValue::Int(v) => SqliteValue::Integer(*v),
+        FieldValue::String(s) => SqliteValue::Text(s.clone()),
+        FieldValue::Bool(b) => SqliteValue::Integer(*b as i64),
+        FieldValue::Float(v) => SqliteValue::Real(*v),
+        FieldValue::Decimal(s) => SqliteValue::Text(s.clone()),
+        FieldValue::Binary(bytes) => SqliteValue::Blob(bytes.clone()),
+        FieldValue::Timestamp(t) => SqliteValue::Text(t.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+        FieldValue::Json(json) => SqliteValue::Text(json.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use recipe::RecipeSet;
+    use book_keeper::{MemoryBookKeeper, IdStrategy};
+    use source::collect;
+    use sink::apply;
+    use stream::{StreamSerializer, SnapshotReader};
+    use book_keeper::BookKeeper;
+
+    fn conn() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+
+        conn.execute_batch(r#"
+            CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, admin BOOLEAN, balance DECIMAL(10, 2),
+                score REAL, avatar BLOB, settings JSON, created_at DATETIME);
+            CREATE TABLE posts (id INTEGER PRIMARY KEY, author_id INTEGER REFERENCES users (id), "ti""tle" TEXT);
+            CREATE TABLE comments (id INTEGER PRIMARY KEY, post_id INTEGER REFERENCES posts (id));
+
+            INSERT INTO users VALUES (1, 'Foo', 1, '12.50', 1.5, x'00ff', '{"a":[1]}', '2018-01-02 03:04:05');
+            INSERT INTO posts VALUES (1, 1, 'First'), (2, 1, 'Second');
+            INSERT INTO comments VALUES (1, 1), (2, 1), (3, 2);
+        "#).unwrap();
+
+        conn
+    }
+
+    #[test]
+    fn it_maps_column_types() {
+        let conn = conn();
+        let user = SqliteSource::new(&conn).fetch(&String::from("users"), "id", &Id::Int(1)).unwrap().unwrap();
+
+        assert_eq!(FieldValue::Int(1), user["id"]);
+        assert_eq!(FieldValue::String(String::from("Foo")), user["name"]);
+        assert_eq!(FieldValue::Bool(true), user["admin"]);
+        assert_eq!(FieldValue::Decimal(String::from("12.5")), user["balance"]);
+        assert_eq!(FieldValue::Float(1.5), user["score"]);
+        assert_eq!(FieldValue::Binary(vec![0, 255]), user["avatar"]);
+        assert_eq!(FieldValue::Json(serde_json::json!({ "a": [1] })), user["settings"]);
+        assert_eq!(FieldValue::Timestamp(DateTime::parse_from_rfc3339("2018-01-02T03:04:05Z").unwrap()), user["created_at"]);
+    }
+
+    #[test]
+    fn it_fetches_rows_by_id_and_foreign_key() {
+        let conn = conn();
+        let source = SqliteSource::new(&conn);
+
+        assert_eq!(None, source.fetch(&String::from("posts"), "id", &Id::Int(3)).unwrap());
+        assert_eq!(
+            Some(&FieldValue::String(String::from("First"))),
+            source.fetch(&String::from("posts"), "id", &Id::Int(1)).unwrap().unwrap().get("ti\"tle")
+        );
+
+        let mut foreign_keys = Row::new();
+        foreign_keys.insert(String::from("post_id"), FieldValue::Int(1));
+
+        assert_eq!(2, source.fetch_children(&String::from("comments"), &foreign_keys).unwrap().len());
+        assert!(matches!(source.fetch_children(&String::from("nopes"), &foreign_keys), Err(Error::Database(_))));
+    }
+
+    fn recipes() -> RecipeSet {
+        serde_json::from_str(r#"{
+            "users": { "primary_key": "id", "ingredients": { "name": { "type": "VALUE", "config": {} } } },
+            "posts": { "primary_key": "id", "ingredients": { "author_id": { "type": "REF", "config": { "type": "users" } } } },
+            "comments": { "primary_key": "id", "ingredients": { "post_id": { "type": "REF", "config": { "type": "posts" } } } }
+        }"#).unwrap()
+    }
+
+    #[test]
+    fn it_snapshots_a_subtree() {
+        let recipes = recipes();
+        let conn = conn();
+        let rows = collect(&recipes, &SqliteSource::new(&conn), String::from("posts"), Id::Int(1)).unwrap();
+        let ops = ::serialize(&recipes, rows, &MemoryBookKeeper::new()).unwrap();
+
+        let types: Vec<&str> = ops.iter().map(|op| op.type_().as_str()).collect();
+
+        assert_eq!(vec!["users", "posts", "comments", "comments"], types);
+    }
+
+    #[test]
+    fn it_applies_a_snapshot_in_a_transaction() {
+        let recipes = recipes();
+        let conn = conn();
+        let rows = collect(&recipes, &SqliteSource::new(&conn), String::from("posts"), Id::Int(2)).unwrap();
+        let ops = ::serialize(&recipes, rows, &MemoryBookKeeper::new()).unwrap();
+
+        let mut target = conn;
+        let books = MemoryBookKeeper::new();
+        apply(&recipes, &ops, SqliteSink::new(&mut target).unwrap(), &books).unwrap();
+
+        let user_id = books.resolve_id(String::from("users"), ops[0].id().unwrap(), false).unwrap();
+        let post_id = books.resolve_id(String::from("posts"), ops[1].id().unwrap(), false).unwrap();
+
+        assert_eq!(Id::Int(2), user_id);
+        assert_eq!(Id::Int(3), post_id);
+
+        let source = SqliteSource::new(&target);
+        let post = source.fetch(&String::from("posts"), "id", &post_id).unwrap().unwrap();
+
+        assert_eq!(FieldValue::Int(2), post["author_id"]);
+        assert_eq!(FieldValue::Null, post["ti\"tle"]);
+        assert_eq!(Some(FieldValue::String(String::from("Foo"))), source.fetch(&String::from("users"), "id", &user_id).unwrap().map(|u| u["name"].clone()));
+    }
+
+    #[test]
+    fn it_rolls_back_on_failure() {
+        let recipes = recipes();
+        let mut conn = conn();
+
+        let mut name = Row::new();
+        name.insert(String::from("name"), FieldValue::String(String::from("Bar")));
+
+        let ops = vec![
+            Operation::new(OperationKind::Insert, String::from("users"), Some(Id::Int(1)), name.clone()),
+            Operation::new(OperationKind::Update, String::from("users"), Some(Id::Int(9)), name),
+        ];
+
+        let books = MemoryBookKeeper::new();
+        books.register(String::from("users"), Id::Int(9), Id::Int(9));
+
+        assert_eq!(
+            Err(Error::MissingRow(String::from("users"), Id::Int(9))),
+            apply(&recipes, &ops, SqliteSink::new(&mut conn).unwrap(), &books)
+        );
+
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM users", [], |r| r.get(0)).unwrap();
+
+        assert_eq!(1, count);
+    }
+
+    #[test]
+    fn it_snapshots_and_applies_rows_with_composite_keys() {
+        let recipes: RecipeSet = serde_json::from_str(r#"{
+            "users": { "primary_key": "id", "ingredients": { "name": { "type": "VALUE", "config": {} } } },
+            "posts": { "primary_key": "id", "ingredients": { "author_id": { "type": "REF", "config": { "type": "users" } } } },
+            "follows": { "primary_key": ["user_id", "post_id"], "ingredients": {
+                "user_id": { "type": "REF", "config": { "type": "users" } },
+                "post_id": { "type": "REF", "config": { "type": "posts" } },
+                "muted": { "type": "VALUE", "config": {} }
+            } }
+        }"#).unwrap();
+
+        let mut conn = conn();
+        conn.execute_batch(r#"
+            CREATE TABLE follows (user_id INTEGER, post_id INTEGER, muted BOOLEAN, PRIMARY KEY (user_id, post_id));
+            INSERT INTO follows VALUES (1, 1, 0), (1, 2, 1);
+        "#).unwrap();
+
+        let rows = collect(&recipes, &SqliteSource::new(&conn), String::from("users"), Id::Int(1)).unwrap();
+        let ops = ::serialize(&recipes, rows, &MemoryBookKeeper::new()).unwrap();
+
+        assert_eq!(5, ops.len());
+
+        apply(&recipes, &ops, SqliteSink::new(&mut conn).unwrap(), &MemoryBookKeeper::new()).unwrap();
+
+        let mut key = Row::new();
+        key.insert(String::from("user_id"), FieldValue::Int(2));
+
+        let mut follows = SqliteSource::new(&conn).fetch_children(&String::from("follows"), &key).unwrap();
+        follows.sort_by_key(|f| format!("{:?}", f["post_id"]));
+
+        assert_eq!(2, follows.len());
+        assert_eq!(FieldValue::Int(3), follows[0]["post_id"]);
+        assert_eq!(FieldValue::Bool(true), follows[1]["muted"]);
+
+        let mut muted = Row::new();
+        muted.insert(String::from("muted"), FieldValue::Bool(true));
+
+        let books = MemoryBookKeeper::new();
+        books.register(String::from("users"), Id::Int(2), Id::Int(2));
+        books.register(String::from("posts"), Id::Int(3), Id::Int(3));
+        books.register(String::from("posts"), Id::Int(1), Id::Int(1));
+
+        let update = |post_id| Operation::new(OperationKind::Update, String::from("follows"), Some(Id::Composite(vec![Id::Int(2), Id::Int(post_id)])), muted.clone());
+
+        apply(&recipes, &[update(3)], SqliteSink::new(&mut conn).unwrap(), &books).unwrap();
+
+        let muted: bool = conn.query_row("SELECT muted FROM follows WHERE user_id = 2 AND post_id = 3", [], |r| r.get(0)).unwrap();
+
+        assert!(muted);
+        assert_eq!(
+            Err(Error::MissingRow(String::from("follows"), Id::Composite(vec![Id::Int(2), Id::Int(1)]))),
+            apply(&recipes, &[update(1)], SqliteSink::new(&mut conn).unwrap(), &books)
+        );
+    }
+
+    #[test]
+    fn it_applies_rows_keyed_by_text_the_database_doesnt_generate() {
+        let recipes: RecipeSet = serde_json::from_str(r#"{
+            "tags": { "primary_key": "slug", "id_kind": "string", "ingredients": { "name": { "type": "VALUE", "config": {} } } },
+            "labels": { "primary_key": "id", "ingredients": { "tag_slug": { "type": "REF", "config": { "type": "tags" } } } }
+        }"#).unwrap();
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(r#"
+            CREATE TABLE tags (slug TEXT PRIMARY KEY, name TEXT);
+            CREATE TABLE labels (id INTEGER PRIMARY KEY, tag_slug TEXT REFERENCES tags (slug));
+
+            INSERT INTO tags VALUES ('red', 'Red');
+            INSERT INTO labels VALUES (1, 'red');
+        "#).unwrap();
+
+        let rows = collect(&recipes, &SqliteSource::new(&conn), String::from("tags"), Id::String(String::from("red"))).unwrap();
+        let ops = ::serialize(&recipes, rows, &MemoryBookKeeper::new()).unwrap();
+
+        let mut sink = SqliteSink::new(&mut conn).unwrap();
+        assert!(!sink.generates_key(&String::from("tags"), "slug").unwrap());
+        assert!(sink.generates_key(&String::from("labels"), "id").unwrap());
+        drop(sink);
+
+        // Random ids are written where the database has none to give
+        let books = MemoryBookKeeper::new();
+        apply(&recipes, &ops, SqliteSink::new(&mut conn).unwrap(), &books).unwrap();
+
+        let slug = books.resolve_id(String::from("tags"), ops[0].id().unwrap(), false).unwrap();
+        let label: String = conn.query_row("SELECT tag_slug FROM labels WHERE id = 2", [], |r| r.get(0)).unwrap();
+
+        assert_eq!(slug.to_string(), label);
+        assert_ne!("red", label);
+
+        // Preserved ids are written as they are, even where the database would assign them
+        let ops = ::serialize(&recipes, collect(&recipes, &SqliteSource::new(&conn), String::from("tags"), slug).unwrap(), &MemoryBookKeeper::with_strategy(IdStrategy::Preserve)).unwrap();
+        conn.execute_batch("DELETE FROM labels; DELETE FROM tags;").unwrap();
+
+        apply(&recipes, &ops, SqliteSink::new(&mut conn).unwrap(), &MemoryBookKeeper::with_strategy(IdStrategy::Preserve)).unwrap();
+
+        let labels: Vec<(i64, String)> = conn.prepare("SELECT id, tag_slug FROM labels").unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+
+        assert_eq!(vec![(2, label)], labels);
+    }
+
+    #[test]
+    fn it_applies_streamed_rows_referring_to_themselves() {
+        let recipes: RecipeSet = serde_json::from_str(r#"{
+            "nodes": { "primary_key": "id", "ingredients": { "next_id": { "type": "CIRCULAR", "config": {
+                "ingredient": { "type": "REF", "config": { "type": "nodes" } },
+                "fallback": { "type": "RAW", "config": { "value": null } }
+            } } } }
+        }"#).unwrap();
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(r#"
+            CREATE TABLE nodes (id INTEGER PRIMARY KEY, next_id INTEGER REFERENCES nodes (id));
+            INSERT INTO nodes VALUES (1, 1);
+        "#).unwrap();
+
+        let node = SqliteSource::new(&conn).fetch(&String::from("nodes"), "id", &Id::Int(1)).unwrap().unwrap();
+
+        let books = MemoryBookKeeper::new();
+        let mut s = StreamSerializer::new(&recipes, &books, vec![]).unwrap();
+        s.add(String::from("nodes"), node).unwrap();
+
+        let written = s.finish().unwrap();
+        let ops: Vec<Operation> = SnapshotReader::new(&written[..]).unwrap().map(|op| op.unwrap()).collect();
+
+        apply(&recipes, &ops, SqliteSink::new(&mut conn).unwrap(), &MemoryBookKeeper::new()).unwrap();
+
+        let copy = SqliteSource::new(&conn).fetch(&String::from("nodes"), "id", &Id::Int(2)).unwrap().unwrap();
+
+        assert_eq!(FieldValue::Int(2), copy["next_id"]);
+    }
+}
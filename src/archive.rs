@@ -0,0 +1,356 @@
+//! Compressed snapshot archives
+//!
+//! An archive holds a streamed snapshot, as written by `SnapshotWriter`, split into deflated
+//! blocks. Every block is preceded by its compressed length, its length and a CRC32 of its
+//! content. A zero length ends the blocks and is followed by a SHA-256 digest of the whole
+//! content:
+//!
+//! ```text
+//! "SNAPARC1" (u32 compressed, u32 length, u32 crc32, deflated bytes)* u32 0, [u8; 32] sha256
+//! ```
+//!
+//! All integers are little endian.
+
+use recipe::RecipeSet;
+use stream::{SnapshotWriter, SnapshotReader};
+use error::{Error, Result};
+use std::vec::Vec;
+use std::io::{self, Read, Write, Seek, SeekFrom, BufReader};
+use flate2::Compression;
+use flate2::write::DeflateEncoder;
+use flate2::read::DeflateDecoder;
+use crc32fast;
+use sha2::{Sha256, Digest};
+
+pub const MAGIC: &[u8; 8] = b"SNAPARC1";
+
+/// The default amount of content per block
+pub const BLOCK_SIZE: usize = 64 * 1024;
+
+/// The largest amount of content per block, anything larger is refused as corrupt when reading
+pub const MAX_BLOCK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Start writing a streamed snapshot into an archive
+///
+/// Finish the snapshot writer first, then the archive writer it hands back.
+pub fn create<W: Write>(writer: W, recipes: &RecipeSet) -> Result<SnapshotWriter<ArchiveWriter<W>>> {
+    SnapshotWriter::new(ArchiveWriter::new(writer)?, recipes)
+}
+
+/// Open an archived snapshot for reading
+///
+/// The whole archive is checked before anything is returned, so a damaged archive is refused
+/// before a single operation is read from it.
+pub fn open<R: Read + Seek>(mut reader: R) -> Result<SnapshotReader<BufReader<ArchiveReader<R>>>> {
+    let start = reader.stream_position()?;
+
+    verify(&mut reader)?;
+
+    reader.seek(SeekFrom::Start(start))?;
+
+    SnapshotReader::new(BufReader::new(ArchiveReader::new(reader)?))
+}
+
+/// Check every block and the digest of an archive
+pub fn verify<R: Read>(reader: R) -> Result<()> {
+    io::copy(&mut ArchiveReader::new(reader)?, &mut io::sink())?;
+
+    Ok(())
+}
+
+/// Compress everything written into checksummed blocks
+pub struct ArchiveWriter<W: Write> {
+    writer: W,
+    block_size: usize,
+    buffer: Vec<u8>,
+    digest: Sha256,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(writer: W) -> Result<ArchiveWriter<W>> {
+        ArchiveWriter::with_block_size(writer, BLOCK_SIZE)
+    }
+
+    /// Write blocks of the given size, which is kept between a single byte and `MAX_BLOCK_SIZE`
+    pub fn with_block_size(mut writer: W, block_size: usize) -> Result<ArchiveWriter<W>> {
+        let block_size = block_size.clamp(1, MAX_BLOCK_SIZE);
+
+        writer.write_all(MAGIC)?;
+
+        Ok(ArchiveWriter {
+            writer,
+            block_size,
+            buffer: Vec::with_capacity(block_size),
+            digest: Sha256::new(),
+        })
+    }
+
+    /// Write the last block and the digest, and hand back the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        if !self.buffer.is_empty() {
+            let content = self.buffer.split_off(0);
+            self.write_block(&content)?;
+        }
+
+        self.writer.write_all(&0u32.to_le_bytes())?;
+        self.writer.write_all(&self.digest.finalize_reset())?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    fn write_block(&mut self, content: &[u8]) -> io::Result<()> {
+        let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+        encoder.write_all(content)?;
+        let compressed = encoder.finish()?;
+
+        self.writer.write_all(&(compressed.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&(content.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&crc32fast::hash(content).to_le_bytes())?;
+        self.writer.write_all(&compressed)?;
+        self.digest.update(content);
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ArchiveWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+
+        while self.buffer.len() >= self.block_size {
+            let rest = self.buffer.split_off(self.block_size);
+            let content = std::mem::replace(&mut self.buffer, rest);
+
+            self.write_block(&content)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    /// Blocks are only written once full, see `finish`
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Decompress an archive, checking every block as it is read and the digest at the end
+///
+/// Damage is reported as an `io::Error` wrapping the precise `Error`.
+pub struct ArchiveReader<R: Read> {
+    reader: R,
+    block: Vec<u8>,
+    position: usize,
+    index: usize,
+    digest: Sha256,
+    done: bool,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(mut reader: R) -> Result<ArchiveReader<R>> {
+        let mut magic = [0u8; 8];
+
+        if reader.read_exact(&mut magic).is_err() || &magic != MAGIC {
+            return Err(Error::NotAnArchive);
+        }
+
+        Ok(ArchiveReader {
+            reader,
+            block: vec![],
+            position: 0,
+            index: 0,
+            digest: Sha256::new(),
+            done: false,
+        })
+    }
+
+    /// Read and check the next block, or the digest after the last one
+    fn next_block(&mut self) -> Result<()> {
+        let compressed_len = self.read_u32()?;
+
+        if compressed_len == 0 {
+            let mut expected = [0u8; 32];
+            self.read_exact(&mut expected)?;
+
+            if self.digest.finalize_reset()[..] != expected[..] {
+                return Err(Error::DigestMismatch);
+            }
+
+            self.done = true;
+            self.block.clear();
+            self.position = 0;
+
+            return Ok(());
+        }
+
+        let len = self.read_u32()? as usize;
+        let crc = self.read_u32()?;
+
+        // Refused before anything is allocated, deflating never grows content by more than a bit
+        if len > MAX_BLOCK_SIZE || compressed_len as usize > len + len / 16 + 64 {
+            return Err(Error::CorruptBlock(self.index));
+        }
+
+        let mut compressed = vec![0u8; compressed_len as usize];
+        self.read_exact(&mut compressed)?;
+
+        let mut content = Vec::with_capacity(len);
+        DeflateDecoder::new(&compressed[..])
+            .take(len as u64 + 1)
+            .read_to_end(&mut content)
+            .map_err(|_| Error::CorruptBlock(self.index))?;
+
+        if content.len() != len || crc32fast::hash(&content) != crc {
+            return Err(Error::CorruptBlock(self.index));
+        }
+
+        self.digest.update(&content);
+        self.block = content;
+        self.position = 0;
+        self.index += 1;
+
+        Ok(())
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let mut bytes = [0u8; 4];
+        self.read_exact(&mut bytes)?;
+
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// Read exactly enough bytes, running out means the archive is truncated
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        let index = self.index;
+
+        self.reader.read_exact(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => Error::TruncatedArchive(index),
+            _ => Error::from(e),
+        })
+    }
+}
+
+impl<R: Read> Read for ArchiveReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.block.len() && !self.done {
+            self.next_block().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+
+        let n = (self.block.len() - self.position).min(buf.len());
+        buf[..n].copy_from_slice(&self.block[self.position..self.position + n]);
+        self.position += n;
+
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use contracts::*;
+    use recipe::{Recipe, PrimaryKey, Ingredient};
+    use ingredients::value::Value;
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    fn recipes() -> RecipeSet {
+        let mut ingredients = HashMap::new();
+        ingredients.insert(String::from("name"), Ingredient::Value(Value::new()));
+
+        let mut recipes = RecipeSet::new();
+        recipes.add(String::from("foos"), Recipe::new(PrimaryKey::String(String::from("id")), ingredients));
+
+        recipes
+    }
+
+    fn op(i: u64) -> Operation {
+        let mut row = Row::new();
+        row.insert(String::from("id"), FieldValue::Int(i as i64));
+        row.insert(String::from("name"), FieldValue::String(format!("Foo {}", i)));
+
+        Operation::new(OperationKind::Insert, String::from("foos"), Some(Id::Int(i)), row)
+    }
+
+    /// An archive of 100 operations in blocks of 256 bytes
+    fn archive() -> Vec<u8> {
+        let recipes = recipes();
+        let mut w = SnapshotWriter::new(ArchiveWriter::with_block_size(vec![], 256).unwrap(), &recipes).unwrap();
+
+        for i in 0..100 {
+            w.write(&op(i)).unwrap();
+        }
+
+        w.finish().unwrap().finish().unwrap()
+    }
+
+    /// Where the compressed content of the given block starts
+    fn block_offset(archive: &[u8], block: usize) -> usize {
+        let mut offset = MAGIC.len();
+
+        for _ in 0..block {
+            let mut len = [0u8; 4];
+            len.copy_from_slice(&archive[offset..offset + 4]);
+            offset += 12 + u32::from_le_bytes(len) as usize;
+        }
+
+        offset + 12
+    }
+
+    #[test]
+    fn it_round_trips() {
+        let reader = open(Cursor::new(archive())).unwrap();
+
+        assert!(reader.recipes().contains("foos"));
+
+        let ops: Vec<Operation> = reader.map(|op| op.unwrap()).collect();
+
+        assert_eq!(100, ops.len());
+        assert_eq!(op(42), ops[42]);
+    }
+
+    #[test]
+    fn it_refuses_truncated_archives() {
+        let mut archive = archive();
+        let len = archive.len();
+        archive.truncate(len - 40);
+
+        assert!(matches!(open(Cursor::new(archive)).err(), Some(Error::TruncatedArchive(_))));
+    }
+
+    #[test]
+    fn it_names_the_corrupt_block() {
+        let mut archive = archive();
+        let offset = block_offset(&archive, 2);
+        archive[offset + 3] ^= 0xff;
+
+        assert_eq!(Some(Error::CorruptBlock(2)), open(Cursor::new(archive)).err());
+    }
+
+    #[test]
+    fn it_refuses_blocks_larger_than_allowed() {
+        let mut too_long = archive();
+        let offset = block_offset(&too_long, 1);
+        too_long[offset - 8..offset - 4].copy_from_slice(&(MAX_BLOCK_SIZE as u32 + 1).to_le_bytes());
+
+        let mut too_compressed = archive();
+        let offset = block_offset(&too_compressed, 1);
+        too_compressed[offset - 12..offset - 8].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        assert_eq!(Some(Error::CorruptBlock(1)), open(Cursor::new(too_long)).err());
+        assert_eq!(Some(Error::CorruptBlock(1)), open(Cursor::new(too_compressed)).err());
+    }
+
+    #[test]
+    fn it_refuses_archives_not_matching_their_digest() {
+        let mut archive = archive();
+        let len = archive.len();
+        archive[len - 1] ^= 0xff;
+
+        assert_eq!(Some(Error::DigestMismatch), open(Cursor::new(archive)).err());
+    }
+
+    #[test]
+    fn it_refuses_other_input() {
+        assert_eq!(Some(Error::NotAnArchive), open(Cursor::new(b"{}".to_vec())).err());
+    }
+}
@@ -1,9 +1,257 @@
 use contracts::*;
+use uuid::Uuid;
+use serde_json;
+use std::cell::RefCell;
+use std::collections::HashMap;
 
 pub trait BookKeeper {
     /// Find or create an id associated with the given type and id
     fn resolve_id(&self, etype: EntityType, id: Id, authoritative: bool) -> Option<Id>;
 
+    /// Map the components of a composite id of the given type, given the type of the row each
+    /// component refers to
+    ///
+    /// Components referring to a row must resolve to an id already in the books, the others are
+    /// kept as they are. A composite id is never created, it follows from its components, but
+    /// it is registered so that references to the row resolve to it.
+    fn resolve_components(&self, etype: EntityType, components: Vec<(Option<EntityType>, Id)>) -> Option<Id> {
+        let id = Id::Composite(components.iter().map(|(_, id)| id.clone()).collect());
+
+        let resolved = components.into_iter()
+            .map(|(etype, id)| match etype {
+                Some(etype) => self.resolve_id(etype, id, false),
+                None => Some(id),
+            })
+            .collect::<Option<Vec<Id>>>()
+            .map(Id::Composite)?;
+
+        self.register(etype, id, resolved.clone());
+
+        Some(resolved)
+    }
+
+    /// Whether the ids the books create are to be written as the keys of new rows, rather than
+    /// left to the database to assign
+    fn keeps_ids(&self) -> bool {
+        false
+    }
+
+    /// Record a known mapping, such as an id assigned by the database on insert
+    ///
+    /// Books that only ever look ids up, and never learn new ones, can leave this out.
+    fn register(&self, _etype: EntityType, _id: Id, _resolved: Id) {}
+
     /// Reset the BookKeeper's internal state
     fn reset(&mut self);
-}
\ No newline at end of file
+}
+
+/// How a BookKeeper creates the id of a row it hasn't seen before
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum IdStrategy {
+    /// A random UUID
+    #[default]
+    UuidV4,
+    /// A UUID derived from the type and id of the row within the namespace, so that the same
+    /// rows get the same ids in every serialization
+    UuidV5(Uuid),
+    /// Integers counting up from 1, separately for every type
+    Sequential,
+    /// The id the row already has
+    Preserve,
+}
+
+/// A BookKeeper keeping its books in memory, creating ids for unseen rows by its strategy
+#[derive(Debug, Default)]
+pub struct MemoryBookKeeper {
+    books: RefCell<HashMap<Dep, Id>>,
+    strategy: IdStrategy,
+    /// The last sequential id handed out for every type
+    sequences: RefCell<HashMap<EntityType, u64>>,
+}
+
+impl MemoryBookKeeper {
+    /// Books handing out random UUIDs
+    pub fn new() -> MemoryBookKeeper {
+        MemoryBookKeeper::with_strategy(IdStrategy::UuidV4)
+    }
+
+    pub fn with_strategy(strategy: IdStrategy) -> MemoryBookKeeper {
+        MemoryBookKeeper {
+            books: RefCell::new(HashMap::new()),
+            strategy,
+            sequences: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Create an id for a row by the strategy
+    fn create(&self, etype: &EntityType, id: &Id) -> Id {
+        match &self.strategy {
+            IdStrategy::UuidV4 => Id::Uuid(Uuid::new_v4().to_string()),
+            IdStrategy::UuidV5(namespace) => {
+                // Tell the integer 1 from the text "1" by naming the id in its JSON form
+                let name = format!("{}:{}", etype, serde_json::to_string(id).unwrap_or_default());
+
+                Id::Uuid(Uuid::new_v5(namespace, name.as_bytes()).to_string())
+            },
+            IdStrategy::Sequential => {
+                let mut sequences = self.sequences.borrow_mut();
+                let next = sequences.entry(etype.clone()).or_insert(0);
+                *next += 1;
+
+                Id::Int(*next)
+            },
+            IdStrategy::Preserve => id.clone(),
+        }
+    }
+
+    /// Number of ids in the books
+    pub fn len(&self) -> usize {
+        self.books.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.books.borrow().is_empty()
+    }
+}
+
+impl BookKeeper for MemoryBookKeeper {
+    /// Look up the id, creating a new one for it only if authoritative
+    fn resolve_id(&self, etype: EntityType, id: Id, authoritative: bool) -> Option<Id> {
+        let key = (etype, id);
+
+        if let Some(resolved) = self.books.borrow().get(&key) {
+            return Some(resolved.clone());
+        }
+
+        if !authoritative {
+            return None;
+        }
+
+        let resolved = self.create(&key.0, &key.1);
+
+        self.books.borrow_mut().insert(key, resolved.clone());
+
+        Some(resolved)
+    }
+
+    /// Preserved and derived ids are kept, random and sequential ones are left to the database
+    fn keeps_ids(&self) -> bool {
+        matches!(self.strategy, IdStrategy::UuidV5(_) | IdStrategy::Preserve)
+    }
+
+    fn register(&self, etype: EntityType, id: Id, resolved: Id) {
+        self.books.borrow_mut().insert((etype, id), resolved);
+    }
+
+    /// Forget all ids, starting sequences over
+    fn reset(&mut self) {
+        self.books.get_mut().clear();
+        self.sequences.get_mut().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_creates_ids_when_authoritative() {
+        let b = MemoryBookKeeper::new();
+
+        let created = b.resolve_id(String::from("foos"), Id::Int(1), true);
+
+        match created {
+            Some(Id::Uuid(ref s)) => assert!(Uuid::parse_str(s).is_ok()),
+            _ => panic!("Expected a UUID, got {:?}", created),
+        }
+
+        assert_eq!(created, b.resolve_id(String::from("foos"), Id::Int(1), false));
+        assert_eq!(created, b.resolve_id(String::from("foos"), Id::Int(1), true));
+        assert_ne!(created, b.resolve_id(String::from("foos"), Id::Int(2), true));
+        assert_ne!(created, b.resolve_id(String::from("bars"), Id::Int(1), true));
+    }
+
+    #[test]
+    fn it_only_looks_up_when_not_authoritative() {
+        let b = MemoryBookKeeper::new();
+
+        assert_eq!(None, b.resolve_id(String::from("foos"), Id::Int(1), false));
+        assert!(b.is_empty());
+    }
+
+    #[test]
+    fn it_resolves_registered_ids() {
+        let b = MemoryBookKeeper::new();
+
+        b.register(String::from("foos"), Id::Uuid(String::from("abc")), Id::Int(123));
+
+        assert_eq!(Some(Id::Int(123)), b.resolve_id(String::from("foos"), Id::Uuid(String::from("abc")), false));
+    }
+
+    #[test]
+    fn it_resolves_composite_ids_component_wise() {
+        let b = MemoryBookKeeper::new();
+
+        b.register(String::from("users"), Id::Int(1), Id::Int(10));
+
+        assert_eq!(
+            Some(Id::Composite(vec![Id::Int(10), Id::Int(2)])),
+            b.resolve_components(String::from("members"), vec![(Some(String::from("users")), Id::Int(1)), (None, Id::Int(2))])
+        );
+        assert_eq!(None, b.resolve_components(String::from("members"), vec![(Some(String::from("users")), Id::Int(2)), (None, Id::Int(2))]));
+        assert_eq!(
+            Some(Id::Composite(vec![Id::Int(10), Id::Int(2)])),
+            b.resolve_id(String::from("members"), Id::Composite(vec![Id::Int(1), Id::Int(2)]), false)
+        );
+        assert_eq!(2, b.len());
+    }
+
+    #[test]
+    fn it_derives_the_same_uuids_from_the_same_rows() {
+        let namespace = Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap();
+        let a = MemoryBookKeeper::with_strategy(IdStrategy::UuidV5(namespace));
+        let b = MemoryBookKeeper::with_strategy(IdStrategy::UuidV5(namespace));
+        let c = MemoryBookKeeper::with_strategy(IdStrategy::UuidV5(Uuid::nil()));
+
+        let created = a.resolve_id(String::from("foos"), Id::Int(1), true);
+
+        assert!(matches!(created, Some(Id::Uuid(_))));
+        assert_eq!(created, b.resolve_id(String::from("foos"), Id::Int(1), true));
+        assert_ne!(created, c.resolve_id(String::from("foos"), Id::Int(1), true));
+        assert_ne!(created, a.resolve_id(String::from("bars"), Id::Int(1), true));
+        assert_ne!(created, a.resolve_id(String::from("foos"), Id::String(String::from("1")), true));
+    }
+
+    #[test]
+    fn it_counts_up_per_type() {
+        let mut b = MemoryBookKeeper::with_strategy(IdStrategy::Sequential);
+
+        assert_eq!(Some(Id::Int(1)), b.resolve_id(String::from("foos"), Id::Int(10), true));
+        assert_eq!(Some(Id::Int(2)), b.resolve_id(String::from("foos"), Id::Int(5), true));
+        assert_eq!(Some(Id::Int(1)), b.resolve_id(String::from("bars"), Id::Int(10), true));
+        assert_eq!(Some(Id::Int(1)), b.resolve_id(String::from("foos"), Id::Int(10), true));
+
+        b.reset();
+
+        assert_eq!(Some(Id::Int(1)), b.resolve_id(String::from("foos"), Id::Int(5), true));
+    }
+
+    #[test]
+    fn it_preserves_original_ids() {
+        let b = MemoryBookKeeper::with_strategy(IdStrategy::Preserve);
+
+        assert_eq!(Some(Id::Signed(-3)), b.resolve_id(String::from("foos"), Id::Signed(-3), true));
+        assert_eq!(None, b.resolve_id(String::from("foos"), Id::Int(4), false));
+    }
+
+    #[test]
+    fn it_resets() {
+        let mut b = MemoryBookKeeper::new();
+
+        b.resolve_id(String::from("foos"), Id::Int(1), true);
+        b.reset();
+
+        assert_eq!(0, b.len());
+        assert_eq!(None, b.resolve_id(String::from("foos"), Id::Int(1), false));
+    }
+}
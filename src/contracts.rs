@@ -3,20 +3,450 @@ extern crate serde_json;
 use std::string::String;
 use std::vec::Vec;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::fmt;
+use serde::ser::{Serialize, Serializer, SerializeMap};
+use serde::de::{self, Deserialize, Deserializer, Visitor, MapAccess, SeqAccess};
+use chrono::{DateTime, FixedOffset, SecondsFormat};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use uuid::Uuid;
 
-#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Eq, Hash)]
+/// A column value
+///
+/// Plain JSON values are written as-is, the others as a single-key object naming the kind of
+/// value, such as `{ "$decimal": "12.50" }`.
+#[derive(Debug, Clone)]
 pub enum FieldValue {
     Null,
     Int(i64),
     String(String),
+    Bool(bool),
+    /// Compared and hashed by its bits, so that it can be used as a map key
+    Float(f64),
+    /// An exact decimal number in its textual form
+    Decimal(String),
+    Binary(Vec<u8>),
+    Timestamp(DateTime<FixedOffset>),
+    Json(serde_json::Value),
+}
+
+impl PartialEq for FieldValue {
+    fn eq(&self, other: &FieldValue) -> bool {
+        match (self, other) {
+            (FieldValue::Null, FieldValue::Null) => true,
+            (FieldValue::Int(a), FieldValue::Int(b)) => a == b,
+            (FieldValue::String(a), FieldValue::String(b)) => a == b,
+            (FieldValue::Bool(a), FieldValue::Bool(b)) => a == b,
+            (FieldValue::Float(a), FieldValue::Float(b)) => a.to_bits() == b.to_bits(),
+            (FieldValue::Decimal(a), FieldValue::Decimal(b)) => a == b,
+            (FieldValue::Binary(a), FieldValue::Binary(b)) => a == b,
+            (FieldValue::Timestamp(a), FieldValue::Timestamp(b)) => a == b && a.offset() == b.offset(),
+            (FieldValue::Json(a), FieldValue::Json(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for FieldValue {}
+
+impl Hash for FieldValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+
+        match self {
+            FieldValue::Null => {},
+            FieldValue::Int(v) => v.hash(state),
+            FieldValue::String(s) => s.hash(state),
+            FieldValue::Bool(v) => v.hash(state),
+            FieldValue::Float(v) => v.to_bits().hash(state),
+            FieldValue::Decimal(s) => s.hash(state),
+            FieldValue::Binary(v) => v.hash(state),
+            FieldValue::Timestamp(v) => v.hash(state),
+            FieldValue::Json(v) => v.hash(state),
+        }
+    }
+}
+
+impl Serialize for FieldValue {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        match self {
+            FieldValue::Null => serializer.serialize_unit(),
+            FieldValue::Int(v) => serializer.serialize_i64(*v),
+            FieldValue::String(s) => serializer.serialize_str(s),
+            FieldValue::Bool(v) => serializer.serialize_bool(*v),
+            FieldValue::Float(v) if v.is_finite() => serializer.serialize_f64(*v),
+            FieldValue::Float(v) => tagged(serializer, "$float", &v.to_string()),
+            FieldValue::Decimal(s) => tagged(serializer, "$decimal", s),
+            FieldValue::Binary(v) if serializer.is_human_readable() => tagged(serializer, "$binary", &BASE64.encode(v)),
+            FieldValue::Binary(v) => serializer.serialize_bytes(v),
+            FieldValue::Timestamp(v) => tagged(serializer, "$timestamp", &v.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+            FieldValue::Json(v) => tagged(serializer, "$json", v),
+        }
+    }
+}
+
+/// Serialize a value as a single-key object
+fn tagged<S, T>(serializer: S, tag: &str, value: &T) -> Result<S::Ok, S::Error>
+    where S: Serializer, T: Serialize + ?Sized
+{
+    let mut map = serializer.serialize_map(Some(1))?;
+    map.serialize_entry(tag, value)?;
+    map.end()
+}
+
+impl<'de> Deserialize<'de> for FieldValue {
+    fn deserialize<D>(deserializer: D) -> Result<FieldValue, D::Error>
+        where D: Deserializer<'de>
+    {
+        deserializer.deserialize_any(FieldValueVisitor)
+    }
+}
+
+struct FieldValueVisitor;
+
+impl<'de> Visitor<'de> for FieldValueVisitor {
+    type Value = FieldValue;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a field value")
+    }
+
+    fn visit_unit<E>(self) -> Result<FieldValue, E> {
+        Ok(FieldValue::Null)
+    }
+
+    fn visit_none<E>(self) -> Result<FieldValue, E> {
+        Ok(FieldValue::Null)
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<FieldValue, D::Error>
+        where D: Deserializer<'de>
+    {
+        FieldValue::deserialize(deserializer)
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<FieldValue, E> {
+        Ok(FieldValue::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<FieldValue, E> {
+        Ok(FieldValue::Int(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<FieldValue, E> {
+        // Integers too large for an i64 are kept exact as decimals
+        if v > i64::MAX as u64 {
+            Ok(FieldValue::Decimal(v.to_string()))
+        } else {
+            Ok(FieldValue::Int(v as i64))
+        }
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<FieldValue, E> {
+        Ok(FieldValue::Float(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<FieldValue, E> {
+        Ok(FieldValue::String(v.to_string()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<FieldValue, E> {
+        Ok(FieldValue::String(v))
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<FieldValue, E> {
+        Ok(FieldValue::Binary(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<FieldValue, E> {
+        Ok(FieldValue::Binary(v))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<FieldValue, A::Error>
+        where A: SeqAccess<'de>
+    {
+        let mut values = vec![];
+
+        while let Some(value) = seq.next_element::<serde_json::Value>()? {
+            values.push(value);
+        }
+
+        Ok(FieldValue::Json(serde_json::Value::Array(values)))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<FieldValue, A::Error>
+        where A: MapAccess<'de>
+    {
+        let tag = match map.next_key::<String>()? {
+            Some(tag) => tag,
+            None => return Ok(FieldValue::Json(serde_json::Value::Object(serde_json::Map::new()))),
+        };
+
+        let value = match &tag[..] {
+            "$float" => map.next_value::<String>()?
+                .parse::<f64>()
+                .map(FieldValue::Float)
+                .map_err(de::Error::custom)?,
+            "$decimal" => {
+                let text = map.next_value::<String>()?;
+
+                if !is_decimal(&text) && !NUMERIC_SPECIALS.contains(&&text[..]) {
+                    return Err(de::Error::custom(format!("{:?} isn't a decimal", text)));
+                }
+
+                FieldValue::Decimal(text)
+            },
+            "$binary" => BASE64.decode(map.next_value::<String>()?)
+                .map(FieldValue::Binary)
+                .map_err(de::Error::custom)?,
+            "$timestamp" => DateTime::parse_from_rfc3339(&map.next_value::<String>()?)
+                .map(FieldValue::Timestamp)
+                .map_err(de::Error::custom)?,
+            "$json" => FieldValue::Json(map.next_value::<serde_json::Value>()?),
+            _ => {
+                // Any other object is taken as JSON
+                let mut object = serde_json::Map::new();
+                object.insert(tag, map.next_value::<serde_json::Value>()?);
+
+                while let Some((key, value)) = map.next_entry::<String, serde_json::Value>()? {
+                    object.insert(key, value);
+                }
+
+                return Ok(FieldValue::Json(serde_json::Value::Object(object)));
+            },
+        };
+
+        if let Some(key) = map.next_key::<String>()? {
+            return Err(de::Error::custom(format!("unexpected key `{}` after `{}`", key, tag)));
+        }
+
+        Ok(value)
+    }
+}
+
+/// The decimals that aren't numbers, as PostgreSQL writes them
+pub const NUMERIC_SPECIALS: [&str; 3] = ["NaN", "Infinity", "-Infinity"];
+
+/// Whether the text is a decimal number: an optional sign, digits with an optional fraction,
+/// and an optional exponent, such as `-12.50` or `1.5e3`
+pub fn is_decimal(text: &str) -> bool {
+    let digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+    let unsigned = text.strip_prefix(['+', '-']).unwrap_or(text);
+
+    let (mantissa, exponent) = match unsigned.find(['e', 'E']) {
+        Some(i) => (&unsigned[..i], Some(&unsigned[i + 1..])),
+        None => (unsigned, None),
+    };
+
+    let (integer, fraction) = match mantissa.find('.') {
+        Some(i) => (&mantissa[..i], &mantissa[i + 1..]),
+        None => (mantissa, ""),
+    };
+
+    let exponent = match exponent {
+        Some(e) => {
+            let e = e.strip_prefix(['+', '-']).unwrap_or(e);
+
+            !e.is_empty() && digits(e)
+        },
+        None => true,
+    };
+
+    !(integer.is_empty() && fraction.is_empty()) && digits(integer) && digits(fraction) && exponent
 }
 
 pub type Row = HashMap<String, FieldValue>;
 
-#[derive(Debug, PartialEq, Clone)]
+/// The id of a row
+///
+/// Integers are `Int` when non-negative and `Signed` otherwise, and textual ids are classified
+/// by their format. Ids are compared by value, so that the same text is the same id whatever
+/// kind it was classified as.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
 pub enum Id {
     Int(u64),
+    /// A negative integer
+    Signed(i64),
+    /// A UUID in its hyphenated form
     Uuid(String),
+    /// A ULID in its canonical Crockford base32 form
+    Ulid(String),
+    /// Any other textual id, such as a slug
+    String(String),
+    /// The components of a multi-column primary key, in the order of its columns
+    Composite(Vec<Id>),
+}
+
+/// What an id is compared and hashed by
+#[derive(PartialEq, Eq, Hash)]
+enum IdValue<'a> {
+    Int(i128),
+    Text(&'a str),
+    Composite(&'a [Id]),
+}
+
+impl Id {
+    /// Classify a textual id as a UUID, a ULID or any other string
+    pub fn from_text(text: String) -> Id {
+        if is_uuid(&text) {
+            Id::Uuid(text)
+        } else if is_ulid(&text) {
+            Id::Ulid(text)
+        } else {
+            Id::String(text)
+        }
+    }
+
+    /// An integer id, `Signed` only if negative
+    pub fn from_i64(v: i64) -> Id {
+        if v < 0 {
+            Id::Signed(v)
+        } else {
+            Id::Int(v as u64)
+        }
+    }
+
+    fn value(&self) -> IdValue<'_> {
+        match self {
+            Id::Int(v) => IdValue::Int(*v as i128),
+            Id::Signed(v) => IdValue::Int(*v as i128),
+            Id::Uuid(s) | Id::Ulid(s) | Id::String(s) => IdValue::Text(s),
+            Id::Composite(ids) => IdValue::Composite(ids),
+        }
+    }
+}
+
+/// Whether the text is a UUID in its hyphenated form
+fn is_uuid(text: &str) -> bool {
+    text.len() == 36 && Uuid::parse_str(text).is_ok()
+}
+
+/// Whether the text is a ULID: 26 Crockford base32 digits, the first no greater than 7
+fn is_ulid(text: &str) -> bool {
+    text.len() == 26
+        && text.as_bytes()[0] <= b'7'
+        && text.bytes().all(|b| b.is_ascii_digit() || (b.is_ascii_uppercase() && !b"ILOU".contains(&b)))
+}
+
+impl PartialEq for Id {
+    fn eq(&self, other: &Id) -> bool {
+        self.value() == other.value()
+    }
+}
+
+impl Eq for Id {}
+
+impl Hash for Id {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.value().hash(state)
+    }
+}
+
+impl<'de> Deserialize<'de> for Id {
+    fn deserialize<D>(deserializer: D) -> Result<Id, D::Error>
+        where D: Deserializer<'de>
+    {
+        deserializer.deserialize_any(IdVisitor)
+    }
+}
+
+struct IdVisitor;
+
+impl<'de> Visitor<'de> for IdVisitor {
+    type Value = Id;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an integer, a string or an array of ids")
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Id, E> {
+        Ok(Id::from_i64(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Id, E> {
+        Ok(Id::Int(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Id, E> {
+        Ok(Id::from_text(v.to_string()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Id, E> {
+        Ok(Id::from_text(v))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Id, A::Error>
+        where A: SeqAccess<'de>
+    {
+        let mut ids = vec![];
+
+        while let Some(id) = seq.next_element()? {
+            ids.push(id);
+        }
+
+        Ok(Id::Composite(ids))
+    }
+}
+
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Id::Int(v) => write!(f, "{}", v),
+            Id::Signed(v) => write!(f, "{}", v),
+            Id::Uuid(s) | Id::Ulid(s) | Id::String(s) => write!(f, "{}", s),
+            Id::Composite(ids) => {
+                let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+
+                write!(f, "({})", ids.join(", "))
+            },
+        }
+    }
+}
+
+/// The kind of ids a primary key holds, declared as the `id_kind` of a recipe
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum IdKind {
+    /// Non-negative integers up to `u64::MAX`
+    Int,
+    /// Integers within `i64`, negative ones included
+    SignedInt,
+    Uuid,
+    Ulid,
+    /// Any text, UUIDs and ULIDs included
+    String,
+}
+
+impl IdKind {
+    /// Whether the id is one of this kind
+    pub fn accepts(&self, id: &Id) -> bool {
+        match (self, id) {
+            (IdKind::Int, Id::Int(_)) => true,
+            (IdKind::SignedInt, Id::Int(v)) => *v <= i64::MAX as u64,
+            (IdKind::SignedInt, Id::Signed(_)) => true,
+            (IdKind::Uuid, Id::Uuid(_)) => true,
+            (IdKind::Ulid, Id::Ulid(_)) => true,
+            (IdKind::String, Id::Uuid(_)) | (IdKind::String, Id::Ulid(_)) | (IdKind::String, Id::String(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for IdKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            IdKind::Int => "int",
+            IdKind::SignedInt => "signed_int",
+            IdKind::Uuid => "uuid",
+            IdKind::Ulid => "ulid",
+            IdKind::String => "string",
+        })
+    }
 }
 
 pub type EntityType = String;
@@ -43,3 +473,138 @@ impl DeserializedValue {
 
     pub fn value(&self) -> FieldValue { self.value.clone() }
 }
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum OperationKind {
+    #[serde(rename="INSERT")]
+    Insert,
+    #[serde(rename="UPDATE")]
+    Update,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Operation {
+    op: OperationKind,
+    #[serde(rename="type")]
+    type_: EntityType,
+    id: Option<Id>,
+    row: Row,
+    /// Circular fields an INSERT holds the fallback value of, set by a later UPDATE
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    deferred: Vec<String>,
+}
+
+impl Operation {
+    pub fn new(op: OperationKind, type_: EntityType, id: Option<Id>, row: Row) -> Operation {
+        Operation {
+            op,
+            type_,
+            id,
+            row,
+            deferred: vec![],
+        }
+    }
+
+    /// Mark the fields holding the fallback value of a circular ingredient
+    pub fn defer(mut self, mut fields: Vec<String>) -> Operation {
+        fields.sort();
+        self.deferred = fields;
+
+        self
+    }
+
+    pub fn op(&self) -> OperationKind { self.op.clone() }
+
+    pub fn type_(&self) -> &EntityType { &self.type_ }
+
+    pub fn id(&self) -> Option<Id> { self.id.clone() }
+
+    pub fn row(&self) -> &Row { &self.row }
+
+    pub fn deferred(&self) -> &[String] { &self.deferred }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn it_writes_plain_json_values_as_is() {
+        let row: Row = serde_json::from_str(r#"{ "a": null, "b": 1, "c": "x", "d": true, "e": 1.5 }"#).unwrap();
+
+        assert_eq!(Some(&FieldValue::Null), row.get("a"));
+        assert_eq!(Some(&FieldValue::Int(1)), row.get("b"));
+        assert_eq!(Some(&FieldValue::String(String::from("x"))), row.get("c"));
+        assert_eq!(Some(&FieldValue::Bool(true)), row.get("d"));
+        assert_eq!(Some(&FieldValue::Float(1.5)), row.get("e"));
+        assert_eq!("1.5", serde_json::to_string(&FieldValue::Float(1.5)).unwrap());
+    }
+
+    #[test]
+    fn it_tags_values_json_cannot_hold() {
+        assert_eq!(r#"{"$decimal":"12.50"}"#, serde_json::to_string(&FieldValue::Decimal(String::from("12.50"))).unwrap());
+        assert_eq!(r#"{"$binary":"AQI="}"#, serde_json::to_string(&FieldValue::Binary(vec![1, 2])).unwrap());
+        assert_eq!(r#"{"$float":"NaN"}"#, serde_json::to_string(&FieldValue::Float(f64::NAN)).unwrap());
+        assert_eq!(
+            FieldValue::Timestamp(DateTime::parse_from_rfc3339("2018-01-02T03:04:05Z").unwrap()),
+            serde_json::from_str(r#"{ "$timestamp": "2018-01-02T03:04:05Z" }"#).unwrap()
+        );
+        assert!(serde_json::from_str::<FieldValue>(r#"{ "$decimal": "1", "extra": 2 }"#).is_err());
+    }
+
+    #[test]
+    fn it_reads_only_numbers_as_decimals() {
+        for text in &["12.50", "-1", "+.5", "1.", "1.5e3", "2E-7", "NaN", "-Infinity"] {
+            assert!(serde_json::from_str::<FieldValue>(&format!(r#"{{ "$decimal": "{}" }}"#, text)).is_ok(), "{}", text);
+        }
+
+        for text in &["", "1--", "--", "e", "1-2", ".", "1e", "1.2.3", "1e+", "nan", "1; DROP TABLE foos"] {
+            assert!(serde_json::from_str::<FieldValue>(&format!(r#"{{ "$decimal": "{}" }}"#, text)).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn it_hashes_floats_by_their_bits() {
+        let mut set = HashSet::new();
+        set.insert(FieldValue::Float(f64::NAN));
+        set.insert(FieldValue::Float(f64::NAN));
+        set.insert(FieldValue::Float(0.0));
+        set.insert(FieldValue::Float(-0.0));
+
+        assert_eq!(3, set.len());
+    }
+
+    #[test]
+    fn it_writes_composite_ids_as_arrays() {
+        let id = Id::Composite(vec![Id::Int(1), Id::Uuid(String::from("a"))]);
+
+        assert_eq!(r#"[1,"a"]"#, serde_json::to_string(&id).unwrap());
+        assert_eq!(id, serde_json::from_str(r#"[1, "a"]"#).unwrap());
+        assert_eq!("(1, a)", id.to_string());
+    }
+
+    #[test]
+    fn it_classifies_ids_when_reading_them() {
+        let ids: Vec<Id> = serde_json::from_str(r#"[1, -1, "67e55044-10b1-426f-9247-bb680e5fe0c8", "01ARZ3NDEKTSV4RRFFQ69G5FAV", "my-slug"]"#).unwrap();
+
+        assert!(matches!(ids[0], Id::Int(1)));
+        assert!(matches!(ids[1], Id::Signed(-1)));
+        assert!(matches!(ids[2], Id::Uuid(_)));
+        assert!(matches!(ids[3], Id::Ulid(_)));
+        assert!(matches!(ids[4], Id::String(_)));
+        assert_eq!(r#"[1,-1,"67e55044-10b1-426f-9247-bb680e5fe0c8","01ARZ3NDEKTSV4RRFFQ69G5FAV","my-slug"]"#, serde_json::to_string(&ids).unwrap());
+    }
+
+    #[test]
+    fn it_compares_ids_by_value() {
+        let mut set = HashSet::new();
+        set.insert(Id::Uuid(String::from("a")));
+        set.insert(Id::String(String::from("a")));
+        set.insert(Id::Signed(5));
+        set.insert(Id::Int(5));
+
+        assert_eq!(2, set.len());
+        assert_ne!(Id::Int(1), Id::String(String::from("1")));
+    }
+}
@@ -0,0 +1,252 @@
+use contracts::*;
+use book_keeper::*;
+use recipe::{RecipeSet, Recipe, PrimaryKey};
+use ingredients::ingredient::Ingredient;
+use error::{Error, Result};
+use std::vec::Vec;
+
+pub struct Deserializer<'a> {
+    recipes: &'a RecipeSet,
+}
+
+impl<'a> Deserializer<'a> {
+    pub fn new(recipes: &'a RecipeSet) -> Deserializer<'a> {
+        Deserializer {
+            recipes,
+        }
+    }
+
+    /// Replay snapshot operations into INSERT and UPDATE operations ready to be persisted
+    ///
+    /// The operations are kept in snapshot order. INSERTs get fresh ids from the books, UPDATEs
+    /// and references use the ids previously handed out for their targets.
+    pub fn deserialize(&self, ops: &[Operation], books: &dyn BookKeeper) -> Result<Vec<Operation>> {
+        ops.iter()
+            .map(|op| self.deserialize_operation(op, books))
+            .collect()
+    }
+
+    /// Deserialize a single operation
+    ///
+    /// Operations must still be given in snapshot order, so that references find their targets.
+    pub fn deserialize_operation(&self, op: &Operation, books: &dyn BookKeeper) -> Result<Operation> {
+        let etype = op.type_();
+        let recipe = self.recipe(etype)?;
+        let id = self.resolve_id(op, recipe, books)?;
+        let mut row = self.deserialize_row(op, books)?;
+
+        if let Some(key) = id.as_ref().and_then(|id| recipe.primary_key().key_row(id)) {
+            row.extend(key);
+        }
+
+        Ok(Operation::new(op.op(), etype.clone(), id, row))
+    }
+
+    /// Deserialize the fields of an operation's row, leaving out its primary key
+    ///
+    /// Fields an INSERT was written with the fallback value of are deserialized as such.
+    pub fn deserialize_row(&self, op: &Operation, books: &dyn BookKeeper) -> Result<Row> {
+        Ok(self.deserialize_fields(op, books)?
+            .into_iter()
+            .map(|(field, deserialized)| (field, deserialized.value()))
+            .collect())
+    }
+
+    /// Deserialize the fields of an operation's row like `deserialize_row`, keeping the rows
+    /// every value depends on
+    pub fn deserialize_fields(&self, op: &Operation, books: &dyn BookKeeper) -> Result<Vec<(String, DeserializedValue)>> {
+        let recipe = self.recipe(op.type_())?;
+        let mut fields = vec![];
+
+        for (field, value) in op.row() {
+            if let PrimaryKey::String(pk) = recipe.primary_key() {
+                if pk == field {
+                    continue;
+                }
+            }
+
+            if let Some(ingredient) = recipe.ingredient(field) {
+                let circular = !op.deferred().contains(field);
+
+                fields.push((field.clone(), ingredient.snapper_deserialize(value, op.row(), books, circular)?));
+            }
+        }
+
+        Ok(fields)
+    }
+
+    /// Get the recipe for the given type
+    fn recipe(&self, etype: &EntityType) -> Result<&'a Recipe> {
+        self.recipes.get(etype).ok_or_else(|| Error::MissingRecipe(etype.clone()))
+    }
+
+    /// Ask the books for the id the operation's row should have when persisted
+    pub(crate) fn resolve_id(&self, op: &Operation, recipe: &Recipe, books: &dyn BookKeeper) -> Result<Option<Id>> {
+        let etype = op.type_();
+
+        match (recipe.primary_key(), op.id()) {
+            // Rows without a primary key can only be inserted again, whatever id they were given
+            (PrimaryKey::Null, _) if op.op() == OperationKind::Insert => Ok(None),
+            (PrimaryKey::Null, _) => Err(Error::MissingPrimaryKey(etype.clone())),
+            (_, None) => Err(Error::MissingId(etype.clone())),
+            (PrimaryKey::Composite(pks), Some(Id::Composite(ids))) if pks.len() == ids.len() => {
+                let components = recipe.key_types()
+                    .into_iter()
+                    .zip(ids.iter().cloned())
+                    .map(|((_, component_type), id)| (component_type.cloned(), id))
+                    .collect();
+
+                books.resolve_components(etype.clone(), components)
+                    .map(Some)
+                    .ok_or(Error::UnresolvableId(etype.clone(), Id::Composite(ids)))
+            },
+            (PrimaryKey::Composite(_), Some(id)) => Err(Error::UnresolvableId(etype.clone(), id)),
+            (PrimaryKey::String(_), Some(id)) => {
+                // Only an INSERT may introduce a new id, everything else refers to one
+                let authoritative = op.op() == OperationKind::Insert;
+
+                books.resolve_id(etype.clone(), id.clone(), authoritative)
+                    .map(Some)
+                    .ok_or(Error::UnresolvableId(etype.clone(), id))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::{recipes, row};
+    use recipe::Ingredient;
+    use ingredients::value::Value;
+    use ingredients::reference::Reference;
+    use std::collections::HashMap;
+
+    struct BookKeeperMock {}
+
+    impl BookKeeperMock {
+        pub fn new() -> BookKeeperMock { BookKeeperMock {} }
+    }
+
+    impl BookKeeper for BookKeeperMock {
+        fn resolve_id(&self, _etype: EntityType, id: Id, _authoritative: bool) -> Option<Id> {
+            match id {
+                Id::Uuid(s) | Id::String(s) => s.rsplit('-').next()
+                    .and_then(|n| n.parse::<u64>().ok())
+                    .map(|n| Id::Int(n + 100)),
+                _ => None,
+            }
+        }
+        fn reset(&mut self) { unimplemented!() }
+    }
+
+    fn uuid(s: &str) -> FieldValue {
+        FieldValue::String(String::from(s))
+    }
+
+    #[test]
+    fn it_deserializes_operations_with_fresh_ids() {
+        let recipes = recipes();
+        let b = BookKeeperMock::new();
+        let d = Deserializer::new(&recipes);
+
+        let ops = d.deserialize(&[
+            Operation::new(OperationKind::Insert, String::from("parents"), Some(Id::Uuid(String::from("parents-1"))), row(vec![
+                ("id", uuid("parents-1")),
+                ("name", uuid("Foo")),
+                ("favorite_child_id", FieldValue::Null),
+                ("ignored", FieldValue::Int(5)),
+            ])),
+            Operation::new(OperationKind::Insert, String::from("children"), Some(Id::Uuid(String::from("children-2"))), row(vec![
+                ("id", uuid("children-2")),
+                ("parent_id", uuid("parents-1")),
+            ])),
+            Operation::new(OperationKind::Update, String::from("parents"), Some(Id::Uuid(String::from("parents-1"))), row(vec![
+                ("id", uuid("parents-1")),
+                ("favorite_child_id", uuid("children-2")),
+            ])),
+        ], &b).unwrap();
+
+        assert_eq!(vec![
+            Operation::new(OperationKind::Insert, String::from("parents"), Some(Id::Int(101)), row(vec![
+                ("id", FieldValue::Int(101)),
+                ("name", uuid("Foo")),
+                ("favorite_child_id", FieldValue::Null),
+            ])),
+            Operation::new(OperationKind::Insert, String::from("children"), Some(Id::Int(102)), row(vec![
+                ("id", FieldValue::Int(102)),
+                ("parent_id", FieldValue::Int(101)),
+            ])),
+            Operation::new(OperationKind::Update, String::from("parents"), Some(Id::Int(101)), row(vec![
+                ("id", FieldValue::Int(101)),
+                ("favorite_child_id", FieldValue::Int(102)),
+            ])),
+        ], ops);
+    }
+
+    #[test]
+    fn it_fails_on_operations_without_recipe() {
+        let recipes = recipes();
+        let b = BookKeeperMock::new();
+        let d = Deserializer::new(&recipes);
+
+        let result = d.deserialize(&[
+            Operation::new(OperationKind::Insert, String::from("strangers"), Some(Id::Uuid(String::from("strangers-1"))), Row::new()),
+        ], &b);
+
+        assert_eq!(Err(Error::MissingRecipe(String::from("strangers"))), result);
+    }
+
+    #[test]
+    fn it_fails_on_operations_without_id() {
+        let recipes = recipes();
+        let b = BookKeeperMock::new();
+        let d = Deserializer::new(&recipes);
+
+        let result = d.deserialize(&[
+            Operation::new(OperationKind::Insert, String::from("children"), None, row(vec![("parent_id", uuid("parents-1"))])),
+        ], &b);
+
+        assert_eq!(Err(Error::MissingId(String::from("children"))), result);
+    }
+
+    #[test]
+    fn it_inserts_keyless_rows_without_ids() {
+        let mut recipes = recipes();
+        let mut visit_ingredients = HashMap::new();
+        visit_ingredients.insert(String::from("child_id"), Ingredient::Ref(Reference::new(String::from("children"), vec![])));
+        recipes.add(String::from("visits"), Recipe::new(PrimaryKey::Null, visit_ingredients));
+
+        let b = BookKeeperMock::new();
+        let d = Deserializer::new(&recipes);
+        let visit = row(vec![("child_id", uuid("children-2"))]);
+
+        assert_eq!(
+            Ok(Operation::new(OperationKind::Insert, String::from("visits"), None, row(vec![("child_id", FieldValue::Int(102))]))),
+            d.deserialize_operation(&Operation::new(OperationKind::Insert, String::from("visits"), Some(Id::Int(1)), visit.clone()), &b)
+        );
+        assert_eq!(
+            Err(Error::MissingPrimaryKey(String::from("visits"))),
+            d.deserialize_operation(&Operation::new(OperationKind::Update, String::from("visits"), None, visit), &b)
+        );
+    }
+
+    #[test]
+    fn it_leaves_the_primary_key_out_of_deserialized_rows() {
+        let mut recipes = recipes();
+        let mut foo_ingredients = HashMap::new();
+        foo_ingredients.insert(String::from("id"), Ingredient::Value(Value::new()));
+        foo_ingredients.insert(String::from("name"), Ingredient::Value(Value::new()));
+        recipes.add(String::from("foos"), Recipe::new(PrimaryKey::String(String::from("id")), foo_ingredients));
+
+        let b = BookKeeperMock::new();
+        let d = Deserializer::new(&recipes);
+        let op = Operation::new(OperationKind::Insert, String::from("foos"), Some(Id::Uuid(String::from("foos-3"))), row(vec![
+            ("id", uuid("foos-3")),
+            ("name", uuid("Foo")),
+        ]));
+
+        assert_eq!(Ok(row(vec![("name", uuid("Foo"))])), d.deserialize_row(&op, &b));
+        assert_eq!(Some(&FieldValue::Int(103)), d.deserialize_operation(&op, &b).unwrap().row().get("id"));
+    }
+}
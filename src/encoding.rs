@@ -0,0 +1,114 @@
+use error::{Error, Result};
+use std::vec::Vec;
+use std::io::Write;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+use ciborium;
+
+/// The CBOR self-describe tag, written in front of every CBOR document to tell it from JSON
+const CBOR_MAGIC: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+/// How snapshots and recipes are written
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Encoding {
+    Json,
+    /// Compact binary encoding, storing binary values as raw bytes
+    Cbor,
+}
+
+impl Encoding {
+    /// Tell the encoding of a document from its first bytes
+    pub fn detect(bytes: &[u8]) -> Encoding {
+        if bytes.starts_with(&CBOR_MAGIC) {
+            Encoding::Cbor
+        } else {
+            Encoding::Json
+        }
+    }
+}
+
+/// Write a value in the given encoding
+///
+/// JSON objects are written with sorted keys, so the same value is always written the same way.
+pub fn encode<T: Serialize, W: Write>(value: &T, mut writer: W, encoding: Encoding) -> Result<()> {
+    match encoding {
+        Encoding::Json => {
+            let json = serde_json::to_value(value).map_err(|e| Error::Encoding(e.to_string()))?;
+
+            serde_json::to_writer(writer, &json).map_err(|e| Error::Io(e.to_string()))
+        },
+        Encoding::Cbor => {
+            writer.write_all(&CBOR_MAGIC).map_err(Error::from)?;
+
+            ciborium::into_writer(value, writer).map_err(|e| match e {
+                ciborium::ser::Error::Io(e) => Error::from(e),
+                e => Error::Encoding(e.to_string()),
+            })
+        },
+    }
+}
+
+/// Read a value, detecting its encoding
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    match Encoding::detect(bytes) {
+        Encoding::Json => serde_json::from_slice(bytes).map_err(|e| Error::Encoding(e.to_string())),
+        Encoding::Cbor => ciborium::from_reader(&bytes[CBOR_MAGIC.len()..]).map_err(|e| Error::Encoding(e.to_string())),
+    }
+}
+
+/// Write a value into a buffer in the given encoding
+pub fn to_vec<T: Serialize>(value: &T, encoding: Encoding) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+
+    encode(value, &mut bytes, encoding)?;
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use contracts::*;
+    use chrono::DateTime;
+
+    fn values() -> Vec<FieldValue> {
+        vec![
+            FieldValue::Null,
+            FieldValue::Int(-5),
+            FieldValue::Int(i64::MAX),
+            FieldValue::String(String::from("Foo")),
+            FieldValue::Bool(true),
+            FieldValue::Float(1.5),
+            FieldValue::Float(f64::NAN),
+            FieldValue::Decimal(u64::MAX.to_string()),
+            FieldValue::Binary(vec![0, 1, 255]),
+            FieldValue::Timestamp(DateTime::parse_from_rfc3339("2018-01-02T03:04:05.123+02:00").unwrap()),
+            FieldValue::Json(serde_json::json!({ "a": [1, null, "b"] })),
+        ]
+    }
+
+    #[test]
+    fn it_round_trips_field_values_in_both_encodings() {
+        for encoding in &[Encoding::Json, Encoding::Cbor] {
+            let bytes = to_vec(&values(), *encoding).unwrap();
+
+            assert_eq!(*encoding, Encoding::detect(&bytes));
+            assert_eq!(values(), decode::<Vec<FieldValue>>(&bytes).unwrap());
+        }
+    }
+
+    #[test]
+    fn it_stores_binary_values_as_raw_bytes_in_cbor() {
+        let value = FieldValue::Binary(vec![7; 300]);
+
+        assert!(to_vec(&value, Encoding::Cbor).unwrap().len() < 310);
+        assert!(to_vec(&value, Encoding::Json).unwrap().len() > 400);
+    }
+
+    #[test]
+    fn it_fails_on_malformed_input() {
+        assert!(matches!(decode::<Vec<FieldValue>>(&[0xd9, 0xd9, 0xf7, 0xff]), Err(Error::Encoding(_))));
+        assert!(matches!(decode::<Vec<FieldValue>>(b"[1,"), Err(Error::Encoding(_))));
+    }
+}
@@ -0,0 +1,107 @@
+use contracts::*;
+use std::vec::Vec;
+use std::string::String;
+use std::error;
+use std::io;
+use std::fmt;
+use std::result;
+
+/// Everything that can go wrong while serializing or deserializing
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// No recipe was given for the entity type
+    MissingRecipe(EntityType),
+    /// The row has no usable value in its primary key column
+    MissingPrimaryKey(EntityType),
+    /// The operation has no id although its type has a primary key
+    MissingId(EntityType),
+    /// The books refused to provide an id for the row
+    UnresolvableId(EntityType, Id),
+    /// A reference points to a row the books know nothing about
+    DanglingReference(EntityType, Id),
+    /// A morph type that isn't in the morph map
+    UnknownMorphType(FieldValue),
+    /// A negative integer where an id was expected
+    NegativeId(i64),
+    /// A value that can't be used as an id
+    InvalidId(FieldValue),
+    /// A value that isn't an id of the kind the recipe declares
+    WrongIdKind(IdKind, FieldValue),
+    /// A MATCH pattern that isn't a valid regex
+    InvalidPattern(String, String),
+    /// A JSON value that can't be read as a field value
+    InvalidValue(String),
+    /// Reading or writing failed
+    Io(String),
+    /// The input isn't well-formed JSON or CBOR, or doesn't have the expected shape
+    Encoding(String),
+    /// The input isn't a well-formed snapshot
+    InvalidSnapshot(String),
+    /// The snapshot was written in a format version this version of snapper can't read
+    UnsupportedVersion(u64),
+    /// The input isn't a snapshot archive
+    NotAnArchive,
+    /// The archive ends inside or right after the given block
+    TruncatedArchive(usize),
+    /// The given block of the archive doesn't match its checksum
+    CorruptBlock(usize),
+    /// The archive's content doesn't match its digest
+    DigestMismatch,
+    /// The row isn't in the database
+    MissingRow(EntityType, Id),
+    /// The database refused a query
+    Database(String),
+    /// The rows depend on each other in a cycle without any circular field to break it
+    Cycle(Vec<Dep>),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::MissingRecipe(etype) => write!(f, "No recipe for `{}`", etype),
+            Error::MissingPrimaryKey(etype) => write!(f, "A row of `{}` has no primary key", etype),
+            Error::MissingId(etype) => write!(f, "An operation on `{}` has no id", etype),
+            Error::UnresolvableId(etype, id) => write!(f, "The books can't provide an id for {} {}", etype, id),
+            Error::DanglingReference(etype, id) => write!(f, "Reference to unknown row {} {}", etype, id),
+            Error::UnknownMorphType(morph_type) => write!(f, "Unknown morph type {:?}", morph_type),
+            Error::NegativeId(v) => write!(f, "Negative id {}", v),
+            Error::InvalidId(v) => write!(f, "Can't use {:?} as an id", v),
+            Error::WrongIdKind(kind, v) => write!(f, "Can't use {:?} as an id of kind `{}`", v, kind),
+            Error::InvalidPattern(pattern, message) => write!(f, "Invalid pattern `{}`: {}", pattern, message),
+            Error::InvalidValue(message) => write!(f, "Invalid field value: {}", message),
+            Error::Io(message) => write!(f, "I/O error: {}", message),
+            Error::Encoding(message) => write!(f, "Malformed input: {}", message),
+            Error::InvalidSnapshot(message) => write!(f, "Invalid snapshot: {}", message),
+            Error::UnsupportedVersion(version) => write!(f, "Unsupported snapshot format version {}", version),
+            Error::NotAnArchive => write!(f, "Not a snapshot archive"),
+            Error::TruncatedArchive(block) => write!(f, "The archive is truncated at block {}", block),
+            Error::CorruptBlock(block) => write!(f, "Block {} of the archive is corrupt", block),
+            Error::DigestMismatch => write!(f, "The archive doesn't match its digest"),
+            Error::MissingRow(etype, id) => write!(f, "No row {} {} in the database", etype, id),
+            Error::Database(message) => write!(f, "Database error: {}", message),
+            Error::Cycle(members) => {
+                let path: Vec<String> = members.iter()
+                    .chain(members.first())
+                    .map(|(etype, id)| format!("{} {}", etype, id))
+                    .collect();
+
+                write!(f, "Dependency cycle without a circular field to break it: {}", path.join(" -> "))
+            },
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    /// Unwrap errors passed through readers and writers, keep anything else as a message
+    fn from(e: io::Error) -> Error {
+        if e.get_ref().map(|inner| inner.is::<Error>()).unwrap_or(false) {
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+
+        Error::Io(e.to_string())
+    }
+}
@@ -1,93 +1,77 @@
 use ingredients::ingredient::*;
 use contracts::*;
 use book_keeper::*;
+use recipe::RecipeSet;
+use error::Result;
 use std::vec::Vec;
 use std::string::String;
-use ingredients::raw::Raw;
-use ingredients::reference::Reference;
-use ingredients::value::Value;
+use recipe;
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(untagged)]
-pub enum CircularIngredient {
-    Value(Value),
-    Raw(Raw),
-    Ref(Reference),
-}
+/// Any ingredient can be wrapped, including other circular ones
+pub type CircularIngredient = recipe::Ingredient;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct CircularConfig {
-    ingredient: CircularIngredient,
-    fallback: CircularIngredient,
+    ingredient: Box<CircularIngredient>,
+    fallback: Box<CircularIngredient>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Circular {
-    #[serde(rename="type")]
-    type_: String,
     config: CircularConfig,
 }
 
 impl Circular {
     pub fn new(ingredient: CircularIngredient, fallback: CircularIngredient) -> Circular {
         Circular {
-            type_: "CIRCULAR".to_string(),
             config: CircularConfig {
-                ingredient,
-                fallback,
+                ingredient: Box::new(ingredient),
+                fallback: Box::new(fallback),
             }
         }
     }
+
+    /// The ingredient for the real value
+    pub fn ingredient(&self) -> &CircularIngredient {
+        &self.config.ingredient
+    }
+
+    /// The ingredient for the value inserted before the real value can be resolved
+    pub fn fallback(&self) -> &CircularIngredient {
+        &self.config.fallback
+    }
 }
 
 impl Ingredient for Circular {
     /// Get all dependencies of this ingredient
-    fn get_deps(&self, value: &FieldValue, row: &Row, circular: bool) -> Vec<Dep> {
+    fn get_deps(&self, value: &FieldValue, row: &Row, recipes: &RecipeSet, circular: bool) -> Vec<Dep> {
         match circular {
-            true => match &self.config.ingredient {
-                &CircularIngredient::Value(ref v) => v.get_deps(&value, &row, false),
-                &CircularIngredient::Raw(ref r) => r.get_deps(&value, &row, false),
-                &CircularIngredient::Ref(ref r) => r.get_deps(&value, &row, false),
-            },
-            false => match &self.config.fallback {
-                &CircularIngredient::Value(ref v) => v.get_deps(&value, &row, false),
-                &CircularIngredient::Raw(ref r) => r.get_deps(&value, &row, false),
-                &CircularIngredient::Ref(ref r) => r.get_deps(&value, &row, false),
-            },
+            true => self.config.ingredient.get_deps(value, row, recipes, circular),
+            false => self.config.fallback.get_deps(value, row, recipes, circular),
         }
     }
 
     /// Let the ingredient determine the value of the field to store in a serialization
-    fn snapper_serialize(&self, value: &FieldValue, row: &Row, books: &BookKeeper, circular: bool) -> Option<FieldValue> {
+    fn snapper_serialize(&self, value: &FieldValue, row: &Row, recipes: &RecipeSet, books: &dyn BookKeeper, circular: bool) -> Result<FieldValue> {
         match circular {
-            true => match &self.config.ingredient {
-                &CircularIngredient::Value(ref v) => v.snapper_serialize(&value, &row, books, false),
-                &CircularIngredient::Raw(ref r) => r.snapper_serialize(&value, &row, books, false),
-                &CircularIngredient::Ref(ref r) => r.snapper_serialize(&value, &row, books, false),
-            },
-            false => match &self.config.fallback {
-                &CircularIngredient::Value(ref v) => v.snapper_serialize(&value, &row, books, false),
-                &CircularIngredient::Raw(ref r) => r.snapper_serialize(&value, &row, books, false),
-                &CircularIngredient::Ref(ref r) => r.snapper_serialize(&value, &row, books, false),
-            },
+            true => self.config.ingredient.snapper_serialize(value, row, recipes, books, circular),
+            false => self.config.fallback.snapper_serialize(value, row, recipes, books, circular),
         }
     }
 
     /// Let the ingredient determine the value of the field to insert into the database when deserializing
-    fn snapper_deserialize(&self, value: &FieldValue, row: &Row, books: &BookKeeper) -> Option<DeserializedValue> {
-        match &self.config.ingredient {
-            &CircularIngredient::Value(ref v) => v.snapper_deserialize(&value, &row, books),
-            &CircularIngredient::Raw(ref r) => r.snapper_deserialize(&value, &row, books),
-            &CircularIngredient::Ref(ref r) => r.snapper_deserialize(&value, &row, books),
+    fn snapper_deserialize(&self, value: &FieldValue, row: &Row, books: &dyn BookKeeper, circular: bool) -> Result<DeserializedValue> {
+        match circular {
+            true => self.config.ingredient.snapper_deserialize(value, row, books, circular),
+            false => self.config.fallback.snapper_deserialize(value, row, books, circular),
         }
     }
 
     /// Should return an array with fields required to be able to UPDATE a row
+    ///
+    /// Only the real value ends up in an UPDATE, so the fallback has no say.
     fn get_required_extra_fields(&self) -> Vec<String> {
-        match &self.config.ingredient {
-            &CircularIngredient::Value(ref v) => v.get_required_extra_fields(),
-            &CircularIngredient::Raw(ref r) => r.get_required_extra_fields(),
-            &CircularIngredient::Ref(ref r) => r.get_required_extra_fields(),
-        }
+        self.config.ingredient.get_required_extra_fields()
     }
-}
\ No newline at end of file
+}
@@ -1,17 +1,25 @@
 use contracts::*;
 use book_keeper::*;
+use recipe::RecipeSet;
+use error::Result;
 use std::vec::Vec;
 use std::string::String;
 
 pub trait Ingredient {
     /// Get all dependencies of this ingredient
-    fn get_deps(&self, value: &FieldValue, row: &Row, circular: bool) -> Vec<Dep>;
+    ///
+    /// Referenced ids are read as the kind of id the recipe of the referenced type declares.
+    fn get_deps(&self, value: &FieldValue, row: &Row, recipes: &RecipeSet, circular: bool) -> Vec<Dep>;
 
     /// Let the ingredient determine the value of the field to store in a serialization
-    fn snapper_serialize(&self, value: &FieldValue, row: &Row, books: &BookKeeper, circular: bool) -> Option<FieldValue>;
+    ///
+    /// A null value is a legitimate outcome, a reference that can't be resolved is an error.
+    fn snapper_serialize(&self, value: &FieldValue, row: &Row, recipes: &RecipeSet, books: &dyn BookKeeper, circular: bool) -> Result<FieldValue>;
 
     /// Let the ingredient determine the value of the field to insert into the database when deserializing
-    fn snapper_deserialize(&self, value: &FieldValue, row: &Row, books: &BookKeeper) -> Option<DeserializedValue>;
+    ///
+    /// `circular` is false for the fallback value a circular field was inserted with.
+    fn snapper_deserialize(&self, value: &FieldValue, row: &Row, books: &dyn BookKeeper, circular: bool) -> Result<DeserializedValue>;
 
     /// Should return an array with fields required to be able to UPDATE a row
     fn get_required_extra_fields(&self) -> Vec<String>;
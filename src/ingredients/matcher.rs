@@ -1,31 +1,116 @@
 use ingredients::ingredient::*;
 use contracts::*;
 use book_keeper::*;
+use recipe::RecipeSet;
+use error::{Error, Result};
 use std::vec::Vec;
 use std::string::String;
 use std::collections::HashMap;
-use ingredients::raw::Raw;
-use ingredients::reference::Reference;
-use ingredients::value::Value;
-use ingredients::morph::Morph;
-use regex::Regex;
+use recipe;
+use regex::{Regex, RegexSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::result;
+use serde::de::{Deserializer, Visitor, SeqAccess, MapAccess};
 use tools::field_value_to_string;
 
+/// Any ingredient can be picked by a match, including circular ones and other matches
+pub type MatchIngredient = recipe::Ingredient;
+
+/// A regex and the ingredient to use when it matches
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MatchPattern {
+    pub pattern: String,
+    pub ingredient: MatchIngredient,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
-#[serde(untagged)]
-pub enum MatchIngredient {
-    Value(Value),
-    Raw(Raw),
-    Ref(Reference),
-    Morph(Morph),
+struct MatchMapperConfig {
+    field: String,
+    on: HashMap<String, MatchIngredient>,
+    #[serde(deserialize_with="deserialize_patterns")]
+    patterns: Vec<MatchPattern>,
+    default: Option<MatchIngredient>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(try_from="MatchMapperConfig")]
 struct MatchMapper {
     field: String,
     on: HashMap<String, MatchIngredient>,
-    patterns: HashMap<String, MatchIngredient>,
+    /// Tried in order, the first match wins
+    patterns: Vec<MatchPattern>,
     default: Option<MatchIngredient>,
+    /// All patterns compiled together, in the same order
+    #[serde(skip_serializing)]
+    compiled: RegexSet,
+}
+
+impl TryFrom<MatchMapperConfig> for MatchMapper {
+    type Error = Error;
+
+    /// Compile the patterns, failing on the first invalid one
+    fn try_from(config: MatchMapperConfig) -> Result<MatchMapper> {
+        for p in &config.patterns {
+            if let Err(e) = Regex::new(&p.pattern) {
+                return Err(Error::InvalidPattern(p.pattern.clone(), e.to_string()));
+            }
+        }
+
+        let patterns: Vec<&str> = config.patterns.iter().map(|p| &p.pattern[..]).collect();
+        let compiled = RegexSet::new(&patterns)
+            .map_err(|e| Error::InvalidPattern(patterns.join(", "), e.to_string()))?;
+
+        Ok(MatchMapper {
+            field: config.field,
+            on: config.on,
+            patterns: config.patterns,
+            default: config.default,
+            compiled,
+        })
+    }
+}
+
+/// Read patterns from a list of `{ pattern, ingredient }` entries, or from a map of patterns to
+/// ingredients in the order they are written
+fn deserialize_patterns<'de, D>(deserializer: D) -> result::Result<Vec<MatchPattern>, D::Error>
+    where D: Deserializer<'de>
+{
+    struct PatternsVisitor;
+
+    impl<'de> Visitor<'de> for PatternsVisitor {
+        type Value = Vec<MatchPattern>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a list of patterns or a map of patterns to ingredients")
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> result::Result<Self::Value, A::Error>
+            where A: SeqAccess<'de>
+        {
+            let mut patterns = vec![];
+
+            while let Some(p) = seq.next_element::<MatchPattern>()? {
+                patterns.push(p);
+            }
+
+            Ok(patterns)
+        }
+
+        fn visit_map<A>(self, mut map: A) -> result::Result<Self::Value, A::Error>
+            where A: MapAccess<'de>
+        {
+            let mut patterns = vec![];
+
+            while let Some((pattern, ingredient)) = map.next_entry::<String, MatchIngredient>()? {
+                patterns.push(MatchPattern { pattern, ingredient });
+            }
+
+            Ok(patterns)
+        }
+    }
+
+    deserializer.deserialize_any(PatternsVisitor)
 }
 
 impl MatchMapper {
@@ -33,24 +118,16 @@ impl MatchMapper {
     fn get_matched_ingredient(&self, row: &Row) -> Option<&MatchIngredient> {
         row.get(&self.field)
             .and_then(|val| {
-                let string_val = field_value_to_string(&val);
+                let string_val = field_value_to_string(val);
 
                 // Check on
-                if self.on.contains_key(&string_val) {
-                    return Some(self.on.get(&string_val).unwrap());
+                if let Some(ingredient) = self.on.get(&string_val) {
+                    return Some(ingredient);
                 }
 
                 // Check patterns
-                for (pattern, ingredient) in &self.patterns {
-                    let m = Regex::new(pattern)
-                        .map(|re| {
-                            re.is_match(&string_val[..])
-                        })
-                        .unwrap_or(false);
-
-                    if m {
-                        return Some(&ingredient);
-                    }
+                if let Some(i) = self.compiled.matches(&string_val).iter().next() {
+                    return Some(&self.patterns[i].ingredient);
                 }
 
                 // Default?
@@ -61,45 +138,24 @@ impl MatchMapper {
 
 impl MatchMapper {
     /// Get all dependencies of this ingredient
-    fn get_deps(&self, value: &FieldValue, row: &Row, circular: bool) -> Vec<Dep> {
-        self.get_matched_ingredient(&row)
-            .map(|ingredient| {
-                match ingredient {
-                    &MatchIngredient::Value(ref v) => v.get_deps(&value, &row, circular),
-                    &MatchIngredient::Raw(ref r) => r.get_deps(&value, &row, circular),
-                    &MatchIngredient::Ref(ref r) => r.get_deps(&value, &row, circular),
-                    &MatchIngredient::Morph(ref m) => m.get_deps(&value, &row, circular),
-                }
-            })
+    fn get_deps(&self, value: &FieldValue, row: &Row, recipes: &RecipeSet, circular: bool) -> Vec<Dep> {
+        self.get_matched_ingredient(row)
+            .map(|ingredient| ingredient.get_deps(value, row, recipes, circular))
             .unwrap_or(vec![])
     }
 
     /// Let the ingredient determine the value of the field to store in a serialization
-    fn snapper_serialize(&self, value: &FieldValue, row: &Row, books: &BookKeeper, circular: bool) -> Option<FieldValue> {
-        self.get_matched_ingredient(&row)
-            .map(|ingredient| {
-                match ingredient {
-                    &MatchIngredient::Value(ref v) => v.snapper_serialize(&value, &row, books, circular),
-                    &MatchIngredient::Raw(ref r) => r.snapper_serialize(&value, &row, books, circular),
-                    &MatchIngredient::Ref(ref r) => r.snapper_serialize(&value, &row, books, circular),
-                    &MatchIngredient::Morph(ref m) => m.snapper_serialize(&value, &row, books, circular),
-                }
-            })
-            .unwrap_or(None)
+    fn snapper_serialize(&self, value: &FieldValue, row: &Row, recipes: &RecipeSet, books: &dyn BookKeeper, circular: bool) -> Result<FieldValue> {
+        self.get_matched_ingredient(row)
+            .map(|ingredient| ingredient.snapper_serialize(value, row, recipes, books, circular))
+            .unwrap_or(Ok(FieldValue::Null))
     }
 
     /// Let the ingredient determine the value of the field to insert into the database when deserializing
-    fn snapper_deserialize(&self, value: &FieldValue, row: &Row, books: &BookKeeper) -> Option<DeserializedValue> {
-        self.get_matched_ingredient(&row)
-            .map(|ingredient| {
-                match ingredient {
-                    &MatchIngredient::Value(ref v) => v.snapper_deserialize(&value, &row, books),
-                    &MatchIngredient::Raw(ref r) => r.snapper_deserialize(&value, &row, books),
-                    &MatchIngredient::Ref(ref r) => r.snapper_deserialize(&value, &row, books),
-                    &MatchIngredient::Morph(ref m) => m.snapper_deserialize(&value, &row, books),
-                }
-            })
-            .unwrap_or(None)
+    fn snapper_deserialize(&self, value: &FieldValue, row: &Row, books: &dyn BookKeeper, circular: bool) -> Result<DeserializedValue> {
+        self.get_matched_ingredient(row)
+            .map(|ingredient| ingredient.snapper_deserialize(value, row, books, circular))
+            .unwrap_or_else(|| Ok(DeserializedValue::new(vec![], FieldValue::Null)))
     }
 }
 
@@ -110,47 +166,83 @@ struct MatchConfig {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Matcher {
-    #[serde(rename="type")]
-    type_: String,
     config: MatchConfig,
 }
 
 impl Matcher {
-    pub fn new(field: String, on: HashMap<String, MatchIngredient>, patterns: HashMap<String, MatchIngredient>, default: Option<MatchIngredient>) -> Matcher {
-        Matcher {
-            type_: "MATCH".to_string(),
+    /// Create a matcher, failing if any of the patterns is an invalid regex
+    pub fn new(field: String, on: HashMap<String, MatchIngredient>, patterns: Vec<MatchPattern>, default: Option<MatchIngredient>) -> Result<Matcher> {
+        let matcher = MatchMapper::try_from(MatchMapperConfig {
+            field: field.clone(),
+            on,
+            patterns,
+            default,
+        })?;
+
+        Ok(Matcher {
             config: MatchConfig {
-                field: field.clone(),
-                matcher: MatchMapper {
-                    field,
-                    on,
-                    patterns,
-                    default,
-                }
+                field,
+                matcher,
             }
-        }
+        })
+    }
+
+    /// The field to match on
+    pub fn field(&self) -> &str {
+        &self.config.matcher.field
+    }
+
+    /// The field listed as required when updating
+    pub fn config_field(&self) -> &str {
+        &self.config.field
+    }
+
+    /// The regex patterns to match against, in the order they are tried
+    pub fn patterns(&self) -> Vec<&String> {
+        self.config.matcher.patterns.iter().map(|p| &p.pattern).collect()
+    }
+
+    /// All ingredients the matcher can pick between
+    pub fn branches(&self) -> Vec<&MatchIngredient> {
+        self.config.matcher.on.values()
+            .chain(self.config.matcher.patterns.iter().map(|p| &p.ingredient))
+            .chain(self.config.matcher.default.iter())
+            .collect()
     }
 }
 
 impl Ingredient for Matcher {
     /// Get all dependencies of this ingredient
-    fn get_deps(&self, value: &FieldValue, row: &Row, circular: bool) -> Vec<Dep> {
-        self.config.matcher.get_deps(&value, &row, circular)
+    fn get_deps(&self, value: &FieldValue, row: &Row, recipes: &RecipeSet, circular: bool) -> Vec<Dep> {
+        self.config.matcher.get_deps(value, row, recipes, circular)
     }
 
     /// Let the ingredient determine the value of the field to store in a serialization
-    fn snapper_serialize(&self, value: &FieldValue, row: &Row, books: &BookKeeper, circular: bool) -> Option<FieldValue> {
-        self.config.matcher.snapper_serialize(&value, &row, books, circular)
+    fn snapper_serialize(&self, value: &FieldValue, row: &Row, recipes: &RecipeSet, books: &dyn BookKeeper, circular: bool) -> Result<FieldValue> {
+        self.config.matcher.snapper_serialize(value, row, recipes, books, circular)
     }
 
     /// Let the ingredient determine the value of the field to insert into the database when deserializing
-    fn snapper_deserialize(&self, value: &FieldValue, row: &Row, books: &BookKeeper) -> Option<DeserializedValue> {
-        self.config.matcher.snapper_deserialize(&value, &row, books)
+    fn snapper_deserialize(&self, value: &FieldValue, row: &Row, books: &dyn BookKeeper, circular: bool) -> Result<DeserializedValue> {
+        self.config.matcher.snapper_deserialize(value, row, books, circular)
     }
 
     /// Should return an array with fields required to be able to UPDATE a row
+    ///
+    /// Any branch may be picked, so the fields of all branches are included.
     fn get_required_extra_fields(&self) -> Vec<String> {
-        vec![self.config.field.clone()]
+        let mut fields = vec![self.config.field.clone()];
+
+        for branch in self.branches() {
+            for field in branch.get_required_extra_fields() {
+                if !fields.contains(&field) {
+                    fields.push(field);
+                }
+            }
+        }
+
+        fields
     }
 }
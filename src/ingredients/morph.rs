@@ -1,7 +1,9 @@
 use ingredients::ingredient::*;
-use tools::{field_value_to_id, id_to_field_value};
+use tools::{try_snapshot_value_to_id, id_to_field_value};
+use error::{Error, Result};
 use contracts::*;
 use book_keeper::*;
+use recipe::RecipeSet;
 use std::vec::Vec;
 use std::string::String;
 use std::collections::HashMap;
@@ -12,33 +14,29 @@ struct MorphMapper {
 }
 
 impl MorphMapper {
-    /// Help Morph find its dependency
-    pub fn get_deps(&self, morph_type: &FieldValue, value: &FieldValue) -> Vec<Dep> {
+    /// Help Morph find its dependency, reading the id as the kind the referenced type declares
+    pub fn get_deps(&self, morph_type: &FieldValue, value: &FieldValue, recipes: &RecipeSet) -> Vec<Dep> {
         self.morph_map.get(morph_type)
             .and_then(|etype: &EntityType| {
-                field_value_to_id(&value.clone())
+                recipes.read_id(etype, value).ok().and_then(|id| id)
                     .map(|id| vec![(etype.clone(), id)])
             })
             .unwrap_or(vec![])
     }
 
-    /// Help Morph resolve its value
-    pub fn resolve(&self, morph_type: &FieldValue, value: &FieldValue, books: &BookKeeper) -> Option<Id> {
-        self.morph_map.get(morph_type)
-            .and_then(|etype: &EntityType| {
-                field_value_to_id(&value.clone())
-                    .and_then(|id| {
-                        books.resolve_id(etype.clone(), id, false)
-                    })
-            })
-    }
-
-    /// Resolve a morph type into a dependency type
-    pub fn resolve_type(&self, morph_type: &FieldValue) -> Option<EntityType> {
-        self.morph_map.get(morph_type)
-            .map(|etype: &EntityType| {
-                etype.clone()
-            })
+    /// Help Morph resolve its value into its dependency and the resolved id, `None` if the value is null
+    ///
+    /// The id is read with the given function, from the type referred to and the value.
+    pub fn resolve(&self, morph_type: &FieldValue, value: &FieldValue, read: &dyn Fn(&EntityType, &FieldValue) -> Result<Option<Id>>, books: &dyn BookKeeper) -> Result<Option<(Dep, Id)>> {
+        let etype = self.morph_map.get(morph_type)
+            .ok_or_else(|| Error::UnknownMorphType(morph_type.clone()))?;
+
+        match read(etype, value)? {
+            Some(id) => books.resolve_id(etype.clone(), id.clone(), false)
+                .map(|resolved| Some(((etype.clone(), id.clone()), resolved)))
+                .ok_or_else(|| Error::DanglingReference(etype.clone(), id)),
+            None => Ok(None),
+        }
     }
 }
 
@@ -46,20 +44,19 @@ impl MorphMapper {
 struct MorphConfig {
     field: String,
     morph_mapper: MorphMapper,
+    #[serde(default)]
     optional_values: Vec<FieldValue>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Morph {
-    #[serde(rename="type")]
-    type_: String,
     config: MorphConfig,
 }
 
 impl Morph {
     pub fn new(field: String, morph_map: HashMap<FieldValue, String>, optional_values: Vec<FieldValue>) -> Morph {
         Morph {
-            type_: "MORPH".to_string(),
             config: MorphConfig {
                 field,
                 morph_mapper: MorphMapper {
@@ -70,6 +67,24 @@ impl Morph {
         }
     }
 
+    /// The field holding the morph type
+    pub fn field(&self) -> &str {
+        &self.config.field
+    }
+
+    /// All types the morph can refer to
+    pub fn entity_types(&self) -> Vec<&EntityType> {
+        self.config.morph_mapper.morph_map.values().collect()
+    }
+
+    /// The morph types referring to the given type
+    pub fn morph_types(&self, etype: &str) -> Vec<&FieldValue> {
+        self.config.morph_mapper.morph_map.iter()
+            .filter(|(_, t)| t.as_str() == etype)
+            .map(|(morph_type, _)| morph_type)
+            .collect()
+    }
+
     /// Specify which values should be treated as optional
     pub fn optional(&mut self, optional_values: Vec<FieldValue>) -> &mut Self {
         self.config.optional_values = optional_values.clone();
@@ -94,38 +109,40 @@ impl Morph {
 
 impl Ingredient for Morph {
     /// Get all dependencies of this ingredient
-    fn get_deps(&self, value: &FieldValue, row: &Row, _circular: bool) -> Vec<Dep> {
-        self.get_morph_type(&value, &row)
+    fn get_deps(&self, value: &FieldValue, row: &Row, recipes: &RecipeSet, _circular: bool) -> Vec<Dep> {
+        self.get_morph_type(value, row)
             .map(|morph_type| {
-                self.config.morph_mapper.get_deps(&morph_type, &value)
+                self.config.morph_mapper.get_deps(&morph_type, value, recipes)
             })
             .unwrap_or(vec![])
     }
 
     /// Let the ingredient determine the value of the field to store in a serialization
-    fn snapper_serialize(&self, value: &FieldValue, row: &Row, books: &BookKeeper, _circular: bool) -> Option<FieldValue> {
-        self.get_morph_type(&value, &row)
-            .and_then(|morph_type| {
-                self.config.morph_mapper.resolve(&morph_type, &value, books)
-                    .map(|id| {
-                        id_to_field_value(id)
-                    })
-            })
+    fn snapper_serialize(&self, value: &FieldValue, row: &Row, recipes: &RecipeSet, books: &dyn BookKeeper, _circular: bool) -> Result<FieldValue> {
+        let read = |etype: &EntityType, value: &FieldValue| recipes.read_id(etype, value);
+
+        match self.get_morph_type(value, row) {
+            Some(morph_type) => self.config.morph_mapper.resolve(&morph_type, value, &read, books)
+                .map(|resolved| resolved
+                    .map(|(_, id)| id_to_field_value(id))
+                    .unwrap_or(FieldValue::Null)
+                ),
+            None => Ok(FieldValue::Null),
+        }
     }
 
     /// Let the ingredient determine the value of the field to insert into the database when deserializing
-    fn snapper_deserialize(&self, value: &FieldValue, row: &Row, books: &BookKeeper) -> Option<DeserializedValue> {
-        self.get_morph_type(&value, &row)
-            .and_then(|morph_type| {
-                let ref_type = self.config.morph_mapper.resolve_type(&morph_type);
-                let id = self.config.morph_mapper.resolve(&morph_type, &value, books);
-
-                let f = |(ref_type, id): (&EntityType, &Id)| {
-                    DeserializedValue::new(vec![(ref_type.clone(), id.clone())], value.clone())
-                };
-
-                ref_type.iter().zip(id.iter()).map(f).next()
-            })
+    fn snapper_deserialize(&self, value: &FieldValue, row: &Row, books: &dyn BookKeeper, _circular: bool) -> Result<DeserializedValue> {
+        let read = |_: &EntityType, value: &FieldValue| try_snapshot_value_to_id(value);
+
+        match self.get_morph_type(value, row) {
+            Some(morph_type) => self.config.morph_mapper.resolve(&morph_type, value, &read, books)
+                .map(|resolved| match resolved {
+                    Some((dep, id)) => DeserializedValue::new(vec![dep], id_to_field_value(id)),
+                    None => DeserializedValue::new(vec![], FieldValue::Null),
+                }),
+            None => Ok(DeserializedValue::new(vec![], FieldValue::Null)),
+        }
     }
 
     /// Should return an array with fields required to be able to UPDATE a row
@@ -162,15 +179,15 @@ mod test {
         let mut row: Row = HashMap::new();
         row.insert("fooable_type".to_string(), FieldValue::String(String::from("BAR")));
 
-        let deps1 = m.get_deps(&FieldValue::Int(123), &row, false);
+        let deps1 = m.get_deps(&FieldValue::Int(123), &row, &RecipeSet::new(), false);
 
         assert_eq!(1, deps1.len());
 
-        let deps2 = m.get_deps(&FieldValue::Null, &row, false);
+        let deps2 = m.get_deps(&FieldValue::Null, &row, &RecipeSet::new(), false);
 
         assert_eq!(0, deps2.len());
 
-        let deps3 = m.get_deps(&FieldValue::Int(123), &HashMap::new(), false);
+        let deps3 = m.get_deps(&FieldValue::Int(123), &HashMap::new(), &RecipeSet::new(), false);
 
         assert_eq!(0, deps3.len());
     }
@@ -189,22 +206,28 @@ mod test {
         let mut row: Row = HashMap::new();
         row.insert("fooable_type".to_string(), FieldValue::String(String::from("BAR")));
 
-        let o1 = m.snapper_serialize(&FieldValue::Int(123), &row, &b, false);
+        let o1 = m.snapper_serialize(&FieldValue::Int(123), &row, &RecipeSet::new(), &b, false);
 
-        assert!(o1.is_some());
+        assert!(o1.is_ok());
         let serialized1 = o1.unwrap();
 
         assert_eq!(FieldValue::String(String::from("MOCK")), serialized1);
 
-        let o2 = m.snapper_serialize(&FieldValue::Null, &row, &b, false);
+        let o2 = m.snapper_serialize(&FieldValue::Null, &row, &RecipeSet::new(), &b, false);
 
-        assert!(o2.is_none());
+        assert_eq!(Ok(FieldValue::Null), o2);
 
         m.optional(vec![FieldValue::Int(123)]);
 
-        let o2 = m.snapper_serialize(&FieldValue::Int(123), &row, &b, false);
+        let o3 = m.snapper_serialize(&FieldValue::Int(123), &row, &RecipeSet::new(), &b, false);
+
+        assert_eq!(Ok(FieldValue::Null), o3);
+
+        row.insert("fooable_type".to_string(), FieldValue::String(String::from("QUX")));
+
+        let o4 = m.snapper_serialize(&FieldValue::Int(456), &row, &RecipeSet::new(), &b, false);
 
-        assert!(o2.is_none());
+        assert_eq!(Err(Error::UnknownMorphType(FieldValue::String(String::from("QUX")))), o4);
     }
 
     #[test]
@@ -221,13 +244,13 @@ mod test {
         let mut row: Row = HashMap::new();
         row.insert("fooable_type".to_string(), FieldValue::String(String::from("BAR")));
 
-        let o1 = m.snapper_deserialize(&FieldValue::Int(123), &row, &b);
+        let o1 = m.snapper_deserialize(&FieldValue::Int(123), &row, &b, true);
 
-        assert!(o1.is_some());
+        assert!(o1.is_ok());
         let deserialized1 = o1.unwrap();
 
         assert_eq!(1, deserialized1.deps().len());
-        assert_eq!((String::from("bars"), Id::Uuid(String::from("MOCK"))), deserialized1.deps()[0]);
-        assert_eq!(FieldValue::Int(123), deserialized1.value());
+        assert_eq!((String::from("bars"), Id::Int(123)), deserialized1.deps()[0]);
+        assert_eq!(FieldValue::String(String::from("MOCK")), deserialized1.value());
     }
 }
\ No newline at end of file
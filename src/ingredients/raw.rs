@@ -3,10 +3,10 @@ extern crate serde_json;
 use ingredients::ingredient::*;
 use contracts::*;
 use book_keeper::*;
+use recipe::RecipeSet;
+use error::Result;
 use std::vec::Vec;
 use std::string::String;
-use std::collections::HashMap;
-use tools::*;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct RawConfig {
@@ -14,30 +14,29 @@ struct RawConfig {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Raw {
-    #[serde(rename="type")]
-    type_: String,
     config: RawConfig,
 }
 
 impl Raw {
-    pub fn new(value: FieldValue) -> Raw { Raw { type_: "RAW".to_string(), config: RawConfig { value } } }
+    pub fn new(value: FieldValue) -> Raw { Raw { config: RawConfig { value } } }
 }
 
 impl Ingredient for Raw {
     /// Get all dependencies of this ingredient
-    fn get_deps(&self, _value: &FieldValue, _row: &Row, _circular: bool) -> Vec<Dep> {
+    fn get_deps(&self, _value: &FieldValue, _row: &Row, _recipes: &RecipeSet, _circular: bool) -> Vec<Dep> {
         vec![]
     }
 
     /// Let the ingredient determine the value of the field to store in a serialization
-    fn snapper_serialize(&self, _value: &FieldValue, _row: &Row, _books: &BookKeeper, _circular: bool) -> Option<FieldValue> {
-        Some(self.config.value.clone())
+    fn snapper_serialize(&self, _value: &FieldValue, _row: &Row, _recipes: &RecipeSet, _books: &dyn BookKeeper, _circular: bool) -> Result<FieldValue> {
+        Ok(self.config.value.clone())
     }
 
     /// Let the ingredient determine the value of the field to insert into the database when deserializing
-    fn snapper_deserialize(&self, _value: &FieldValue, _row: &Row, _books: &BookKeeper) -> Option<DeserializedValue> {
-        Some(DeserializedValue::new(vec![], self.config.value.clone()))
+    fn snapper_deserialize(&self, _value: &FieldValue, _row: &Row, _books: &dyn BookKeeper, _circular: bool) -> Result<DeserializedValue> {
+        Ok(DeserializedValue::new(vec![], self.config.value.clone()))
     }
 
     /// Should return an array with fields required to be able to UPDATE a row
@@ -49,6 +48,7 @@ impl Ingredient for Raw {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
 
     struct BookKeeperMock {}
 
@@ -65,7 +65,7 @@ mod tests {
     fn it_gets_deps() {
         let r = Raw::new(FieldValue::Int(123));
 
-        assert_eq!(0, r.get_deps(&FieldValue::Null, &HashMap::new(), false).len());
+        assert_eq!(0, r.get_deps(&FieldValue::Null, &HashMap::new(), &RecipeSet::new(), false).len());
     }
 
     #[test]
@@ -73,7 +73,7 @@ mod tests {
         let r = Raw::new(FieldValue::Int(123));
         let b = BookKeeperMock::new();
 
-        assert_eq!(Some(FieldValue::Int(123)), r.snapper_serialize(&FieldValue::Null, &HashMap::new(), &b, false));
+        assert_eq!(Ok(FieldValue::Int(123)), r.snapper_serialize(&FieldValue::Null, &HashMap::new(), &RecipeSet::new(), &b, false));
     }
 
     #[test]
@@ -81,9 +81,8 @@ mod tests {
         let r = Raw::new(FieldValue::Int(123));
         let b = BookKeeperMock::new();
 
-        let o = r.snapper_deserialize(&FieldValue::Null, &HashMap::new(), &b);
+        let o = r.snapper_deserialize(&FieldValue::Null, &HashMap::new(), &b, true);
 
-        assert!(o.is_some());
         let d = o.unwrap();
 
         assert_eq!(FieldValue::Int(123), d.value());
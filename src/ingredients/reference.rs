@@ -1,84 +1,154 @@
 use ingredients::ingredient::*;
 use contracts::*;
 use book_keeper::*;
+use recipe::RecipeSet;
 use std::vec::Vec;
 use std::string::String;
-use std::collections::HashMap;
 use tools::*;
+use error::{Error, Result};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RefConfig {
     #[serde(rename="type")]
     pub type_: EntityType,
+    #[serde(default)]
     pub optional_values: Vec<FieldValue>,
+    /// The fields holding the rest of the key when the referenced row is keyed by several
+    /// columns, in the order of its key, the field of the reference holding the first column
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub key_fields: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Reference {
-    #[serde(rename="type")]
-    type_: String,
     config: RefConfig,
 }
 
 impl Reference {
     pub fn new(type_: EntityType, optional_values: Vec<FieldValue>) -> Reference {
         Reference {
-            type_: "REF".to_string(),
             config: RefConfig {
                 type_,
                 optional_values,
+                key_fields: vec![],
             },
         }
     }
 
+    /// The type of the referenced entity
+    pub fn entity_type(&self) -> &EntityType {
+        &self.config.type_
+    }
+
     /// Specify which values should be treated as optional
     pub fn optional(&mut self, optional_values: Vec<FieldValue>) -> &mut Self {
         self.config.optional_values = optional_values.clone();
 
         self
     }
+
+    /// Refer to a row keyed by several columns, the given fields holding the rest of its key
+    pub fn composite(&mut self, key_fields: Vec<String>) -> &mut Self {
+        self.config.key_fields = key_fields;
+
+        self
+    }
+
+    /// The fields holding the rest of the key of the referenced row, empty unless it's keyed
+    /// by several columns
+    pub fn key_fields(&self) -> &[String] {
+        &self.config.key_fields
+    }
+
+    /// The value of the field followed by the values of the fields holding the rest of the key
+    fn key_values<'a>(&'a self, value: &'a FieldValue, row: &'a Row) -> Vec<&'a FieldValue> {
+        let mut values = vec![value];
+        values.extend(self.config.key_fields.iter().map(|field| row.get(field).unwrap_or(&FieldValue::Null)));
+
+        values
+    }
+
+    /// Read the id of the referenced row from the database, of the kind its recipe declares,
+    /// `None` if any part of it is null
+    fn referenced_id(&self, value: &FieldValue, row: &Row, recipes: &RecipeSet) -> Result<Option<Id>> {
+        recipes.read_key(&self.config.type_, &self.key_values(value, row))
+    }
+
+    /// Read the id of the referenced row from a snapshot, `None` if any part of it is null
+    fn snapshot_id(&self, value: &FieldValue, row: &Row) -> Result<Option<Id>> {
+        let mut ids = vec![];
+
+        for value in self.key_values(value, row) {
+            match try_snapshot_value_to_id(value)? {
+                Some(id) => ids.push(id),
+                None => return Ok(None),
+            }
+        }
+
+        match ids.len() {
+            1 => Ok(ids.pop()),
+            _ => Ok(Some(Id::Composite(ids))),
+        }
+    }
+
+    /// Ask the books for the id of the referenced row, and the value of the field referring to it
+    ///
+    /// A composite id is resolved as a whole, the field taking its first component.
+    fn resolve(&self, id: Id, books: &dyn BookKeeper) -> Result<(Dep, FieldValue)> {
+        let resolved = books.resolve_id(self.config.type_.clone(), id.clone(), false)
+            .ok_or_else(|| Error::DanglingReference(self.config.type_.clone(), id.clone()))?;
+
+        let value = match resolved {
+            Id::Composite(mut ids) if !self.config.key_fields.is_empty() && !ids.is_empty() => id_to_field_value(ids.remove(0)),
+            resolved => id_to_field_value(resolved),
+        };
+
+        Ok(((self.config.type_.clone(), id), value))
+    }
 }
 
 impl Ingredient for Reference {
     /// Get all dependencies of this ingredient
-    fn get_deps(&self, value: &FieldValue, _row: &Row, _circular: bool) -> Vec<Dep> {
+    fn get_deps(&self, value: &FieldValue, row: &Row, recipes: &RecipeSet, _circular: bool) -> Vec<Dep> {
         for v in &self.config.optional_values {
             if v == value {
                 return vec![];
             }
         }
 
-        field_value_to_id(&value)
+        self.referenced_id(value, row, recipes).ok().and_then(|id| id)
             .map(|v| vec![(self.config.type_.clone(), v)])
             .unwrap_or(vec![])
     }
 
     /// Let the ingredient determine the value of the field to store in a serialization
-    fn snapper_serialize(&self, value: &FieldValue, _row: &Row, books: &BookKeeper, _circular: bool) -> Option<FieldValue> {
+    fn snapper_serialize(&self, value: &FieldValue, row: &Row, recipes: &RecipeSet, books: &dyn BookKeeper, _circular: bool) -> Result<FieldValue> {
         for v in &self.config.optional_values {
             if v == value {
-                return Some(value.clone());
+                return Ok(value.clone());
             }
         }
 
-        field_value_to_id(&value)
-            .and_then(|id| books.resolve_id(self.config.type_.clone(), id, false))
-            .map(id_to_field_value)
+        match self.referenced_id(value, row, recipes)? {
+            Some(id) => self.resolve(id, books).map(|(_, resolved)| resolved),
+            None => Ok(FieldValue::Null),
+        }
     }
 
     /// Let the ingredient determine the value of the field to insert into the database when deserializing
-    fn snapper_deserialize(&self, value: &FieldValue, _row: &Row, books: &BookKeeper) -> Option<DeserializedValue> {
+    fn snapper_deserialize(&self, value: &FieldValue, row: &Row, books: &dyn BookKeeper, _circular: bool) -> Result<DeserializedValue> {
         for v in &self.config.optional_values {
             if v == value {
-                return Some(DeserializedValue::new(vec![], value.clone()));
+                return Ok(DeserializedValue::new(vec![], value.clone()));
             }
         }
 
-        field_value_to_id(&value)
-            .and_then(|id| books.resolve_id(self.config.type_.clone(), id.clone(), false)
-                .map(|resolved| (id_to_field_value(resolved), id))
-            )
-            .map(|(resolved, id)| DeserializedValue::new(vec![(self.config.type_.clone(), id)], resolved))
+        match self.snapshot_id(value, row)? {
+            Some(id) => self.resolve(id, books)
+                .map(|(dep, resolved)| DeserializedValue::new(vec![dep], resolved)),
+            None => Ok(DeserializedValue::new(vec![], FieldValue::Null)),
+        }
     }
 
     /// Should return an array with fields required to be able to UPDATE a row
@@ -90,6 +160,8 @@ impl Ingredient for Reference {
 #[cfg(test)]
 mod test {
     use super::*;
+    use recipe::{Recipe, PrimaryKey};
+    use std::collections::HashMap;
 
     struct BookKeeperMock {}
 
@@ -104,22 +176,33 @@ mod test {
         fn reset(&mut self) { unimplemented!() }
     }
 
+    /// Recipes of `foos` keyed by ids of the given kind
+    fn typed_recipes(kind: IdKind) -> RecipeSet {
+        let mut foos = Recipe::new(PrimaryKey::String(String::from("id")), HashMap::new());
+        foos.typed(kind);
+
+        let mut recipes = RecipeSet::new();
+        recipes.add(String::from("foos"), foos);
+
+        recipes
+    }
+
     #[test]
     fn it_gets_deps() {
         let mut r = Reference::new(String::from("foos"), vec![]);
 
-        let deps1 = r.get_deps(&FieldValue::Int(123), &HashMap::new(), false);
+        let deps1 = r.get_deps(&FieldValue::Int(123), &HashMap::new(), &RecipeSet::new(), false);
 
         assert_eq!(1, deps1.len());
         assert_eq!((String::from("foos"), Id::Int(123)), deps1[0]);
 
-        let deps2 = r.get_deps(&FieldValue::Null, &HashMap::new(), false);
+        let deps2 = r.get_deps(&FieldValue::Null, &HashMap::new(), &RecipeSet::new(), false);
 
         assert_eq!(0, deps2.len());
 
         r.optional(vec![FieldValue::Int(123)]);
 
-        let deps3 = r.get_deps(&FieldValue::Int(123), &HashMap::new(), false);
+        let deps3 = r.get_deps(&FieldValue::Int(123), &HashMap::new(), &RecipeSet::new(), false);
 
         assert_eq!(0, deps3.len());
     }
@@ -130,22 +213,22 @@ mod test {
         let mut r = Reference::new(String::from("foos"), vec![]);
         let b = BookKeeperMock::new();
 
-        let o1 = r.snapper_serialize(&FieldValue::Int(123), &HashMap::new(), &b, false);
+        let o1 = r.snapper_serialize(&FieldValue::Int(123), &HashMap::new(), &RecipeSet::new(), &b, false);
 
-        assert!(o1.is_some());
+        assert!(o1.is_ok());
         let serialized1 = o1.unwrap();
 
         assert_eq!(FieldValue::String(String::from("MOCK")), serialized1);
 
-        let o2 = r.snapper_serialize(&FieldValue::Null, &HashMap::new(), &b, false);
+        let o2 = r.snapper_serialize(&FieldValue::Null, &HashMap::new(), &RecipeSet::new(), &b, false);
 
-        assert!(o2.is_none());
+        assert_eq!(Ok(FieldValue::Null), o2);
 
         r.optional(vec![FieldValue::Null]);
 
-        let o3 = r.snapper_serialize(&FieldValue::Null, &HashMap::new(), &b, false);
+        let o3 = r.snapper_serialize(&FieldValue::Null, &HashMap::new(), &RecipeSet::new(), &b, false);
 
-        assert!(o3.is_some());
+        assert!(o3.is_ok());
         let serialized3 = o3.unwrap();
 
         assert_eq!(FieldValue::Null, serialized3);
@@ -157,13 +240,94 @@ mod test {
         let r = Reference::new(String::from("foos"), vec![]);
         let b = BookKeeperMock::new();
 
-        let o1 = r.snapper_deserialize(&FieldValue::Int(123), &HashMap::new(), &b);
+        let o1 = r.snapper_deserialize(&FieldValue::Int(123), &HashMap::new(), &b, true);
 
-        assert!(o1.is_some());
+        assert!(o1.is_ok());
         let deserialized1 = o1.unwrap();
 
         assert_eq!(1, deserialized1.deps().len());
         assert_eq!((String::from("foos"), Id::Int(123)), deserialized1.deps()[0]);
         assert_eq!(FieldValue::String(String::from("MOCK")), deserialized1.value());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn it_resolves_rows_keyed_by_several_columns() {
+        let mut r = Reference::new(String::from("members"), vec![]);
+        r.composite(vec![String::from("member_group")]);
+
+        let b = MemoryBookKeeper::new();
+        b.register(String::from("members"), Id::Composite(vec![Id::Int(1), Id::Int(7)]), Id::Composite(vec![Id::Int(10), Id::Int(7)]));
+
+        let mut row = HashMap::new();
+        row.insert(String::from("member_user"), FieldValue::Int(1));
+        row.insert(String::from("member_group"), FieldValue::Int(7));
+
+        let deserialized = r.snapper_deserialize(&FieldValue::Int(1), &row, &b, true).unwrap();
+
+        assert_eq!(vec![(String::from("members"), Id::Composite(vec![Id::Int(1), Id::Int(7)]))], deserialized.deps());
+        assert_eq!(FieldValue::Int(10), deserialized.value());
+
+        row.insert(String::from("member_group"), FieldValue::Int(8));
+
+        assert_eq!(
+            Err(Error::DanglingReference(String::from("members"), Id::Composite(vec![Id::Int(1), Id::Int(8)]))),
+            r.snapper_serialize(&FieldValue::Int(1), &row, &RecipeSet::new(), &b, false)
+        );
+
+        row.insert(String::from("member_group"), FieldValue::Null);
+
+        assert_eq!(Ok(FieldValue::Null), r.snapper_serialize(&FieldValue::Int(1), &row, &RecipeSet::new(), &b, false));
+        assert!(r.get_deps(&FieldValue::Int(1), &row, &RecipeSet::new(), false).is_empty());
+    }
+
+    #[test]
+    fn it_fails_on_dangling_references()
+    {
+        let r = Reference::new(String::from("foos"), vec![]);
+        let b = MemoryBookKeeper::new();
+
+        assert_eq!(
+            Err(Error::DanglingReference(String::from("foos"), Id::Int(123))),
+            r.snapper_serialize(&FieldValue::Int(123), &HashMap::new(), &RecipeSet::new(), &b, false)
+        );
+        assert!(r.snapper_deserialize(&FieldValue::Int(123), &HashMap::new(), &b, true).is_err());
+        assert_eq!(Err(Error::NegativeId(-1)), r.snapper_serialize(&FieldValue::Int(-1), &HashMap::new(), &RecipeSet::new(), &b, false));
+    }
+
+    #[test]
+    fn it_reads_ids_of_the_kind_the_referenced_recipe_declares() {
+        let r = Reference::new(String::from("foos"), vec![]);
+        let ulid = FieldValue::String(String::from("01ARZ3NDEKTSV4RRFFQ69G5FAV"));
+        let slug = FieldValue::String(String::from("some-slug"));
+        let b = MemoryBookKeeper::new();
+        b.register(String::from("foos"), Id::Signed(-5), Id::Int(1));
+        b.register(String::from("foos"), Id::Ulid(String::from("01ARZ3NDEKTSV4RRFFQ69G5FAV")), Id::Int(2));
+        b.register(String::from("foos"), Id::String(String::from("some-slug")), Id::Int(3));
+
+        let signed = typed_recipes(IdKind::SignedInt);
+        assert_eq!(vec![(String::from("foos"), Id::Signed(-5))], r.get_deps(&FieldValue::Int(-5), &HashMap::new(), &signed, false));
+        assert_eq!(Ok(FieldValue::Int(1)), r.snapper_serialize(&FieldValue::Int(-5), &HashMap::new(), &signed, &b, false));
+
+        let ulids = typed_recipes(IdKind::Ulid);
+        assert_eq!(vec![(String::from("foos"), Id::Ulid(String::from("01ARZ3NDEKTSV4RRFFQ69G5FAV")))], r.get_deps(&ulid, &HashMap::new(), &ulids, false));
+        assert_eq!(Ok(FieldValue::Int(2)), r.snapper_serialize(&ulid, &HashMap::new(), &ulids, &b, false));
+        assert_eq!(Err(Error::WrongIdKind(IdKind::Ulid, slug.clone())), r.snapper_serialize(&slug, &HashMap::new(), &ulids, &b, false));
+        assert!(r.get_deps(&slug, &HashMap::new(), &ulids, false).is_empty());
+
+        let strings = typed_recipes(IdKind::String);
+        assert_eq!(Ok(FieldValue::Int(3)), r.snapper_serialize(&slug, &HashMap::new(), &strings, &b, false));
+        assert_eq!(Err(Error::WrongIdKind(IdKind::String, FieldValue::Int(3))), r.snapper_serialize(&FieldValue::Int(3), &HashMap::new(), &strings, &b, false));
+    }
+
+    #[test]
+    fn it_deserializes_negative_ids_preserved_in_snapshots() {
+        let r = Reference::new(String::from("foos"), vec![]);
+        let b = MemoryBookKeeper::new();
+        b.register(String::from("foos"), Id::Signed(-5), Id::Int(1));
+
+        let deserialized = r.snapper_deserialize(&FieldValue::Int(-5), &HashMap::new(), &b, true).unwrap();
+
+        assert_eq!(vec![(String::from("foos"), Id::Signed(-5))], deserialized.deps());
+        assert_eq!(FieldValue::Int(1), deserialized.value());
+    }
+}
@@ -1,38 +1,42 @@
 use ingredients::ingredient::*;
 use contracts::*;
 use book_keeper::*;
+use recipe::RecipeSet;
+use error::Result;
 use std::vec::Vec;
 use std::string::String;
-use std::collections::HashMap;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ValueConfig {}
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Value {
-    #[serde(rename="type")]
-    type_: String,
     config: ValueConfig,
 }
 
 impl Value {
-    pub fn new() -> Value { Value { type_: "VALUE".to_string(), config: ValueConfig {} } }
+    pub fn new() -> Value { Value { config: ValueConfig {} } }
+}
+
+impl Default for Value {
+    fn default() -> Value { Value::new() }
 }
 
 impl Ingredient for Value {
     /// Get all dependencies of this ingredient
-    fn get_deps(&self, _value: &FieldValue, _row: &Row, _circular: bool) -> Vec<Dep> {
+    fn get_deps(&self, _value: &FieldValue, _row: &Row, _recipes: &RecipeSet, _circular: bool) -> Vec<Dep> {
         vec![]
     }
 
     /// Let the ingredient determine the value of the field to store in a serialization
-    fn snapper_serialize(&self, value: &FieldValue, _row: &Row, _books: &BookKeeper, _circular: bool) -> Option<FieldValue> {
-        Some(value.clone())
+    fn snapper_serialize(&self, value: &FieldValue, _row: &Row, _recipes: &RecipeSet, _books: &dyn BookKeeper, _circular: bool) -> Result<FieldValue> {
+        Ok(value.clone())
     }
 
     /// Let the ingredient determine the value of the field to insert into the database when deserializing
-    fn snapper_deserialize(&self, value: &FieldValue, _row: &Row, _books: &BookKeeper) -> Option<DeserializedValue> {
-        Some(DeserializedValue::new(vec![], value.clone()))
+    fn snapper_deserialize(&self, value: &FieldValue, _row: &Row, _books: &dyn BookKeeper, _circular: bool) -> Result<DeserializedValue> {
+        Ok(DeserializedValue::new(vec![], value.clone()))
     }
 
     /// Should return an array with fields required to be able to UPDATE a row
@@ -44,6 +48,7 @@ impl Ingredient for Value {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
 
     struct BookKeeperMock {}
 
@@ -60,9 +65,9 @@ mod tests {
     fn it_gets_deps() {
         let v = Value::new();
 
-        assert_eq!(0, v.get_deps(&FieldValue::Null, &HashMap::new(), false).len());
-        assert_eq!(0, v.get_deps(&FieldValue::Int(123), &HashMap::new(), false).len());
-        assert_eq!(0, v.get_deps(&FieldValue::String(String::from("Foo")), &HashMap::new(), false).len());
+        assert_eq!(0, v.get_deps(&FieldValue::Null, &HashMap::new(), &RecipeSet::new(), false).len());
+        assert_eq!(0, v.get_deps(&FieldValue::Int(123), &HashMap::new(), &RecipeSet::new(), false).len());
+        assert_eq!(0, v.get_deps(&FieldValue::String(String::from("Foo")), &HashMap::new(), &RecipeSet::new(), false).len());
     }
 
     #[test]
@@ -70,7 +75,7 @@ mod tests {
         let v = Value::new();
         let b = BookKeeperMock::new();
 
-        assert_eq!(Some(FieldValue::Int(123)), v.snapper_serialize(&FieldValue::Int(123), &HashMap::new(), &b, false));
+        assert_eq!(Ok(FieldValue::Int(123)), v.snapper_serialize(&FieldValue::Int(123), &HashMap::new(), &RecipeSet::new(), &b, false));
     }
 
     #[test]
@@ -78,9 +83,8 @@ mod tests {
         let v = Value::new();
         let b = BookKeeperMock::new();
 
-        let o = v.snapper_deserialize(&FieldValue::Int(123), &HashMap::new(), &b);
+        let o = v.snapper_deserialize(&FieldValue::Int(123), &HashMap::new(), &b, true);
 
-        assert!(o.is_some());
         let d = o.unwrap();
 
         assert_eq!(FieldValue::Int(123), d.value());
@@ -1,13 +1,49 @@
+//! Snapper serializes trees of database rows into portable snapshots and back, using recipes
+//! describing how every column of every table should be treated.
+//!
+//! ```
+//! use snapper::prelude::*;
+//!
+//! let recipes: RecipeSet = serde_json::from_str(r#"{
+//!     "foos": { "primary_key": "id", "ingredients": {
+//!         "id": { "type": "VALUE", "config": {} },
+//!         "name": { "type": "VALUE", "config": {} }
+//!     } }
+//! }"#).unwrap();
+//!
+//! let mut row = Row::new();
+//! row.insert(String::from("id"), FieldValue::Int(1));
+//! row.insert(String::from("name"), FieldValue::String(String::from("Foo")));
+//!
+//! let ops = snapper::serialize(&recipes, vec![(String::from("foos"), row)], &MemoryBookKeeper::new()).unwrap();
+//!
+//! assert_eq!(1, ops.len());
+//! ```
+
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
 extern crate serde_json;
 extern crate regex;
+extern crate uuid;
+extern crate chrono;
+extern crate base64;
+extern crate ciborium;
+extern crate flate2;
+extern crate crc32fast;
+extern crate sha2;
+#[cfg(feature="sqlite")]
+extern crate rusqlite;
+#[cfg(feature="postgres")]
+extern crate postgres;
+#[cfg(feature="postgres")]
+extern crate bytes;
 
-mod contracts;
-mod tools;
-mod book_keeper;
-mod ingredients {
+pub mod error;
+pub mod contracts;
+pub mod tools;
+pub mod book_keeper;
+pub mod ingredients {
     pub mod ingredient;
     pub mod value;
     pub mod reference;
@@ -16,4 +52,243 @@ mod ingredients {
     pub mod morph;
     pub mod matcher;
 }
-mod recipe;
\ No newline at end of file
+pub mod recipe;
+pub mod validation;
+pub mod sorter;
+pub mod serializer;
+pub mod deserializer;
+pub mod encoding;
+pub mod snapshot;
+pub mod stream;
+pub mod archive;
+pub mod source;
+pub mod sink;
+pub mod script;
+pub mod adapters {
+    #[cfg(feature="sqlite")]
+    pub mod sqlite;
+    #[cfg(feature="postgres")]
+    pub mod postgres;
+}
+#[cfg(test)]
+mod test_support;
+
+/// Everything needed to write recipes and run serializations
+pub mod prelude {
+    pub use contracts::{FieldValue, Row, Id, IdKind, EntityType, Dep, DeserializedValue, Operation, OperationKind};
+    pub use book_keeper::{BookKeeper, MemoryBookKeeper, IdStrategy};
+    pub use ingredients::ingredient::Ingredient as _;
+    pub use ingredients::value::Value;
+    pub use ingredients::raw::Raw;
+    pub use ingredients::reference::Reference;
+    pub use ingredients::circular::{Circular, CircularIngredient};
+    pub use ingredients::morph::Morph;
+    pub use ingredients::matcher::{Matcher, MatchIngredient, MatchPattern};
+    pub use recipe::{Recipe, RecipeSet, PrimaryKey, Ingredient};
+    pub use validation::{Diagnostic, Severity};
+    pub use serializer::Serializer;
+    pub use deserializer::Deserializer;
+    pub use encoding::Encoding;
+    pub use snapshot::Snapshot;
+    pub use stream::{SnapshotWriter, SnapshotReader, StreamSerializer, StreamDeserializer};
+    pub use archive::{ArchiveWriter, ArchiveReader};
+    pub use source::RowSource;
+    pub use sink::RowSink;
+    pub use script::Dialect;
+    #[cfg(feature="sqlite")]
+    pub use adapters::sqlite::{SqliteSource, SqliteSink};
+    #[cfg(feature="postgres")]
+    pub use adapters::postgres::{PostgresSource, PostgresSink};
+    pub use error::Error;
+}
+
+use contracts::{EntityType, Row, Operation};
+use book_keeper::BookKeeper;
+use recipe::RecipeSet;
+use serializer::Serializer;
+use deserializer::Deserializer;
+
+pub use error::{Error, Result};
+
+/// Serialize rows into a list of snapshot operations
+pub fn serialize<I>(recipes: &RecipeSet, rows: I, books: &dyn BookKeeper) -> Result<Vec<Operation>>
+    where I: IntoIterator<Item = (EntityType, Row)>
+{
+    let mut serializer = Serializer::new(recipes);
+
+    for (etype, row) in rows {
+        serializer.add(etype, row);
+    }
+
+    serializer.serialize(books)
+}
+
+/// Deserialize a list of snapshot operations into operations ready to be persisted
+pub fn deserialize(recipes: &RecipeSet, ops: &[Operation], books: &dyn BookKeeper) -> Result<Vec<Operation>> {
+    Deserializer::new(recipes).deserialize(ops, books)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::{recipes, row};
+    use prelude::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn it_round_trips_rows_through_a_snapshot() {
+        let recipes = recipes();
+
+        let ops = serialize(&recipes, vec![
+            (String::from("children"), row(vec![("id", FieldValue::Int(2)), ("parent_id", FieldValue::Int(1))])),
+            (String::from("parents"), row(vec![
+                ("id", FieldValue::Int(1)),
+                ("name", FieldValue::String(String::from("Foo"))),
+                ("favorite_child_id", FieldValue::Int(2)),
+            ])),
+        ], &MemoryBookKeeper::new()).unwrap();
+
+        let books = MemoryBookKeeper::new();
+        let persisted = deserialize(&recipes, &ops, &books).unwrap();
+
+        assert_eq!(3, persisted.len());
+
+        let parent_id = books.resolve_id(String::from("parents"), ops[0].id().unwrap(), false).unwrap();
+        let child_id = books.resolve_id(String::from("children"), ops[1].id().unwrap(), false).unwrap();
+
+        assert_eq!(OperationKind::Insert, persisted[0].op());
+        assert_eq!(Some(&FieldValue::String(String::from("Foo"))), persisted[0].row().get("name"));
+        assert_eq!(Some(&FieldValue::Null), persisted[0].row().get("favorite_child_id"));
+        assert_eq!(Some(&tools::id_to_field_value(parent_id.clone())), persisted[1].row().get("parent_id"));
+        assert_eq!(OperationKind::Update, persisted[2].op());
+        assert_eq!(Some(parent_id), persisted[2].id());
+        assert_eq!(Some(&tools::id_to_field_value(child_id)), persisted[2].row().get("favorite_child_id"));
+    }
+
+    #[test]
+    fn it_round_trips_circular_fields_with_a_fallback_value() {
+        let mut recipes = recipes();
+
+        let mut parent_ingredients = HashMap::new();
+        parent_ingredients.insert(String::from("favorite_child_id"), Ingredient::Circular(Circular::new(
+            CircularIngredient::Ref(Reference::new(String::from("children"), vec![])),
+            CircularIngredient::Raw(Raw::new(FieldValue::Int(0))),
+        )));
+
+        recipes.add(String::from("parents"), Recipe::new(PrimaryKey::String(String::from("id")), parent_ingredients));
+
+        let ops = serialize(&recipes, vec![
+            (String::from("children"), row(vec![("id", FieldValue::Int(2)), ("parent_id", FieldValue::Int(1))])),
+            (String::from("parents"), row(vec![("id", FieldValue::Int(1)), ("favorite_child_id", FieldValue::Int(2))])),
+        ], &MemoryBookKeeper::new()).unwrap();
+
+        assert_eq!(Some(&FieldValue::Int(0)), ops[0].row().get("favorite_child_id"));
+        assert_eq!(&[String::from("favorite_child_id")], ops[0].deferred());
+
+        let books = MemoryBookKeeper::new();
+        let persisted = deserialize(&recipes, &ops, &books).unwrap();
+
+        let child_id = books.resolve_id(String::from("children"), ops[1].id().unwrap(), false).unwrap();
+
+        assert_eq!(Some(&FieldValue::Int(0)), persisted[0].row().get("favorite_child_id"));
+        assert_eq!(Some(&tools::id_to_field_value(child_id)), persisted[2].row().get("favorite_child_id"));
+    }
+
+    #[test]
+    fn it_round_trips_rows_with_composite_keys() {
+        let mut recipes = recipes();
+
+        let mut member_ingredients = HashMap::new();
+        member_ingredients.insert(String::from("parent_id"), Ingredient::Ref(Reference::new(String::from("parents"), vec![])));
+        member_ingredients.insert(String::from("role"), Ingredient::Value(Value::new()));
+
+        recipes.add(String::from("members"), Recipe::new(
+            PrimaryKey::Composite(vec![String::from("parent_id"), String::from("group")]),
+            member_ingredients,
+        ));
+
+        let ops = serialize(&recipes, vec![
+            (String::from("members"), row(vec![
+                ("parent_id", FieldValue::Int(1)),
+                ("group", FieldValue::Int(7)),
+                ("role", FieldValue::String(String::from("owner"))),
+            ])),
+            (String::from("parents"), row(vec![("id", FieldValue::Int(1)), ("name", FieldValue::String(String::from("Foo")))])),
+        ], &MemoryBookKeeper::new()).unwrap();
+
+        let serialized_parent = ops[0].id().unwrap();
+
+        assert_eq!(Some(Id::Composite(vec![serialized_parent.clone(), Id::Int(7)])), ops[1].id());
+        assert_eq!(Some(&FieldValue::Int(7)), ops[1].row().get("group"));
+
+        let books = MemoryBookKeeper::new();
+        books.register(String::from("parents"), serialized_parent, Id::Int(10));
+
+        let persisted = Deserializer::new(&recipes).deserialize_operation(&ops[1], &books).unwrap();
+
+        assert_eq!(Some(Id::Composite(vec![Id::Int(10), Id::Int(7)])), persisted.id());
+        assert_eq!(Some(&FieldValue::Int(10)), persisted.row().get("parent_id"));
+        assert_eq!(Some(&FieldValue::Int(7)), persisted.row().get("group"));
+        assert_eq!(Some(&FieldValue::String(String::from("owner"))), persisted.row().get("role"));
+    }
+
+    #[test]
+    fn it_round_trips_references_to_rows_with_composite_keys() {
+        let mut recipes = recipes();
+
+        let mut member_ingredients = HashMap::new();
+        member_ingredients.insert(String::from("parent_id"), Ingredient::Ref(Reference::new(String::from("parents"), vec![])));
+        member_ingredients.insert(String::from("group"), Ingredient::Value(Value::new()));
+
+        let mut member_ref = Reference::new(String::from("members"), vec![]);
+        member_ref.composite(vec![String::from("member_group")]);
+
+        let mut note_ingredients = HashMap::new();
+        note_ingredients.insert(String::from("member_parent_id"), Ingredient::Ref(member_ref));
+        note_ingredients.insert(String::from("member_group"), Ingredient::Value(Value::new()));
+
+        recipes
+            .add(String::from("members"), Recipe::new(PrimaryKey::Composite(vec![String::from("parent_id"), String::from("group")]), member_ingredients))
+            .add(String::from("notes"), Recipe::new(PrimaryKey::String(String::from("id")), note_ingredients));
+
+        let note = |group| row(vec![("id", FieldValue::Int(5)), ("member_parent_id", FieldValue::Int(1)), ("member_group", FieldValue::Int(group))]);
+        let rows = |group| vec![
+            (String::from("notes"), note(group)),
+            (String::from("members"), row(vec![("parent_id", FieldValue::Int(1)), ("group", FieldValue::Int(7))])),
+            (String::from("parents"), row(vec![("id", FieldValue::Int(1))])),
+        ];
+
+        assert_eq!(
+            Err(Error::DanglingReference(String::from("members"), Id::Composite(vec![Id::Int(1), Id::Int(8)]))),
+            serialize(&recipes, rows(8), &MemoryBookKeeper::new())
+        );
+
+        let ops = serialize(&recipes, rows(7), &MemoryBookKeeper::new()).unwrap();
+
+        assert_eq!(vec!["parents", "members", "notes"], ops.iter().map(|op| op.type_().as_str()).collect::<Vec<&str>>());
+        assert_eq!(Some(&tools::id_to_field_value(ops[0].id().unwrap())), ops[2].row().get("member_parent_id"));
+
+        let books = MemoryBookKeeper::new();
+        let persisted = deserialize(&recipes, &ops, &books).unwrap();
+
+        let parent_id = books.resolve_id(String::from("parents"), ops[0].id().unwrap(), false).unwrap();
+
+        assert_eq!(Some(&tools::id_to_field_value(parent_id)), persisted[2].row().get("member_parent_id"));
+        assert_eq!(Some(&FieldValue::Int(7)), persisted[2].row().get("member_group"));
+    }
+
+    #[test]
+    fn it_serializes_the_same_rows_the_same_way_with_derived_ids() {
+        let recipes = recipes();
+        let namespace = uuid::Uuid::nil();
+        let rows = || vec![
+            (String::from("children"), row(vec![("id", FieldValue::Int(2)), ("parent_id", FieldValue::Int(1))])),
+            (String::from("parents"), row(vec![("id", FieldValue::Int(1)), ("favorite_child_id", FieldValue::Int(2))])),
+        ];
+
+        let first = serialize(&recipes, rows(), &MemoryBookKeeper::with_strategy(IdStrategy::UuidV5(namespace))).unwrap();
+        let second = serialize(&recipes, rows(), &MemoryBookKeeper::with_strategy(IdStrategy::UuidV5(namespace))).unwrap();
+
+        assert_eq!(first, second);
+    }
+}
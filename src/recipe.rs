@@ -1,48 +1,341 @@
+use ingredients::ingredient;
 use ingredients::value::*;
 use ingredients::raw::*;
 use ingredients::reference::*;
 use ingredients::circular::*;
 use ingredients::morph::*;
+use ingredients::matcher::*;
+use contracts::*;
+use book_keeper::*;
+use error;
+use tools::{id_to_field_value, try_field_value_to_id, try_field_value_to_typed_id};
+use encoding::{Encoding, encode, decode};
+use std::io::{Read, Write};
+use std::vec::Vec;
 use std::string::String;
 use std::collections::HashMap;
-use serde_json;
+use std::fmt;
+use serde::de::{self, Deserializer, Visitor, MapAccess};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
-enum PrimaryKey {
+pub enum PrimaryKey {
     Null,
     String(String),
+    /// A key made up of several columns, such as `["user_id", "group_id"]` on a pivot table
+    Composite(Vec<String>),
 }
 
+impl PrimaryKey {
+    /// The columns making up the key
+    pub fn columns(&self) -> Vec<&String> {
+        match self {
+            PrimaryKey::Null => vec![],
+            PrimaryKey::String(pk) => vec![pk],
+            PrimaryKey::Composite(pks) => pks.iter().collect(),
+        }
+    }
+
+    /// The key columns with the values making up the given id, `None` if the id doesn't fit
+    pub fn key_row(&self, id: &Id) -> Option<Row> {
+        match (self, id) {
+            (PrimaryKey::String(pk), _) => Some(vec![(pk.clone(), id_to_field_value(id.clone()))].into_iter().collect()),
+            (PrimaryKey::Composite(pks), Id::Composite(ids)) if pks.len() == ids.len() => Some(pks.iter()
+                .cloned()
+                .zip(ids.iter().map(|id| id_to_field_value(id.clone())))
+                .collect()),
+            _ => None,
+        }
+    }
+}
+
+/// An ingredient is written as `{ "type": "REF", "config": { ... } }`, where the type decides
+/// how the config is read
 #[derive(Debug, Serialize, Deserialize)]
-#[serde(untagged)]
-enum Ingredient {
+#[serde(tag="type", content="config")]
+pub enum Ingredient {
+    #[serde(rename="VALUE")]
     Value(Value),
+    #[serde(rename="RAW")]
     Raw(Raw),
+    #[serde(rename="REF")]
     Ref(Reference),
+    #[serde(rename="CIRCULAR")]
     Circular(Circular),
+    #[serde(rename="MORPH")]
     Morph(Morph),
+    #[serde(rename="MATCH")]
+    Match(Box<Matcher>),
+}
+
+impl Ingredient {
+    /// Whether the field can be deferred to an UPDATE after all rows are inserted, because a
+    /// circular ingredient is part of it
+    pub fn is_circular(&self) -> bool {
+        match self {
+            Ingredient::Circular(_) => true,
+            Ingredient::Match(m) => m.branches().iter().any(|b| b.is_circular()),
+            _ => false,
+        }
+    }
+}
+
+impl ingredient::Ingredient for Ingredient {
+    /// Get all dependencies of this ingredient
+    fn get_deps(&self, value: &FieldValue, row: &Row, recipes: &RecipeSet, circular: bool) -> Vec<Dep> {
+        match self {
+            Ingredient::Value(v) => v.get_deps(value, row, recipes, circular),
+            Ingredient::Raw(r) => r.get_deps(value, row, recipes, circular),
+            Ingredient::Ref(r) => r.get_deps(value, row, recipes, circular),
+            Ingredient::Circular(c) => c.get_deps(value, row, recipes, circular),
+            Ingredient::Morph(m) => m.get_deps(value, row, recipes, circular),
+            Ingredient::Match(m) => m.get_deps(value, row, recipes, circular),
+        }
+    }
+
+    /// Let the ingredient determine the value of the field to store in a serialization
+    fn snapper_serialize(&self, value: &FieldValue, row: &Row, recipes: &RecipeSet, books: &dyn BookKeeper, circular: bool) -> error::Result<FieldValue> {
+        match self {
+            Ingredient::Value(v) => v.snapper_serialize(value, row, recipes, books, circular),
+            Ingredient::Raw(r) => r.snapper_serialize(value, row, recipes, books, circular),
+            Ingredient::Ref(r) => r.snapper_serialize(value, row, recipes, books, circular),
+            Ingredient::Circular(c) => c.snapper_serialize(value, row, recipes, books, circular),
+            Ingredient::Morph(m) => m.snapper_serialize(value, row, recipes, books, circular),
+            Ingredient::Match(m) => m.snapper_serialize(value, row, recipes, books, circular),
+        }
+    }
+
+    /// Let the ingredient determine the value of the field to insert into the database when deserializing
+    fn snapper_deserialize(&self, value: &FieldValue, row: &Row, books: &dyn BookKeeper, circular: bool) -> error::Result<DeserializedValue> {
+        match self {
+            Ingredient::Value(v) => v.snapper_deserialize(value, row, books, circular),
+            Ingredient::Raw(r) => r.snapper_deserialize(value, row, books, circular),
+            Ingredient::Ref(r) => r.snapper_deserialize(value, row, books, circular),
+            Ingredient::Circular(c) => c.snapper_deserialize(value, row, books, circular),
+            Ingredient::Morph(m) => m.snapper_deserialize(value, row, books, circular),
+            Ingredient::Match(m) => m.snapper_deserialize(value, row, books, circular),
+        }
+    }
+
+    /// Should return an array with fields required to be able to UPDATE a row
+    fn get_required_extra_fields(&self) -> Vec<String> {
+        match self {
+            Ingredient::Value(v) => v.get_required_extra_fields(),
+            Ingredient::Raw(r) => r.get_required_extra_fields(),
+            Ingredient::Ref(r) => r.get_required_extra_fields(),
+            Ingredient::Circular(c) => c.get_required_extra_fields(),
+            Ingredient::Morph(m) => m.get_required_extra_fields(),
+            Ingredient::Match(m) => m.get_required_extra_fields(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Recipe {
     primary_key: PrimaryKey,
+    /// The kind of ids a single-column primary key holds, any integer or text if not declared
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    id_kind: Option<IdKind>,
+    #[serde(deserialize_with="deserialize_ingredients")]
     ingredients: HashMap<String, Ingredient>
 }
 
+/// Deserialize the ingredients of a recipe, naming the field of the ingredient on failure
+fn deserialize_ingredients<'de, D>(deserializer: D) -> Result<HashMap<String, Ingredient>, D::Error>
+    where D: Deserializer<'de>
+{
+    struct IngredientsVisitor;
+
+    impl<'de> Visitor<'de> for IngredientsVisitor {
+        type Value = HashMap<String, Ingredient>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a map of fields to ingredients")
+        }
+
+        fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where A: MapAccess<'de>
+        {
+            let mut ingredients = HashMap::new();
+
+            while let Some(field) = map.next_key::<String>()? {
+                let ingredient = map.next_value::<Ingredient>()
+                    .map_err(|e| de::Error::custom(format!("Invalid ingredient for field `{}`: {}", field, e)))?;
+
+                ingredients.insert(field, ingredient);
+            }
+
+            Ok(ingredients)
+        }
+    }
+
+    deserializer.deserialize_map(IngredientsVisitor)
+}
+
 impl Recipe {
-    fn primary_key(&self) -> &PrimaryKey {
+    pub fn new(primary_key: PrimaryKey, ingredients: HashMap<String, Ingredient>) -> Recipe {
+        Recipe {
+            primary_key,
+            id_kind: None,
+            ingredients,
+        }
+    }
+
+    pub fn primary_key(&self) -> &PrimaryKey {
         &self.primary_key
     }
 
-    fn ingredient(&self, table: &String) -> Option<&Ingredient> {
-        self.ingredients.get(table)
+    /// Declare the kind of ids the primary key holds
+    pub fn typed(&mut self, id_kind: IdKind) -> &mut Self {
+        self.id_kind = Some(id_kind);
+
+        self
+    }
+
+    pub fn id_kind(&self) -> Option<IdKind> {
+        self.id_kind
+    }
+
+    /// Read the value of a single-column primary key as an id, of the declared kind if any
+    pub fn read_id(&self, value: &FieldValue) -> error::Result<Option<Id>> {
+        match self.id_kind {
+            Some(kind) => try_field_value_to_typed_id(value, kind),
+            None => try_field_value_to_id(value),
+        }
+    }
+
+    /// The key columns, with the type of the row each refers to through a `REF` ingredient
+    pub fn key_types(&self) -> Vec<(&String, Option<&EntityType>)> {
+        self.primary_key.columns()
+            .into_iter()
+            .map(|pk| match self.ingredients.get(pk) {
+                Some(Ingredient::Ref(r)) => (pk, Some(r.entity_type())),
+                _ => (pk, None),
+            })
+            .collect()
+    }
+
+    pub fn ingredient(&self, field: &str) -> Option<&Ingredient> {
+        self.ingredients.get(field)
+    }
+
+    pub fn ingredients(&self) -> &HashMap<String, Ingredient> {
+        &self.ingredients
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RecipeSet {
+    recipes: HashMap<EntityType, Recipe>,
+}
+
+impl RecipeSet {
+    pub fn new() -> RecipeSet {
+        RecipeSet { recipes: HashMap::new() }
+    }
+
+    /// Add the recipe describing rows of the given type
+    pub fn add(&mut self, etype: EntityType, recipe: Recipe) -> &mut Self {
+        self.recipes.insert(etype, recipe);
+
+        self
+    }
+
+    pub fn get(&self, etype: &str) -> Option<&Recipe> {
+        self.recipes.get(etype)
+    }
+
+    pub fn contains(&self, etype: &str) -> bool {
+        self.recipes.contains_key(etype)
+    }
+
+    /// Read a value as the id of a row of the given type, of the kind its recipe declares if any
+    pub fn read_id(&self, etype: &str, value: &FieldValue) -> error::Result<Option<Id>> {
+        match self.get(etype) {
+            Some(recipe) => recipe.read_id(value),
+            None => try_field_value_to_id(value),
+        }
+    }
+
+    /// Read the values of the key columns of a row of the given type as its id, `None` if any
+    /// of them is null
+    ///
+    /// Columns referring to another row hold ids of the kind that row's recipe declares.
+    pub fn read_key(&self, etype: &str, values: &[&FieldValue]) -> error::Result<Option<Id>> {
+        let composite = match self.get(etype) {
+            Some(recipe) => matches!(recipe.primary_key(), PrimaryKey::Composite(_)),
+            None => values.len() > 1,
+        };
+
+        if let ([value], false) = (values, composite) {
+            return self.read_id(etype, value);
+        }
+
+        let types = self.get(etype).map(|recipe| recipe.key_types()).unwrap_or_default();
+        let mut ids = vec![];
+
+        for (i, value) in values.iter().enumerate() {
+            let id = match types.get(i) {
+                Some((_, Some(component_type))) => self.read_id(component_type, value)?,
+                _ => try_field_value_to_id(value)?,
+            };
+
+            match id {
+                Some(id) => ids.push(id),
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some(Id::Composite(ids)))
+    }
+
+    /// Read the id of a row of the given type, `None` if its recipe has no primary key or a key
+    /// column doesn't hold a value
+    pub fn row_id(&self, etype: &str, row: &Row) -> error::Result<Option<Id>> {
+        let columns = match self.get(etype) {
+            Some(recipe) => recipe.primary_key().columns(),
+            None => return Err(error::Error::MissingRecipe(etype.to_string())),
+        };
+
+        if columns.is_empty() {
+            return Ok(None);
+        }
+
+        let values: Vec<&FieldValue> = columns.iter()
+            .map(|column| row.get(*column).unwrap_or(&FieldValue::Null))
+            .collect();
+
+        self.read_key(etype, &values)
+    }
+
+    /// All entity types with a recipe, sorted
+    pub fn entity_types(&self) -> Vec<&EntityType> {
+        let mut etypes: Vec<&EntityType> = self.recipes.keys().collect();
+        etypes.sort();
+
+        etypes
+    }
+
+    /// Read recipes in either encoding
+    pub fn read<R: Read>(mut reader: R) -> error::Result<RecipeSet> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).map_err(error::Error::from)?;
+
+        decode(&bytes)
+    }
+
+    /// Write the recipes in the given encoding
+    pub fn write<W: Write>(&self, writer: W, encoding: Encoding) -> error::Result<()> {
+        encode(self, writer, encoding)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ingredients::ingredient::Ingredient as _;
+    use serde_json;
 
     #[test]
     fn it_should_reserialize() {
@@ -76,5 +369,313 @@ mod tests {
         let r: Recipe = serde_json::from_str(json).unwrap();
 
         let _back = serde_json::to_string(&r).unwrap();
+
+        assert!(matches!(r.ingredient("name"), Some(Ingredient::Value(_))));
+        assert!(matches!(r.ingredient("foo"), Some(Ingredient::Raw(_))));
+        assert!(matches!(r.ingredient("foo_id"), Some(Ingredient::Ref(_))));
+        assert!(matches!(r.ingredient("bar_id"), Some(Ingredient::Circular(_))));
+        assert!(matches!(r.ingredient("bazable_id"), Some(Ingredient::Morph(_))));
+    }
+
+    #[test]
+    fn it_should_read_composite_primary_keys() {
+        let json = r#"{
+            "primary_key": ["user_id", "group_id"],
+            "ingredients": {
+                "user_id": { "type": "REF", "config": { "type": "users" } },
+                "group_id": { "type": "VALUE", "config": {} }
+            }
+        }"#;
+
+        let r: Recipe = serde_json::from_str(json).unwrap();
+
+        assert_eq!(vec!["user_id", "group_id"], r.primary_key().columns());
+        assert_eq!(vec![
+            (&String::from("user_id"), Some(&String::from("users"))),
+            (&String::from("group_id"), None),
+        ], r.key_types());
+
+        assert_eq!(r#"["user_id","group_id"]"#, serde_json::to_string(r.primary_key()).unwrap());
+
+        let mut users = Recipe::new(PrimaryKey::String(String::from("id")), HashMap::new());
+        users.typed(IdKind::SignedInt);
+
+        let mut recipes = RecipeSet::new();
+        recipes.add(String::from("members"), r).add(String::from("users"), users);
+
+        let mut row = Row::new();
+        row.insert(String::from("user_id"), FieldValue::Int(-1));
+        row.insert(String::from("group_id"), FieldValue::Int(2));
+
+        let id = recipes.row_id("members", &row).unwrap().unwrap();
+        let primary_key = recipes.get("members").unwrap().primary_key();
+
+        assert_eq!(Id::Composite(vec![Id::Signed(-1), Id::Int(2)]), id);
+        assert_eq!(Some(row.clone()), primary_key.key_row(&id));
+        assert_eq!(None, primary_key.key_row(&Id::Int(1)));
+
+        row.insert(String::from("group_id"), FieldValue::Int(-2));
+
+        assert_eq!(Err(error::Error::NegativeId(-2)), recipes.row_id("members", &row));
+
+        row.remove("group_id");
+
+        assert_eq!(Ok(None), recipes.row_id("members", &row));
+    }
+
+    #[test]
+    fn it_should_read_the_id_kind() {
+        let r: Recipe = serde_json::from_str(r#"{ "primary_key": "id", "id_kind": "signed_int", "ingredients": {} }"#).unwrap();
+
+        assert_eq!(Some(IdKind::SignedInt), r.id_kind());
+        assert_eq!(Ok(Some(Id::Signed(-5))), r.read_id(&FieldValue::Int(-5)));
+        assert!(r.read_id(&FieldValue::String(String::from("5"))).is_err());
+
+        let r: Recipe = serde_json::from_str(r#"{ "primary_key": "id", "ingredients": {} }"#).unwrap();
+
+        assert_eq!(None, r.id_kind());
+        assert_eq!(r#"{"primary_key":"id","ingredients":{}}"#, serde_json::to_string(&r).unwrap());
+    }
+
+    #[test]
+    fn it_should_default_optional_values() {
+        let json = r#"{
+            "primary_key": "id",
+            "ingredients": {
+                "foo_id": { "type": "REF", "config": { "type": "foos" } }
+            }
+        }"#;
+
+        let r: Recipe = serde_json::from_str(json).unwrap();
+        let foo = r.ingredient("foo_id").unwrap();
+
+        assert_eq!(0, foo.get_deps(&FieldValue::Null, &HashMap::new(), &RecipeSet::new(), false).len());
+        assert_eq!(1, foo.get_deps(&FieldValue::Int(1), &HashMap::new(), &RecipeSet::new(), false).len());
+    }
+
+    #[test]
+    fn it_should_name_the_field_with_an_unknown_type() {
+        let json = r#"{
+            "primary_key": "id",
+            "ingredients": {
+                "name": { "type": "VALUE", "config": {} },
+                "foo_id": { "type": "REFERENCE", "config": { "type": "foos", "optional_values": [] } }
+            }
+        }"#;
+
+        let err = serde_json::from_str::<Recipe>(json).unwrap_err().to_string();
+
+        assert!(err.starts_with("Invalid ingredient for field `foo_id`: unknown variant `REFERENCE`"), "{}", err);
+    }
+
+    #[test]
+    fn it_should_name_the_field_with_an_invalid_config() {
+        let json = r#"{
+            "primary_key": "id",
+            "ingredients": {
+                "foo_id": { "type": "REF", "config": { "optional_values": [] } }
+            }
+        }"#;
+
+        let err = serde_json::from_str::<Recipe>(json).unwrap_err().to_string();
+
+        assert!(err.starts_with("Invalid ingredient for field `foo_id`: missing field `type`"), "{}", err);
+    }
+
+    #[test]
+    fn it_should_reserialize_match_ingredients() {
+        let json = r#"{
+            "primary_key": "id",
+            "ingredients": {
+                "thing_type": { "type": "VALUE", "config": {} },
+                "thing_id": { "type": "MATCH", "config": {
+                    "field": "thing_type",
+                    "matcher": {
+                        "field": "thing_type",
+                        "on": {
+                            "FOO": { "type": "REF", "config": { "type": "foos", "optional_values": [] } }
+                        },
+                        "patterns": [
+                            { "pattern": "^BA[RZ]$", "ingredient": { "type": "REF", "config": { "type": "bars", "optional_values": [] } } },
+                            { "pattern": "^BA", "ingredient": { "type": "REF", "config": { "type": "bas", "optional_values": [] } } }
+                        ],
+                        "default": { "type": "RAW", "config": { "value": null } }
+                    }
+                } },
+                "other_id": { "type": "CIRCULAR", "config": {
+                    "ingredient": { "type": "MATCH", "config": {
+                        "field": "thing_type",
+                        "matcher": {
+                            "field": "thing_type",
+                            "on": {},
+                            "patterns": [],
+                            "default": { "type": "VALUE", "config": {} }
+                        }
+                    } },
+                    "fallback": { "type": "RAW", "config": { "value": null } }
+                } }
+            }
+        }"#;
+
+        let r: Recipe = serde_json::from_str(json).unwrap();
+
+        assert!(matches!(r.ingredient("thing_id"), Some(Ingredient::Match(_))));
+        assert!(matches!(r.ingredient("other_id"), Some(Ingredient::Circular(_))));
+        assert_eq!(serde_json::from_str::<serde_json::Value>(json).unwrap(), serde_json::to_value(&r).unwrap());
+
+        let thing = r.ingredient("thing_id").unwrap();
+        let mut row: Row = HashMap::new();
+
+        row.insert(String::from("thing_type"), FieldValue::String(String::from("FOO")));
+        assert_eq!(vec![(String::from("foos"), Id::Int(1))], thing.get_deps(&FieldValue::Int(1), &row, &RecipeSet::new(), false));
+
+        row.insert(String::from("thing_type"), FieldValue::String(String::from("BAZ")));
+        assert_eq!(vec![(String::from("bars"), Id::Int(1))], thing.get_deps(&FieldValue::Int(1), &row, &RecipeSet::new(), false));
+
+        row.insert(String::from("thing_type"), FieldValue::String(String::from("QUX")));
+        assert_eq!(0, thing.get_deps(&FieldValue::Int(1), &row, &RecipeSet::new(), false).len());
+        assert_eq!(vec![String::from("thing_type")], r.ingredient("other_id").unwrap().get_required_extra_fields());
+    }
+
+    #[test]
+    fn it_should_reject_invalid_patterns_at_load() {
+        let json = r#"{
+            "primary_key": "id",
+            "ingredients": {
+                "thing_id": { "type": "MATCH", "config": {
+                    "field": "thing_type",
+                    "matcher": {
+                        "field": "thing_type",
+                        "on": {},
+                        "patterns": { "^(unclosed$": { "type": "VALUE", "config": {} } },
+                        "default": null
+                    }
+                } }
+            }
+        }"#;
+
+        let err = serde_json::from_str::<Recipe>(json).unwrap_err().to_string();
+
+        assert!(err.starts_with("Invalid ingredient for field `thing_id`: Invalid pattern `^(unclosed$`"), "{}", err);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn it_should_accept_patterns_as_a_map_in_written_order() {
+        let json = r#"{
+            "primary_key": "id",
+            "ingredients": {
+                "thing_id": { "type": "MATCH", "config": {
+                    "field": "thing_type",
+                    "matcher": {
+                        "field": "thing_type",
+                        "on": {},
+                        "patterns": {
+                            "^B": { "type": "REF", "config": { "type": "bs" } },
+                            "^BA": { "type": "REF", "config": { "type": "bas" } },
+                            "^A": { "type": "REF", "config": { "type": "as" } }
+                        },
+                        "default": null
+                    }
+                } }
+            }
+        }"#;
+
+        let r: Recipe = serde_json::from_str(json).unwrap();
+
+        match r.ingredient("thing_id") {
+            Some(Ingredient::Match(m)) => assert_eq!(vec!["^B", "^BA", "^A"], m.patterns()),
+            _ => panic!("Expected a MATCH ingredient"),
+        }
+
+        let mut row: Row = HashMap::new();
+        row.insert(String::from("thing_type"), FieldValue::String(String::from("BAR")));
+
+        for _ in 0..10 {
+            assert_eq!(vec![(String::from("bs"), Id::Int(1))], r.ingredient("thing_id").unwrap().get_deps(&FieldValue::Int(1), &row, &RecipeSet::new(), false));
+        }
+    }
+
+    #[test]
+    fn it_should_compose_ingredients_arbitrarily() {
+        let json = r#"{
+            "primary_key": "id",
+            "ingredients": {
+                "owner_id": { "type": "CIRCULAR", "config": {
+                    "ingredient": { "type": "MORPH", "config": {
+                        "field": "owner_type",
+                        "morph_mapper": { "morph_map": { "USER": "users" } }
+                    } },
+                    "fallback": { "type": "RAW", "config": { "value": null } }
+                } },
+                "target_id": { "type": "MATCH", "config": {
+                    "field": "target_kind",
+                    "matcher": {
+                        "field": "target_kind",
+                        "on": {
+                            "SELF": { "type": "CIRCULAR", "config": {
+                                "ingredient": { "type": "REF", "config": { "type": "nodes" } },
+                                "fallback": { "type": "RAW", "config": { "value": null } }
+                            } }
+                        },
+                        "patterns": [
+                            { "pattern": "^OWNER", "ingredient": { "type": "MORPH", "config": {
+                                "field": "owner_type",
+                                "morph_mapper": { "morph_map": { "USER": "users" } }
+                            } } }
+                        ],
+                        "default": null
+                    }
+                } }
+            }
+        }"#;
+
+        let r: Recipe = serde_json::from_str(json).unwrap();
+        let owner = r.ingredient("owner_id").unwrap();
+        let target = r.ingredient("target_id").unwrap();
+        let mut row: Row = HashMap::new();
+
+        row.insert(String::from("owner_type"), FieldValue::String(String::from("USER")));
+        row.insert(String::from("target_kind"), FieldValue::String(String::from("SELF")));
+
+        assert!(owner.is_circular());
+        assert!(target.is_circular());
+        assert_eq!(vec![(String::from("users"), Id::Int(1))], owner.get_deps(&FieldValue::Int(1), &row, &RecipeSet::new(), true));
+        assert_eq!(0, owner.get_deps(&FieldValue::Int(1), &row, &RecipeSet::new(), false).len());
+        assert_eq!(vec![(String::from("nodes"), Id::Int(2))], target.get_deps(&FieldValue::Int(2), &row, &RecipeSet::new(), true));
+        assert_eq!(0, target.get_deps(&FieldValue::Int(2), &row, &RecipeSet::new(), false).len());
+
+        assert_eq!(vec![String::from("owner_type")], owner.get_required_extra_fields());
+        assert_eq!(vec![String::from("target_kind"), String::from("owner_type")], target.get_required_extra_fields());
+    }
+
+    #[test]
+    fn it_should_round_trip_recipe_sets_in_cbor() {
+        let json = serde_json::json!({
+            "foos": {
+                "primary_key": "id",
+                "ingredients": {
+                    "kind": { "type": "VALUE", "config": {} },
+                    "bar_id": { "type": "CIRCULAR", "config": {
+                        "ingredient": { "type": "MATCH", "config": {
+                            "field": "kind",
+                            "matcher": {
+                                "field": "kind",
+                                "on": { "BAR": { "type": "REF", "config": { "type": "bars", "optional_values": [0] } } },
+                                "patterns": [],
+                                "default": null
+                            }
+                        } },
+                        "fallback": { "type": "RAW", "config": { "value": { "$binary": "AQI=" } } }
+                    } }
+                }
+            }
+        });
+        let recipes: RecipeSet = serde_json::from_value(json.clone()).unwrap();
+
+        let mut written = vec![];
+        recipes.write(&mut written, Encoding::Cbor).unwrap();
+
+        assert_eq!(Encoding::Cbor, Encoding::detect(&written));
+        assert_eq!(json, serde_json::to_value(RecipeSet::read(&written[..]).unwrap()).unwrap());
+    }
+}
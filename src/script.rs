@@ -0,0 +1,459 @@
+//! SQL scripts applying a snapshot, for when the target database can't be reached directly
+//!
+//! The database assigns the primary keys as the script runs, so references to inserted rows
+//! are written as placeholders: rows in a temporary `snapper_ids` table in SQLite and
+//! PostgreSQL, user variables in MySQL. In PostgreSQL the keys are taken from the sequences
+//! of the primary key columns before inserting.
+
+use contracts::*;
+use book_keeper::{MemoryBookKeeper, IdStrategy};
+use recipe::{RecipeSet, Recipe, PrimaryKey};
+use deserializer::Deserializer;
+use tools::id_to_field_value;
+use error::{Error, Result};
+use std::vec::Vec;
+use std::string::String;
+use std::collections::HashMap;
+use std::io::Write;
+use chrono::{SecondsFormat, Utc};
+
+/// The SQL dialect to write
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Dialect {
+    Sqlite,
+    Postgres,
+    MySql,
+}
+
+impl Dialect {
+    /// Quote a table or column name
+    pub fn quote(&self, name: &str) -> String {
+        match self {
+            Dialect::Sqlite | Dialect::Postgres => format!("\"{}\"", name.replace('"', "\"\"")),
+            Dialect::MySql => format!("`{}`", name.replace('`', "``")),
+        }
+    }
+
+    /// Write a value as a literal
+    pub fn literal(&self, value: &FieldValue) -> Result<String> {
+        Ok(match value {
+            FieldValue::Null => String::from("NULL"),
+            FieldValue::Int(v) => v.to_string(),
+            FieldValue::String(s) => self.string(s),
+            FieldValue::Bool(b) => match (self, b) {
+                (Dialect::Sqlite, true) => String::from("1"),
+                (Dialect::Sqlite, false) => String::from("0"),
+                (_, true) => String::from("TRUE"),
+                (_, false) => String::from("FALSE"),
+            },
+            FieldValue::Float(v) if v.is_finite() => format!("{:?}", v),
+            FieldValue::Float(v) => match self {
+                Dialect::Postgres if v.is_nan() => String::from("'NaN'"),
+                Dialect::Postgres if *v > 0.0 => String::from("'Infinity'"),
+                Dialect::Postgres => String::from("'-Infinity'"),
+                Dialect::Sqlite if v.is_infinite() && *v > 0.0 => String::from("9e999"),
+                Dialect::Sqlite if v.is_infinite() => String::from("-9e999"),
+                _ => return Err(Error::InvalidValue(format!("{:?} can't be written in {:?}", v, self))),
+            },
+            FieldValue::Decimal(s) if is_decimal(s) => s.clone(),
+            FieldValue::Decimal(s) => match self {
+                Dialect::Postgres if NUMERIC_SPECIALS.contains(&&s[..]) => format!("'{}'", s),
+                _ => return Err(Error::InvalidValue(format!("{:?} isn't a decimal", s))),
+            },
+            FieldValue::Binary(bytes) => {
+                let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+                match self {
+                    Dialect::Postgres => format!("'\\x{}'", hex),
+                    _ => format!("X'{}'", hex),
+                }
+            },
+            FieldValue::Timestamp(t) => match self {
+                Dialect::MySql => self.string(&t.with_timezone(&Utc).format("%Y-%m-%d %H:%M:%S%.6f").to_string()),
+                _ => self.string(&t.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+            },
+            FieldValue::Json(json) => self.string(&json.to_string()),
+        })
+    }
+
+    fn string(&self, s: &str) -> String {
+        match self {
+            Dialect::MySql => format!("'{}'", s.replace('\\', "\\\\").replace('\'', "''")),
+            _ => format!("'{}'", s.replace('\'', "''")),
+        }
+    }
+
+    /// The expression giving the id the database assigned to the given placeholder
+    fn placeholder(&self, n: usize) -> String {
+        match self {
+            Dialect::MySql => format!("@snapper_{}", n),
+            _ => format!("(SELECT \"resolved\" FROM \"snapper_ids\" WHERE \"n\" = {})", n),
+        }
+    }
+}
+
+/// Numbers the rows whose keys the database assigns, by their type and id in the snapshot
+///
+/// Values referring to such a row are written as the expression giving its key, whatever the
+/// values themselves look like.
+#[derive(Default)]
+struct Placeholders {
+    ids: HashMap<Dep, usize>,
+    /// Rows keyed by several columns, by the row the first of them refers to, which is the key
+    /// references to them hold
+    firsts: HashMap<Dep, Dep>,
+}
+
+impl Placeholders {
+    /// Number a row whose key the database assigns
+    fn add(&mut self, dep: Dep) -> usize {
+        let next = self.ids.len();
+
+        *self.ids.entry(dep).or_insert(next)
+    }
+
+    /// Note the row the first key column of a row keyed by several columns refers to
+    fn add_composite(&mut self, etype: &EntityType, recipe: &Recipe, id: &Id) {
+        if let (Some((_, Some(first_type))), Id::Composite(ids)) = (recipe.key_types().first(), id) {
+            self.firsts.insert((etype.clone(), id.clone()), ((*first_type).clone(), ids[0].clone()));
+        }
+    }
+
+    /// A value as a literal, or the expression giving the key of the row it refers to
+    fn value(&self, dialect: Dialect, value: &FieldValue, deps: &[Dep]) -> Result<String> {
+        let n = match deps {
+            [dep] => self.ids.get(dep).or_else(|| self.firsts.get(dep).and_then(|first| self.ids.get(first))),
+            _ => None,
+        };
+
+        match n {
+            Some(n) => Ok(dialect.placeholder(*n)),
+            None => dialect.literal(value),
+        }
+    }
+
+    /// The columns of a row's key with their values
+    fn key(&self, dialect: Dialect, etype: &EntityType, recipe: &Recipe, id: &Id) -> Result<HashMap<String, String>> {
+        match (recipe.primary_key(), id) {
+            (PrimaryKey::String(pk), _) => {
+                let value = self.value(dialect, &id_to_field_value(id.clone()), &[(etype.clone(), id.clone())])?;
+
+                Ok(vec![(pk.clone(), value)].into_iter().collect())
+            },
+            (PrimaryKey::Composite(_), Id::Composite(ids)) => recipe.key_types()
+                .into_iter()
+                .zip(ids)
+                .map(|((column, component_type), id)| {
+                    let deps: Vec<Dep> = component_type.map(|t| (t.clone(), id.clone())).into_iter().collect();
+
+                    Ok((column.clone(), self.value(dialect, &id_to_field_value(id.clone()), &deps)?))
+                })
+                .collect(),
+            _ => Err(Error::MissingPrimaryKey(etype.clone())),
+        }
+    }
+}
+
+/// Write a script applying snapshot operations in a single transaction
+///
+/// Rows of types with a primary key get their keys from the database, which must be able to
+/// assign them, such as with an auto-incrementing integer column.
+pub fn generate<W: Write>(recipes: &RecipeSet, ops: &[Operation], dialect: Dialect, mut writer: W) -> Result<()> {
+    let deserializer = Deserializer::new(recipes);
+    // Ids stay as they are in the snapshot, placeholders stand in for them where written
+    let books = MemoryBookKeeper::with_strategy(IdStrategy::Preserve);
+    let mut placeholders = Placeholders::default();
+
+    let mut lines = vec![];
+
+    match dialect {
+        Dialect::Sqlite => {
+            lines.push(String::from("BEGIN;"));
+            lines.push(String::from("CREATE TEMP TABLE \"snapper_ids\" (\"n\" INTEGER PRIMARY KEY, \"resolved\" INTEGER);"));
+        },
+        Dialect::Postgres => {
+            lines.push(String::from("BEGIN;"));
+            lines.push(String::from("CREATE TEMP TABLE \"snapper_ids\" (\"n\" integer PRIMARY KEY, \"resolved\" bigint);"));
+        },
+        Dialect::MySql => lines.push(String::from("START TRANSACTION;")),
+    }
+
+    for op in ops {
+        let etype = op.type_();
+        let recipe = recipes.get(etype).ok_or_else(|| Error::MissingRecipe(etype.clone()))?;
+        let id = deserializer.resolve_id(op, recipe, &books)?;
+
+        let mut values = HashMap::new();
+        for (field, deserialized) in deserializer.deserialize_fields(op, &books)? {
+            values.insert(field, placeholders.value(dialect, &deserialized.value(), &deserialized.deps())?);
+        }
+
+        match (op.op(), recipe.primary_key(), id) {
+            // Only single-column keys are assigned by the database, the others are written as they are
+            (OperationKind::Insert, PrimaryKey::String(pk), Some(id)) => {
+                let n = placeholders.add((etype.clone(), id));
+
+                if dialect == Dialect::Postgres {
+                    lines.push(format!(
+                        "INSERT INTO \"snapper_ids\" (\"n\", \"resolved\") VALUES ({}, nextval(pg_get_serial_sequence({}, {})));",
+                        n, dialect.literal(&FieldValue::String(dialect.quote(etype)))?, dialect.literal(&FieldValue::String(pk.clone()))?
+                    ));
+
+                    values.insert(pk.clone(), dialect.placeholder(n));
+                    lines.push(insert(dialect, etype, &values));
+                } else {
+                    lines.push(insert(dialect, etype, &values));
+                    lines.push(match dialect {
+                        Dialect::MySql => format!("SET @snapper_{} = LAST_INSERT_ID();", n),
+                        _ => format!("INSERT INTO \"snapper_ids\" (\"n\", \"resolved\") VALUES ({}, last_insert_rowid());", n),
+                    });
+                }
+            },
+            (OperationKind::Insert, PrimaryKey::Null, _) => lines.push(insert(dialect, etype, &values)),
+            (OperationKind::Insert, PrimaryKey::Composite(_), Some(id)) => {
+                values.extend(placeholders.key(dialect, etype, recipe, &id)?);
+                placeholders.add_composite(etype, recipe, &id);
+
+                lines.push(insert(dialect, etype, &values));
+            },
+            (OperationKind::Update, PrimaryKey::String(_), Some(id)) | (OperationKind::Update, PrimaryKey::Composite(_), Some(id)) => {
+                for pk in recipe.primary_key().columns() {
+                    values.remove(pk);
+                }
+
+                if !values.is_empty() {
+                    lines.push(update(dialect, etype, &placeholders.key(dialect, etype, recipe, &id)?, &values));
+                }
+            },
+            _ => return Err(Error::MissingPrimaryKey(etype.clone())),
+        }
+    }
+
+    if dialect != Dialect::MySql {
+        lines.push(String::from("DROP TABLE \"snapper_ids\";"));
+    }
+    lines.push(String::from("COMMIT;"));
+
+    for line in lines {
+        writeln!(writer, "{}", line).map_err(Error::from)?;
+    }
+
+    Ok(())
+}
+
+/// The written values of a row, sorted by their quoted columns
+fn columns(dialect: Dialect, values: &HashMap<String, String>) -> Vec<(String, String)> {
+    let mut columns: Vec<(String, String)> = values.iter()
+        .map(|(field, value)| (dialect.quote(field), value.clone()))
+        .collect();
+    columns.sort();
+
+    columns
+}
+
+fn insert(dialect: Dialect, etype: &EntityType, values: &HashMap<String, String>) -> String {
+    if values.is_empty() {
+        return match dialect {
+            Dialect::MySql => format!("INSERT INTO {} () VALUES ();", dialect.quote(etype)),
+            _ => format!("INSERT INTO {} DEFAULT VALUES;", dialect.quote(etype)),
+        };
+    }
+
+    let (columns, values): (Vec<String>, Vec<String>) = columns(dialect, values).into_iter().unzip();
+
+    format!("INSERT INTO {} ({}) VALUES ({});", dialect.quote(etype), columns.join(", "), values.join(", "))
+}
+
+fn update(dialect: Dialect, etype: &EntityType, key: &HashMap<String, String>, values: &HashMap<String, String>) -> String {
+    let assignments: Vec<String> = columns(dialect, values).into_iter()
+        .map(|(column, value)| format!("{} = {}", column, value))
+        .collect();
+
+    let conditions: Vec<String> = columns(dialect, key).into_iter()
+        .map(|(column, value)| format!("{} = {}", column, value))
+        .collect();
+
+    format!(
+        "UPDATE {} SET {} WHERE {};",
+        dialect.quote(etype), assignments.join(", "), conditions.join(" AND ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::recipes;
+    use recipe::{Recipe, Ingredient};
+    use ingredients::value::Value;
+    use ingredients::reference::Reference;
+    use book_keeper::MemoryBookKeeper;
+    use serializer::Serializer;
+    use chrono::DateTime;
+
+    fn ops(recipes: &RecipeSet) -> Vec<Operation> {
+        let mut parent = Row::new();
+        parent.insert(String::from("id"), FieldValue::Int(1));
+        parent.insert(String::from("name"), FieldValue::String(String::from("O'Foo\\")));
+        parent.insert(String::from("favorite_child_id"), FieldValue::Int(2));
+
+        let mut child = Row::new();
+        child.insert(String::from("id"), FieldValue::Int(2));
+        child.insert(String::from("parent_id"), FieldValue::Int(1));
+
+        let mut s = Serializer::new(recipes);
+        s.add(String::from("parents"), parent).add(String::from("children"), child);
+
+        s.serialize(&MemoryBookKeeper::new()).unwrap()
+    }
+
+    fn script(dialect: Dialect) -> String {
+        let recipes = recipes();
+        let mut written = vec![];
+
+        generate(&recipes, &ops(&recipes), dialect, &mut written).unwrap();
+
+        String::from_utf8(written).unwrap()
+    }
+
+    #[test]
+    fn it_writes_sqlite() {
+        assert_eq!(r#"BEGIN;
+CREATE TEMP TABLE "snapper_ids" ("n" INTEGER PRIMARY KEY, "resolved" INTEGER);
+INSERT INTO "parents" ("favorite_child_id", "name") VALUES (NULL, 'O''Foo\');
+INSERT INTO "snapper_ids" ("n", "resolved") VALUES (0, last_insert_rowid());
+INSERT INTO "children" ("parent_id") VALUES ((SELECT "resolved" FROM "snapper_ids" WHERE "n" = 0));
+INSERT INTO "snapper_ids" ("n", "resolved") VALUES (1, last_insert_rowid());
+UPDATE "parents" SET "favorite_child_id" = (SELECT "resolved" FROM "snapper_ids" WHERE "n" = 1) WHERE "id" = (SELECT "resolved" FROM "snapper_ids" WHERE "n" = 0);
+DROP TABLE "snapper_ids";
+COMMIT;
+"#, script(Dialect::Sqlite));
+    }
+
+    #[test]
+    fn it_takes_postgres_keys_from_sequences() {
+        let script = script(Dialect::Postgres);
+        let lines: Vec<&str> = script.lines().collect();
+
+        assert_eq!(r#"INSERT INTO "snapper_ids" ("n", "resolved") VALUES (0, nextval(pg_get_serial_sequence('"parents"', 'id')));"#, lines[2]);
+        assert_eq!(r#"INSERT INTO "parents" ("favorite_child_id", "id", "name") VALUES (NULL, (SELECT "resolved" FROM "snapper_ids" WHERE "n" = 0), 'O''Foo\');"#, lines[3]);
+    }
+
+    #[test]
+    fn it_keeps_mysql_keys_in_variables() {
+        let script = script(Dialect::MySql);
+        let lines: Vec<&str> = script.lines().collect();
+
+        assert_eq!(vec![
+            "START TRANSACTION;",
+            r#"INSERT INTO `parents` (`favorite_child_id`, `name`) VALUES (NULL, 'O''Foo\\');"#,
+            "SET @snapper_0 = LAST_INSERT_ID();",
+            "INSERT INTO `children` (`parent_id`) VALUES (@snapper_0);",
+            "SET @snapper_1 = LAST_INSERT_ID();",
+            "UPDATE `parents` SET `favorite_child_id` = @snapper_1 WHERE `id` = @snapper_0;",
+            "COMMIT;",
+        ], lines);
+    }
+
+    #[test]
+    fn it_writes_values_only_referring_to_rows_as_placeholders() {
+        let recipes = recipes();
+        let mut ops = ops(&recipes);
+
+        let mut parent = Row::new();
+        parent.insert(String::from("id"), FieldValue::Int(3));
+        parent.insert(String::from("name"), FieldValue::String(String::from("\u{e000}snapper:0")));
+        ops.push(Operation::new(OperationKind::Insert, String::from("parents"), Some(Id::Int(3)), parent));
+
+        let mut written = vec![];
+        generate(&recipes, &ops, Dialect::MySql, &mut written).unwrap();
+        let script = String::from_utf8(written).unwrap();
+
+        assert_eq!("INSERT INTO `parents` (`name`) VALUES ('\u{e000}snapper:0');", script.lines().nth(6).unwrap());
+    }
+
+    #[test]
+    fn it_writes_composite_keys_as_they_are() {
+        let mut recipes = recipes();
+        let mut tag_ingredients = HashMap::new();
+        tag_ingredients.insert(String::from("child_id"), Ingredient::Ref(Reference::new(String::from("children"), vec![])));
+        tag_ingredients.insert(String::from("color"), Ingredient::Value(Value::new()));
+        recipes.add(String::from("tags"), Recipe::new(PrimaryKey::Composite(vec![String::from("child_id"), String::from("tag")]), tag_ingredients));
+
+        let mut tag_ref = Reference::new(String::from("tags"), vec![]);
+        tag_ref.composite(vec![String::from("tag")]);
+        let mut note_ingredients = HashMap::new();
+        note_ingredients.insert(String::from("tag_child_id"), Ingredient::Ref(tag_ref));
+        note_ingredients.insert(String::from("tag"), Ingredient::Value(Value::new()));
+        recipes.add(String::from("notes"), Recipe::new(PrimaryKey::String(String::from("id")), note_ingredients));
+
+        let mut ops = ops(&recipes);
+        let child = ops[1].id().unwrap();
+        let id = Some(Id::Composite(vec![child.clone(), Id::Uuid(String::from("red"))]));
+
+        let mut tag = Row::new();
+        tag.insert(String::from("child_id"), id_to_field_value(child));
+        tag.insert(String::from("tag"), FieldValue::String(String::from("red")));
+        ops.push(Operation::new(OperationKind::Insert, String::from("tags"), id.clone(), tag));
+
+        let mut color = Row::new();
+        color.insert(String::from("color"), FieldValue::Int(1));
+        ops.push(Operation::new(OperationKind::Update, String::from("tags"), id, color));
+
+        let mut note = Row::new();
+        note.insert(String::from("tag_child_id"), id_to_field_value(ops[1].id().unwrap()));
+        note.insert(String::from("tag"), FieldValue::String(String::from("red")));
+        ops.push(Operation::new(OperationKind::Insert, String::from("notes"), Some(Id::Int(1)), note));
+
+        let mut written = vec![];
+        generate(&recipes, &ops, Dialect::MySql, &mut written).unwrap();
+        let script = String::from_utf8(written).unwrap();
+        let lines: Vec<&str> = script.lines().collect();
+
+        assert_eq!("INSERT INTO `tags` (`child_id`, `tag`) VALUES (@snapper_1, 'red');", lines[6]);
+        assert_eq!("UPDATE `tags` SET `color` = 1 WHERE `child_id` = @snapper_1 AND `tag` = 'red';", lines[7]);
+        assert_eq!("INSERT INTO `notes` (`tag_child_id`, `tag`) VALUES (@snapper_1, 'red');", lines[8]);
+    }
+
+    #[test]
+    fn it_writes_literals_by_dialect() {
+        let timestamp = FieldValue::Timestamp(DateTime::parse_from_rfc3339("2018-01-02T03:04:05+02:00").unwrap());
+
+        assert_eq!("'2018-01-02T03:04:05+02:00'", Dialect::Postgres.literal(&timestamp).unwrap());
+        assert_eq!("'2018-01-02 01:04:05.000000'", Dialect::MySql.literal(&timestamp).unwrap());
+        assert_eq!("X'00ff'", Dialect::Sqlite.literal(&FieldValue::Binary(vec![0, 255])).unwrap());
+        assert_eq!("'\\x00ff'", Dialect::Postgres.literal(&FieldValue::Binary(vec![0, 255])).unwrap());
+        assert_eq!("FALSE", Dialect::MySql.literal(&FieldValue::Bool(false)).unwrap());
+        assert_eq!("'NaN'", Dialect::Postgres.literal(&FieldValue::Float(f64::NAN)).unwrap());
+        assert!(Dialect::MySql.literal(&FieldValue::Float(f64::NAN)).is_err());
+        assert!(Dialect::Sqlite.literal(&FieldValue::Decimal(String::from("1; DROP TABLE foos"))).is_err());
+        assert!(Dialect::Sqlite.literal(&FieldValue::Decimal(String::from("1--"))).is_err());
+        assert!(Dialect::Sqlite.literal(&FieldValue::Decimal(String::from("--"))).is_err());
+        assert_eq!("-1.5e3", Dialect::Sqlite.literal(&FieldValue::Decimal(String::from("-1.5e3"))).unwrap());
+        assert_eq!("'NaN'", Dialect::Postgres.literal(&FieldValue::Decimal(String::from("NaN"))).unwrap());
+        assert!(Dialect::MySql.literal(&FieldValue::Decimal(String::from("NaN"))).is_err());
+        assert_eq!("`a``b`", Dialect::MySql.quote("a`b"));
+    }
+
+    #[cfg(feature="sqlite")]
+    #[test]
+    fn it_writes_sqlite_that_runs() {
+        use rusqlite::Connection;
+
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(r#"
+            CREATE TABLE parents (id INTEGER PRIMARY KEY, name TEXT, favorite_child_id INTEGER);
+            CREATE TABLE children (id INTEGER PRIMARY KEY, parent_id INTEGER);
+            INSERT INTO parents (name) VALUES ('Existing');
+        "#).unwrap();
+
+        conn.execute_batch(&script(Dialect::Sqlite)).unwrap();
+
+        let parent: (i64, String, i64) = conn.query_row(
+            "SELECT id, name, favorite_child_id FROM parents WHERE id = 2", [], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+        ).unwrap();
+        let child_parent: i64 = conn.query_row("SELECT parent_id FROM children WHERE id = ?1", [parent.2], |r| r.get(0)).unwrap();
+
+        assert_eq!(String::from("O'Foo\\"), parent.1);
+        assert_eq!(2, child_parent);
+    }
+}
@@ -0,0 +1,348 @@
+use contracts::*;
+use book_keeper::*;
+use recipe::{RecipeSet, Recipe, PrimaryKey};
+use sorter::Sorter;
+use error::{Error, Result};
+use ingredients::ingredient::Ingredient;
+use tools::row_digest;
+use std::vec::Vec;
+use std::collections::HashSet;
+
+pub struct Serializer<'a> {
+    recipes: &'a RecipeSet,
+    rows: Vec<(EntityType, Row)>,
+}
+
+impl<'a> Serializer<'a> {
+    pub fn new(recipes: &'a RecipeSet) -> Serializer<'a> {
+        Serializer {
+            recipes,
+            rows: vec![],
+        }
+    }
+
+    /// Add a row of the given type to the serialization
+    pub fn add(&mut self, etype: EntityType, row: Row) -> &mut Self {
+        self.rows.push((etype, row));
+
+        self
+    }
+
+    /// Serialize all added rows into a list of snapshot operations
+    ///
+    /// Every row becomes an INSERT, ordered so that rows come after the rows they reference.
+    /// Circular fields needed to break a dependency cycle are inserted with their fallback value
+    /// and set to their real value in UPDATEs following all INSERTs. Rows without a primary key
+    /// are written once for every distinct content, and never referred to.
+    pub fn serialize(&self, books: &dyn BookKeeper) -> Result<Vec<Operation>> {
+        let rows = self.unique_rows()?;
+
+        let mut sorter = Sorter::new(self.recipes);
+        for (etype, row) in &rows {
+            sorter.add(etype, row);
+        }
+
+        let sorting = sorter.sort()?;
+
+        // Let the books know about every row first, so that references between them resolve
+        let mut ids = vec![];
+        for &i in sorting.order() {
+            let (etype, row) = rows[i];
+
+            ids.push(resolve_primary_key(etype, self.recipes, row, books)?);
+        }
+
+        let mut inserts = vec![];
+        let mut updates = vec![];
+
+        for (&i, id) in sorting.order().iter().zip(ids) {
+            let (etype, row) = rows[i];
+            let recipe = self.recipe(etype)?;
+            let mut insert = Row::new();
+            let mut deferred = Row::new();
+            let mut fallbacks = vec![];
+            let mut extra_fields = vec![];
+
+            for (field, ingredient) in recipe.ingredients() {
+                let value = row.get(field).cloned().unwrap_or(FieldValue::Null);
+
+                if sorting.is_deferred(i, field) {
+                    deferred.insert(field.clone(), ingredient.snapper_serialize(&value, row, self.recipes, books, true)?);
+                    extra_fields.extend(ingredient.get_required_extra_fields());
+                    insert.insert(field.clone(), ingredient.snapper_serialize(&value, row, self.recipes, books, false)?);
+                    fallbacks.push(field.clone());
+                } else {
+                    insert.insert(field.clone(), ingredient.snapper_serialize(&value, row, self.recipes, books, true)?);
+                }
+            }
+
+            if let Some(key) = id.as_ref().and_then(|id| recipe.primary_key().key_row(id)) {
+                insert.extend(key.clone());
+
+                if !deferred.is_empty() {
+                    let mut update = deferred;
+
+                    update.extend(key);
+
+                    for field in extra_fields {
+                        if let Some(value) = insert.get(&field) {
+                            update.insert(field, value.clone());
+                        }
+                    }
+
+                    updates.push(Operation::new(OperationKind::Update, etype.clone(), id.clone(), update));
+                }
+            }
+
+            inserts.push(Operation::new(OperationKind::Insert, etype.clone(), id, insert).defer(fallbacks));
+        }
+
+        inserts.extend(updates);
+
+        Ok(inserts)
+    }
+
+    /// The added rows, leaving out rows without a primary key identical to one added before
+    fn unique_rows(&self) -> Result<Vec<&(EntityType, Row)>> {
+        let mut seen = HashSet::new();
+        let mut rows = vec![];
+
+        for entry in &self.rows {
+            let (etype, row) = entry;
+
+            if let PrimaryKey::Null = self.recipe(etype)?.primary_key() {
+                if !seen.insert(row_digest(etype, row)) {
+                    continue;
+                }
+            }
+
+            rows.push(entry);
+        }
+
+        Ok(rows)
+    }
+
+    /// Get the recipe for the given type
+    fn recipe(&self, etype: &EntityType) -> Result<&'a Recipe> {
+        self.recipes.get(etype).ok_or_else(|| Error::MissingRecipe(etype.clone()))
+    }
+}
+
+/// Ask the books for the id the row should have in the serialization
+pub(crate) fn resolve_primary_key(etype: &EntityType, recipes: &RecipeSet, row: &Row, books: &dyn BookKeeper) -> Result<Option<Id>> {
+    let recipe = recipes.get(etype).ok_or_else(|| Error::MissingRecipe(etype.clone()))?;
+
+    if let PrimaryKey::Null = recipe.primary_key() {
+        return Ok(None);
+    }
+
+    let id = recipes.row_id(etype, row)?
+        .ok_or_else(|| Error::MissingPrimaryKey(etype.clone()))?;
+
+    match id {
+        Id::Composite(ids) => {
+            let components: Vec<(Option<EntityType>, Id)> = recipe.key_types()
+                .into_iter()
+                .map(|(_, component_type)| component_type.cloned())
+                .zip(ids.iter().cloned())
+                .collect();
+
+            books.resolve_components(etype.clone(), components)
+                .map(Some)
+                .ok_or(Error::UnresolvableId(etype.clone(), Id::Composite(ids)))
+        },
+        id => books.resolve_id(etype.clone(), id.clone(), true)
+            .map(Some)
+            .ok_or(Error::UnresolvableId(etype.clone(), id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::{recipes, row};
+    use recipe::Ingredient;
+    use ingredients::reference::Reference;
+    use std::collections::HashMap;
+
+    struct BookKeeperMock {}
+
+    impl BookKeeperMock {
+        pub fn new() -> BookKeeperMock { BookKeeperMock {} }
+    }
+
+    impl BookKeeper for BookKeeperMock {
+        fn resolve_id(&self, etype: EntityType, id: Id, _authoritative: bool) -> Option<Id> {
+            match id {
+                Id::Int(v) => Some(Id::Uuid(format!("{}-{}", etype, v))),
+                _ => None,
+            }
+        }
+        fn reset(&mut self) { unimplemented!() }
+    }
+
+    #[test]
+    fn it_serializes_rows_through_their_recipes() {
+        let recipes = recipes();
+        let b = BookKeeperMock::new();
+        let mut s = Serializer::new(&recipes);
+
+        s.add(String::from("parents"), row(vec![
+            ("id", FieldValue::Int(1)),
+            ("name", FieldValue::String(String::from("Foo"))),
+            ("favorite_child_id", FieldValue::Null),
+            ("ignored", FieldValue::Int(5)),
+        ]));
+        s.add(String::from("children"), row(vec![("id", FieldValue::Int(2)), ("parent_id", FieldValue::Int(1))]));
+
+        let ops = s.serialize(&b).unwrap();
+
+        assert_eq!(2, ops.len());
+        assert_eq!(Operation::new(OperationKind::Insert, String::from("parents"), Some(Id::Uuid(String::from("parents-1"))), row(vec![
+            ("id", FieldValue::String(String::from("parents-1"))),
+            ("name", FieldValue::String(String::from("Foo"))),
+            ("favorite_child_id", FieldValue::Null),
+        ])), ops[0]);
+        assert_eq!(Operation::new(OperationKind::Insert, String::from("children"), Some(Id::Uuid(String::from("children-2"))), row(vec![
+            ("id", FieldValue::String(String::from("children-2"))),
+            ("parent_id", FieldValue::String(String::from("parents-1"))),
+        ])), ops[1]);
+    }
+
+    #[test]
+    fn it_defers_circular_fields_to_updates() {
+        let recipes = recipes();
+        let b = BookKeeperMock::new();
+        let mut s = Serializer::new(&recipes);
+
+        s.add(String::from("parents"), row(vec![
+            ("id", FieldValue::Int(1)),
+            ("name", FieldValue::String(String::from("Foo"))),
+            ("favorite_child_id", FieldValue::Int(2)),
+        ]));
+        s.add(String::from("children"), row(vec![("id", FieldValue::Int(2)), ("parent_id", FieldValue::Int(1))]));
+
+        let ops = s.serialize(&b).unwrap();
+
+        assert_eq!(3, ops.len());
+        assert_eq!(Some(&FieldValue::Null), ops[0].row().get("favorite_child_id"));
+        assert_eq!(Operation::new(OperationKind::Update, String::from("parents"), Some(Id::Uuid(String::from("parents-1"))), row(vec![
+            ("id", FieldValue::String(String::from("parents-1"))),
+            ("favorite_child_id", FieldValue::String(String::from("children-2"))),
+        ])), ops[2]);
+    }
+
+    #[test]
+    fn it_orders_rows_after_their_references() {
+        let recipes = recipes();
+        let b = BookKeeperMock::new();
+        let mut s = Serializer::new(&recipes);
+
+        s.add(String::from("children"), row(vec![("id", FieldValue::Int(2)), ("parent_id", FieldValue::Int(1))]));
+        s.add(String::from("parents"), row(vec![("id", FieldValue::Int(1)), ("name", FieldValue::Null)]));
+
+        let ops = s.serialize(&b).unwrap();
+
+        assert_eq!("parents", ops[0].type_());
+        assert_eq!("children", ops[1].type_());
+    }
+
+    #[test]
+    fn it_writes_keyless_rows_once_after_their_references() {
+        let mut recipes = recipes();
+        let mut visit_ingredients = HashMap::new();
+        visit_ingredients.insert(String::from("child_id"), Ingredient::Ref(Reference::new(String::from("children"), vec![])));
+        recipes.add(String::from("visits"), Recipe::new(PrimaryKey::Null, visit_ingredients));
+
+        let b = BookKeeperMock::new();
+        let mut s = Serializer::new(&recipes);
+
+        s.add(String::from("visits"), row(vec![("child_id", FieldValue::Int(2))]));
+        s.add(String::from("visits"), row(vec![("child_id", FieldValue::Int(2))]));
+        s.add(String::from("children"), row(vec![("id", FieldValue::Int(2)), ("parent_id", FieldValue::Int(1))]));
+        s.add(String::from("parents"), row(vec![("id", FieldValue::Int(1)), ("name", FieldValue::Null)]));
+
+        let ops = s.serialize(&b).unwrap();
+
+        assert_eq!(3, ops.len());
+        assert_eq!(Operation::new(OperationKind::Insert, String::from("visits"), None, row(vec![
+            ("child_id", FieldValue::String(String::from("children-2"))),
+        ])), ops[2]);
+    }
+
+    #[test]
+    fn it_reads_primary_keys_of_the_declared_kind() {
+        let mut recipes = recipes();
+        let mut tags = Recipe::new(PrimaryKey::String(String::from("slug")), HashMap::new());
+        tags.typed(IdKind::String);
+        recipes.add(String::from("tags"), tags);
+
+        let b = MemoryBookKeeper::new();
+        b.register(String::from("tags"), Id::String(String::from("red")), Id::String(String::from("red")));
+
+        let mut s = Serializer::new(&recipes);
+        s.add(String::from("tags"), row(vec![("slug", FieldValue::String(String::from("red")))]));
+
+        assert_eq!(Some(Id::String(String::from("red"))), s.serialize(&b).unwrap()[0].id());
+
+        let mut s = Serializer::new(&recipes);
+        s.add(String::from("tags"), row(vec![("slug", FieldValue::Int(1))]));
+
+        assert_eq!(Err(Error::WrongIdKind(IdKind::String, FieldValue::Int(1))), s.serialize(&b));
+    }
+
+    #[test]
+    fn it_reads_references_of_the_kind_the_referenced_recipe_declares() {
+        let mut ingredients = HashMap::new();
+        ingredients.insert(String::from("parent_id"), Ingredient::Ref(Reference::new(String::from("nodes"), vec![])));
+
+        let mut nodes = Recipe::new(PrimaryKey::String(String::from("id")), ingredients);
+        nodes.typed(IdKind::SignedInt);
+
+        let mut recipes = RecipeSet::new();
+        recipes.add(String::from("nodes"), nodes);
+
+        let b = MemoryBookKeeper::with_strategy(IdStrategy::Preserve);
+        let mut s = Serializer::new(&recipes);
+        s.add(String::from("nodes"), row(vec![("id", FieldValue::Int(1)), ("parent_id", FieldValue::Int(-5))]));
+        s.add(String::from("nodes"), row(vec![("id", FieldValue::Int(-5)), ("parent_id", FieldValue::Null)]));
+
+        let ops = s.serialize(&b).unwrap();
+
+        assert_eq!(Some(Id::Signed(-5)), ops[0].id());
+        assert_eq!(Some(&FieldValue::Int(-5)), ops[1].row().get("parent_id"));
+    }
+
+    #[test]
+    fn it_fails_on_rows_without_recipe() {
+        let recipes = recipes();
+        let b = BookKeeperMock::new();
+        let mut s = Serializer::new(&recipes);
+
+        s.add(String::from("strangers"), row(vec![("id", FieldValue::Int(1))]));
+
+        assert_eq!(Err(Error::MissingRecipe(String::from("strangers"))), s.serialize(&b));
+    }
+
+    #[test]
+    fn it_fails_on_rows_without_primary_key() {
+        let recipes = recipes();
+        let b = BookKeeperMock::new();
+        let mut s = Serializer::new(&recipes);
+
+        s.add(String::from("children"), row(vec![("parent_id", FieldValue::Int(1))]));
+
+        assert_eq!(Err(Error::MissingPrimaryKey(String::from("children"))), s.serialize(&b));
+    }
+
+    #[test]
+    fn it_fails_on_dangling_references() {
+        let recipes = recipes();
+        let b = BookKeeperMock::new();
+        let mut s = Serializer::new(&recipes);
+
+        s.add(String::from("children"), row(vec![("id", FieldValue::Int(2)), ("parent_id", FieldValue::String(String::from("gone")))]));
+
+        assert_eq!(Err(Error::DanglingReference(String::from("parents"), Id::Uuid(String::from("gone")))), s.serialize(&b));
+    }
+}
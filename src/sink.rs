@@ -0,0 +1,200 @@
+//! Writing deserialized snapshots into a live database
+
+use contracts::*;
+use book_keeper::BookKeeper;
+use recipe::{RecipeSet, PrimaryKey};
+use deserializer::Deserializer;
+use tools::{id_to_field_value, typed_id_to_field_value};
+use error::{Error, Result};
+use std::collections::HashMap;
+
+/// Somewhere rows can be written to, such as a database, within a single transaction
+///
+/// Dropping a sink without committing it must discard everything written to it.
+pub trait RowSink {
+    /// Insert a row, letting the database assign its primary key and returning the value it
+    /// holds, or returning `None` for types whose key isn't assigned by the database
+    fn insert(&mut self, etype: &EntityType, primary_key: Option<&str>, row: &Row) -> Result<Option<FieldValue>>;
+
+    /// Whether the database assigns the primary key of a row of the given type inserted without
+    /// one, such as with an auto-incrementing column or a default
+    fn generates_key(&mut self, etype: &EntityType, primary_key: &str) -> Result<bool>;
+
+    /// Set the given fields of the row whose primary key holds the given id
+    fn update(&mut self, etype: &EntityType, primary_key: &PrimaryKey, id: &Id, row: &Row) -> Result<()>;
+
+    /// Make everything written so far permanent
+    fn commit(self) -> Result<()> where Self: Sized;
+}
+
+/// Apply snapshot operations to a sink, committing only if every operation was applied
+///
+/// The primary keys the database assigns are registered in the books, so that references and
+/// UPDATEs further on resolve to them. Keys the database doesn't generate are written as the
+/// books resolve them, and so are all keys when the books keep the ids they create. Composite
+/// keys are written as they are, made up of the keys their components resolve to. After a
+/// failure nothing has been written, but the books hold keys that were rolled back and should
+/// be discarded.
+pub fn apply<S: RowSink>(recipes: &RecipeSet, ops: &[Operation], mut sink: S, books: &dyn BookKeeper) -> Result<()> {
+    let deserializer = Deserializer::new(recipes);
+    // Whether the database generates the keys of a type, asked once per type
+    let mut generating: HashMap<EntityType, bool> = HashMap::new();
+
+    for op in ops {
+        let etype = op.type_();
+        let recipe = recipes.get(etype).ok_or_else(|| Error::MissingRecipe(etype.clone()))?;
+        let primary_key = recipe.primary_key();
+
+        match (op.op(), primary_key) {
+            (OperationKind::Insert, PrimaryKey::String(pk)) => {
+                let id = op.id().ok_or_else(|| Error::MissingId(etype.clone()))?;
+                let mut row = deserializer.deserialize_row(op, books)?;
+
+                let generates = match generating.get(etype) {
+                    Some(&generates) => generates,
+                    None => {
+                        let generates = sink.generates_key(etype, pk)?;
+                        generating.insert(etype.clone(), generates);
+
+                        generates
+                    },
+                };
+
+                if books.keeps_ids() || !generates {
+                    let key = books.resolve_id(etype.clone(), id.clone(), true)
+                        .ok_or_else(|| Error::UnresolvableId(etype.clone(), id.clone()))?;
+
+                    row.insert(pk.clone(), match recipe.id_kind() {
+                        Some(kind) => typed_id_to_field_value(key, kind)?,
+                        None => id_to_field_value(key),
+                    });
+                }
+
+                let assigned = sink.insert(etype, Some(pk), &row)?;
+                let assigned = recipe.read_id(&assigned.unwrap_or(FieldValue::Null))?
+                    .ok_or_else(|| Error::MissingPrimaryKey(etype.clone()))?;
+
+                books.register(etype.clone(), id, assigned);
+            },
+            (OperationKind::Insert, _) => {
+                let deserialized = deserializer.deserialize_operation(op, books)?;
+
+                sink.insert(etype, None, deserialized.row())?;
+            },
+            (OperationKind::Update, PrimaryKey::Null) => return Err(Error::MissingPrimaryKey(etype.clone())),
+            (OperationKind::Update, _) => {
+                let deserialized = deserializer.deserialize_operation(op, books)?;
+                let id = deserialized.id().ok_or_else(|| Error::MissingId(etype.clone()))?;
+                let mut row = deserialized.row().clone();
+
+                for pk in primary_key.columns() {
+                    row.remove(pk);
+                }
+
+                sink.update(etype, primary_key, &id, &row)?;
+            },
+        }
+    }
+
+    sink.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::recipes;
+    use recipe::Recipe;
+    use book_keeper::MemoryBookKeeper;
+    use serializer::Serializer;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    /// Logs what is written, handing out ids after `next_id`
+    struct RowSinkMock {
+        log: Rc<RefCell<Vec<String>>>,
+        next_id: u64,
+    }
+
+    impl RowSink for RowSinkMock {
+        fn insert(&mut self, etype: &EntityType, primary_key: Option<&str>, row: &Row) -> Result<Option<FieldValue>> {
+            self.next_id += 1;
+            self.log.borrow_mut().push(format!("INSERT {} {:?}", etype, row.get("parent_id")));
+
+            Ok(primary_key.map(|_| FieldValue::Int(self.next_id as i64)))
+        }
+
+        fn generates_key(&mut self, _etype: &EntityType, _primary_key: &str) -> Result<bool> {
+            Ok(true)
+        }
+
+        fn update(&mut self, etype: &EntityType, _primary_key: &PrimaryKey, id: &Id, row: &Row) -> Result<()> {
+            if etype == "nopes" {
+                return Err(Error::MissingRow(etype.clone(), id.clone()));
+            }
+
+            self.log.borrow_mut().push(format!("UPDATE {} {} {:?}", etype, id, row.get("favorite_child_id")));
+
+            Ok(())
+        }
+
+        fn commit(self) -> Result<()> {
+            self.log.borrow_mut().push(String::from("COMMIT"));
+
+            Ok(())
+        }
+    }
+
+    fn ops(recipes: &RecipeSet) -> Vec<Operation> {
+        let mut parent = Row::new();
+        parent.insert(String::from("id"), FieldValue::Int(1));
+        parent.insert(String::from("name"), FieldValue::String(String::from("Foo")));
+        parent.insert(String::from("favorite_child_id"), FieldValue::Int(2));
+
+        let mut child = Row::new();
+        child.insert(String::from("id"), FieldValue::Int(2));
+        child.insert(String::from("parent_id"), FieldValue::Int(1));
+
+        let mut s = Serializer::new(recipes);
+        s.add(String::from("parents"), parent).add(String::from("children"), child);
+
+        s.serialize(&MemoryBookKeeper::new()).unwrap()
+    }
+
+    #[test]
+    fn it_applies_operations_with_the_keys_the_database_assigns() {
+        let recipes = recipes();
+        let ops = ops(&recipes);
+        let books = MemoryBookKeeper::new();
+        let log = Rc::new(RefCell::new(vec![]));
+
+        apply(&recipes, &ops, RowSinkMock { log: log.clone(), next_id: 100 }, &books).unwrap();
+
+        assert_eq!(vec![
+            "INSERT parents None",
+            "INSERT children Some(Int(101))",
+            "UPDATE parents 101 Some(Int(102))",
+            "COMMIT",
+        ], *log.borrow());
+        assert_eq!(Some(Id::Int(102)), books.resolve_id(String::from("children"), ops[1].id().unwrap(), false));
+    }
+
+    #[test]
+    fn it_doesnt_commit_on_failure() {
+        let mut recipes = recipes();
+        recipes.add(String::from("nopes"), Recipe::new(PrimaryKey::String(String::from("id")), HashMap::new()));
+
+        let mut ops = ops(&recipes);
+        ops.push(Operation::new(OperationKind::Update, String::from("nopes"), Some(Id::Int(1)), Row::new()));
+
+        let books = MemoryBookKeeper::new();
+        books.register(String::from("nopes"), Id::Int(1), Id::Int(1));
+        let log = Rc::new(RefCell::new(vec![]));
+
+        assert_eq!(
+            Err(Error::MissingRow(String::from("nopes"), Id::Int(1))),
+            apply(&recipes, &ops, RowSinkMock { log: log.clone(), next_id: 100 }, &books)
+        );
+        assert!(!log.borrow().contains(&String::from("COMMIT")));
+    }
+}
@@ -0,0 +1,168 @@
+use contracts::*;
+use recipe::RecipeSet;
+use error::{Error, Result};
+use std::vec::Vec;
+use std::io::{Read, Write, Cursor};
+use encoding::{Encoding, encode, decode};
+use archive;
+
+/// The snapshot format version written, and the only one read
+pub const FORMAT_VERSION: u64 = 1;
+
+/// A self-describing snapshot: the recipes used and the operations serialized with them
+///
+/// Written as `{ "version": 1, "recipes": { ... }, "operations": [ ... ] }`, in JSON or CBOR.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    version: u64,
+    recipes: RecipeSet,
+    operations: Vec<Operation>,
+}
+
+impl Snapshot {
+    pub fn new(recipes: RecipeSet, operations: Vec<Operation>) -> Snapshot {
+        Snapshot {
+            version: FORMAT_VERSION,
+            recipes,
+            operations,
+        }
+    }
+
+    pub fn version(&self) -> u64 { self.version }
+
+    pub fn recipes(&self) -> &RecipeSet { &self.recipes }
+
+    /// The operations in the order they were serialized
+    pub fn operations(&self) -> &[Operation] { &self.operations }
+
+    pub fn into_parts(self) -> (RecipeSet, Vec<Operation>) {
+        (self.recipes, self.operations)
+    }
+
+    /// Read a snapshot in either encoding, or from an archive, refusing any other format version than the current one
+    pub fn read<R: Read>(mut reader: R) -> Result<Snapshot> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).map_err(Error::from)?;
+
+        if bytes.starts_with(archive::MAGIC) {
+            let mut reader = archive::open(Cursor::new(bytes))?;
+            let operations = reader.by_ref().collect::<Result<Vec<Operation>>>()?;
+
+            return Ok(Snapshot::new(reader.into_recipes(), operations));
+        }
+
+        // Check the version before anything else, as other versions may be laid out differently
+        let versioned: Versioned = decode(&bytes)?;
+        check_version(versioned.version)?;
+
+        decode(&bytes)
+    }
+
+    /// Write the snapshot in the given encoding
+    pub fn write<W: Write>(&self, writer: W, encoding: Encoding) -> Result<()> {
+        encode(self, writer, encoding)
+    }
+}
+
+/// Just the version of a snapshot
+#[derive(Deserialize)]
+struct Versioned {
+    version: Option<u64>,
+}
+
+pub(crate) fn check_version(version: Option<u64>) -> Result<()> {
+    match version {
+        Some(FORMAT_VERSION) => Ok(()),
+        Some(version) => Err(Error::UnsupportedVersion(version)),
+        None => Err(Error::InvalidSnapshot(String::from("missing format version"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use serde_json;
+
+    fn snapshot() -> Snapshot {
+        let recipes: RecipeSet = serde_json::from_str(r#"{
+            "foos": { "primary_key": "id", "ingredients": { "name": { "type": "VALUE", "config": {} } } }
+        }"#).unwrap();
+
+        let mut row: Row = HashMap::new();
+        row.insert(String::from("id"), FieldValue::String(String::from("a")));
+        row.insert(String::from("name"), FieldValue::String(String::from("Foo")));
+
+        Snapshot::new(recipes, vec![
+            Operation::new(OperationKind::Insert, String::from("foos"), Some(Id::Uuid(String::from("a"))), row),
+        ])
+    }
+
+    #[test]
+    fn it_round_trips() {
+        let mut written = vec![];
+        snapshot().write(&mut written, Encoding::Json).unwrap();
+
+        let read = Snapshot::read(&written[..]).unwrap();
+
+        assert_eq!(FORMAT_VERSION, read.version());
+        assert_eq!(snapshot().operations(), read.operations());
+        assert!(read.recipes().contains("foos"));
+    }
+
+    #[test]
+    fn it_writes_deterministically() {
+        let mut first = vec![];
+        let mut second = vec![];
+        snapshot().write(&mut first, Encoding::Json).unwrap();
+        snapshot().write(&mut second, Encoding::Json).unwrap();
+
+        assert_eq!(first, second);
+        assert!(String::from_utf8(first).unwrap().starts_with(r#"{"operations":[{"id":"a","op":"INSERT","row":{"id":"a","name":"Foo"},"type":"foos"}],"recipes":"#));
+    }
+
+    #[test]
+    fn it_round_trips_in_cbor() {
+        let mut written = vec![];
+        snapshot().write(&mut written, Encoding::Cbor).unwrap();
+
+        let read = Snapshot::read(&written[..]).unwrap();
+
+        assert_eq!(Encoding::Cbor, Encoding::detect(&written));
+        assert_eq!(snapshot().operations(), read.operations());
+        assert!(read.recipes().contains("foos"));
+    }
+
+    #[test]
+    fn it_reads_archives() {
+        let (recipes, operations) = snapshot().into_parts();
+        let mut w = archive::create(vec![], &recipes).unwrap();
+        for op in &operations {
+            w.write(op).unwrap();
+        }
+        let written = w.finish().unwrap().finish().unwrap();
+
+        let read = Snapshot::read(&written[..]).unwrap();
+
+        assert_eq!(snapshot().operations(), read.operations());
+    }
+
+    #[test]
+    fn it_refuses_other_versions() {
+        let json = r#"{ "version": 2, "recipes": {}, "operations": [] }"#;
+
+        assert_eq!(Some(Error::UnsupportedVersion(2)), Snapshot::read(json.as_bytes()).err());
+
+        let mut cbor = vec![];
+        encode(&serde_json::json!({ "version": 2, "recipes": {}, "operations": [] }), &mut cbor, Encoding::Cbor).unwrap();
+
+        assert_eq!(Some(Error::UnsupportedVersion(2)), Snapshot::read(&cbor[..]).err());
+    }
+
+    #[test]
+    fn it_refuses_snapshots_without_version() {
+        let json = r#"{ "recipes": {}, "operations": [] }"#;
+
+        assert!(matches!(Snapshot::read(json.as_bytes()), Err(Error::InvalidSnapshot(_))));
+    }
+}
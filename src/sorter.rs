@@ -0,0 +1,385 @@
+use contracts::*;
+use error::{Error, Result};
+use recipe::{Recipe, RecipeSet};
+use ingredients::ingredient::Ingredient;
+use std::vec::Vec;
+use std::string::String;
+use std::collections::{HashMap, HashSet, BTreeSet};
+
+/// A row to sort
+struct Node<'a> {
+    etype: &'a EntityType,
+    row: &'a Row,
+}
+
+/// A dependency from one row onto another
+struct Edge {
+    source: usize,
+    target: usize,
+    /// The circular field whose deferral removes the dependency, if any
+    field: Option<String>,
+    active: bool,
+}
+
+/// The outcome of a sort
+#[derive(Debug, PartialEq)]
+pub struct Sorting {
+    order: Vec<usize>,
+    deferred: HashSet<(usize, String)>,
+}
+
+impl Sorting {
+    /// Indices of the added rows, parents before children
+    pub fn order(&self) -> &[usize] {
+        &self.order
+    }
+
+    /// Whether the field of the row at the given index had to be deferred to break a cycle
+    pub fn is_deferred(&self, index: usize, field: &str) -> bool {
+        self.deferred.contains(&(index, field.to_string()))
+    }
+}
+
+pub struct Sorter<'a> {
+    recipes: &'a RecipeSet,
+    nodes: Vec<Node<'a>>,
+}
+
+impl<'a> Sorter<'a> {
+    pub fn new(recipes: &'a RecipeSet) -> Sorter<'a> {
+        Sorter {
+            recipes,
+            nodes: vec![],
+        }
+    }
+
+    /// Add a row of the given type
+    pub fn add(&mut self, etype: &'a EntityType, row: &'a Row) -> &mut Self {
+        self.nodes.push(Node { etype, row });
+
+        self
+    }
+
+    /// Order the rows so that every row comes after the rows it depends on
+    ///
+    /// Rows are kept in the order they were added where the dependencies allow it. When the rows
+    /// form a cycle, a circular field on the cycle is deferred and its fallback is used instead.
+    pub fn sort(&self) -> Result<Sorting> {
+        let index = self.index();
+
+        let mut edges: Vec<Edge> = vec![];
+        let mut pending = vec![0; self.nodes.len()];
+        let mut dependents: Vec<Vec<usize>> = vec![vec![]; self.nodes.len()];
+
+        for (source, node) in self.nodes.iter().enumerate() {
+            // Visit fields in a fixed order so the same rows always break the same way
+            let mut fields: Vec<_> = self.recipe(node.etype)?.ingredients().iter().collect();
+            fields.sort_by(|a, b| a.0.cmp(b.0));
+
+            for (field, ingredient) in fields {
+                let value = node.row.get(field).cloned().unwrap_or(FieldValue::Null);
+
+                // Only the dependencies the fallback doesn't share can be broken by deferring
+                let fallback_deps = if ingredient.is_circular() {
+                    Some(ingredient.get_deps(&value, node.row, self.recipes, false))
+                } else {
+                    None
+                };
+
+                for dep in ingredient.get_deps(&value, node.row, self.recipes, true) {
+                    if let Some(&target) = index.get(&dep) {
+                        let breakable = fallback_deps.as_ref().map(|deps| !deps.contains(&dep)).unwrap_or(false);
+
+                        pending[source] += 1;
+                        dependents[target].push(edges.len());
+                        edges.push(Edge { source, target, field: if breakable { Some(field.clone()) } else { None }, active: true });
+                    }
+                }
+            }
+        }
+
+        let mut ready: BTreeSet<usize> = (0..self.nodes.len()).filter(|&i| pending[i] == 0).collect();
+        let mut emitted = vec![false; self.nodes.len()];
+        let mut order = vec![];
+        let mut deferred = HashSet::new();
+
+        while order.len() < self.nodes.len() {
+            while let Some(&current) = ready.iter().next() {
+                ready.remove(&current);
+                emitted[current] = true;
+                order.push(current);
+
+                for &e in &dependents[current] {
+                    if edges[e].active {
+                        edges[e].active = false;
+                        pending[edges[e].source] -= 1;
+
+                        if pending[edges[e].source] == 0 {
+                            ready.insert(edges[e].source);
+                        }
+                    }
+                }
+            }
+
+            if order.len() == self.nodes.len() {
+                break;
+            }
+
+            // Everything left is stuck behind a cycle, find one and break it through a circular field
+            let cycle = find_cycle(&edges, &emitted);
+            let breakable = cycle.iter()
+                .find(|&&e| edges[e].field.is_some())
+                .map(|&e| (edges[e].source, edges[e].field.clone().unwrap()));
+
+            let (source, field) = match breakable {
+                Some(b) => b,
+                None => return Err(Error::Cycle(cycle.iter()
+                    .map(|&e| self.dep(edges[e].source))
+                    .collect())),
+            };
+
+            for edge in edges.iter_mut() {
+                if edge.active && edge.source == source && edge.field.as_ref() == Some(&field) {
+                    edge.active = false;
+                    pending[source] -= 1;
+                }
+            }
+
+            // The fallback is inserted in place of the real value, so its own dependencies apply
+            let node = &self.nodes[source];
+            let ingredient = self.recipe(node.etype)?.ingredient(&field).unwrap();
+            let value = node.row.get(&field).cloned().unwrap_or(FieldValue::Null);
+            let deps = ingredient.get_deps(&value, node.row, self.recipes, true);
+
+            for dep in ingredient.get_deps(&value, node.row, self.recipes, false) {
+                if deps.contains(&dep) {
+                    continue;
+                }
+
+                if let Some(&target) = index.get(&dep) {
+                    if !emitted[target] {
+                        pending[source] += 1;
+                        dependents[target].push(edges.len());
+                        edges.push(Edge { source, target, field: None, active: true });
+                    }
+                }
+            }
+
+            if pending[source] == 0 {
+                ready.insert(source);
+            }
+
+            deferred.insert((source, field));
+        }
+
+        Ok(Sorting { order, deferred })
+    }
+
+    /// Get the recipe for the given type
+    fn recipe(&self, etype: &EntityType) -> Result<&'a Recipe> {
+        self.recipes.get(etype).ok_or_else(|| Error::MissingRecipe(etype.clone()))
+    }
+
+    /// Map the identity of every row to its index
+    fn index(&self) -> HashMap<Dep, usize> {
+        let mut index = HashMap::new();
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            if let Some(id) = self.node_id(node) {
+                index.insert((node.etype.clone(), id), i);
+            }
+        }
+
+        index
+    }
+
+    /// Identify the row at the given index
+    fn dep(&self, i: usize) -> Dep {
+        (self.nodes[i].etype.clone(), self.node_id(&self.nodes[i]).unwrap())
+    }
+
+    /// Get the id of a row, if its recipe has a primary key its key columns hold
+    fn node_id(&self, node: &Node) -> Option<Id> {
+        self.recipes.row_id(node.etype, node.row).ok().and_then(|id| id)
+    }
+}
+
+/// Walk from the first stuck row along unresolved dependencies until a row repeats
+///
+/// Returns the edges making up the cycle.
+fn find_cycle(edges: &[Edge], emitted: &[bool]) -> Vec<usize> {
+    let mut current = emitted.iter().position(|&e| !e).unwrap();
+    let mut path: Vec<usize> = vec![];
+    let mut seen: HashMap<usize, usize> = HashMap::new();
+
+    loop {
+        if let Some(&start) = seen.get(&current) {
+            return path[start..].to_vec();
+        }
+
+        seen.insert(current, path.len());
+
+        let e = edges.iter()
+            .position(|edge| edge.active && edge.source == current)
+            .unwrap();
+
+        path.push(e);
+        current = edges[e].target;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::row;
+    use recipe::{Ingredient, PrimaryKey};
+    use ingredients::raw::Raw;
+    use ingredients::reference::Reference;
+    use ingredients::circular::{Circular, CircularIngredient};
+    use ingredients::matcher::{Matcher, MatchPattern};
+
+    fn recipe(ingredients: Vec<(&str, Ingredient)>) -> Recipe {
+        Recipe::new(
+            PrimaryKey::String(String::from("id")),
+            ingredients.into_iter().map(|(k, v)| (k.to_string(), v)).collect(),
+        )
+    }
+
+    fn recipes(parent_recipe: Recipe, child_recipe: Recipe) -> RecipeSet {
+        let mut recipes = RecipeSet::new();
+        recipes.add(String::from("parents"), parent_recipe)
+            .add(String::from("children"), child_recipe);
+
+        recipes
+    }
+
+    fn reference(etype: &str) -> Ingredient {
+        Ingredient::Ref(Reference::new(String::from(etype), vec![FieldValue::Null]))
+    }
+
+    fn circular(etype: &str) -> Ingredient {
+        Ingredient::Circular(Circular::new(
+            CircularIngredient::Ref(Reference::new(String::from(etype), vec![FieldValue::Null])),
+            CircularIngredient::Raw(Raw::new(FieldValue::Null)),
+        ))
+    }
+
+    #[test]
+    fn it_sorts_parents_before_children() {
+        let parents = String::from("parents");
+        let children = String::from("children");
+        let recipes = recipes(
+            recipe(vec![]),
+            recipe(vec![("parent_id", reference("parents"))]),
+        );
+        let child = row(vec![("id", FieldValue::Int(1)), ("parent_id", FieldValue::Int(2))]);
+        let parent = row(vec![("id", FieldValue::Int(2))]);
+        let orphan = row(vec![("id", FieldValue::Int(3)), ("parent_id", FieldValue::Null)]);
+
+        let mut s = Sorter::new(&recipes);
+        s.add(&children, &child)
+            .add(&parents, &parent)
+            .add(&children, &orphan);
+
+        let sorting = s.sort().unwrap();
+
+        assert_eq!(&[1, 0, 2], sorting.order());
+        assert!(!sorting.is_deferred(0, "parent_id"));
+    }
+
+    #[test]
+    fn it_sorts_rows_with_negative_signed_ids() {
+        let parents = String::from("parents");
+        let children = String::from("children");
+        let mut parent_recipe = recipe(vec![]);
+        parent_recipe.typed(IdKind::SignedInt);
+        let recipes = recipes(parent_recipe, recipe(vec![("parent_id", reference("parents"))]));
+        let child = row(vec![("id", FieldValue::Int(1)), ("parent_id", FieldValue::Int(-5))]);
+        let parent = row(vec![("id", FieldValue::Int(-5))]);
+
+        let mut s = Sorter::new(&recipes);
+        s.add(&children, &child)
+            .add(&parents, &parent);
+
+        assert_eq!(&[1, 0], s.sort().unwrap().order());
+    }
+
+    #[test]
+    fn it_breaks_cycles_through_circular_fields() {
+        let parents = String::from("parents");
+        let children = String::from("children");
+        let recipes = recipes(
+            recipe(vec![("favorite_child_id", circular("children"))]),
+            recipe(vec![("parent_id", reference("parents"))]),
+        );
+        let parent = row(vec![("id", FieldValue::Int(1)), ("favorite_child_id", FieldValue::Int(2))]);
+        let child = row(vec![("id", FieldValue::Int(2)), ("parent_id", FieldValue::Int(1))]);
+
+        let mut s = Sorter::new(&recipes);
+        s.add(&children, &child)
+            .add(&parents, &parent);
+
+        let sorting = s.sort().unwrap();
+
+        assert_eq!(&[1, 0], sorting.order());
+        assert!(sorting.is_deferred(1, "favorite_child_id"));
+    }
+
+    #[test]
+    fn it_fails_on_cycles_without_circular_fields() {
+        let parents = String::from("parents");
+        let children = String::from("children");
+        let recipes = recipes(
+            recipe(vec![("favorite_child_id", reference("children"))]),
+            recipe(vec![("parent_id", reference("parents"))]),
+        );
+        let root = row(vec![("id", FieldValue::Int(3))]);
+        let parent = row(vec![("id", FieldValue::Int(1)), ("favorite_child_id", FieldValue::Int(2))]);
+        let child = row(vec![("id", FieldValue::Int(2)), ("parent_id", FieldValue::Int(1))]);
+
+        let mut s = Sorter::new(&recipes);
+        s.add(&parents, &root)
+            .add(&parents, &parent)
+            .add(&children, &child);
+
+        let err = s.sort().unwrap_err();
+
+        assert_eq!(Error::Cycle(vec![
+            (String::from("parents"), Id::Int(1)),
+            (String::from("children"), Id::Int(2)),
+        ]), err);
+        assert_eq!(
+            "Dependency cycle without a circular field to break it: parents 1 -> children 2 -> parents 1",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn it_breaks_cycles_through_circular_fields_nested_in_matches() {
+        let parents = String::from("parents");
+        let children = String::from("children");
+        let matcher = Matcher::new(String::from("favorite_type"), HashMap::new(), vec![
+            MatchPattern { pattern: String::from("^CHILD$"), ingredient: circular("children") },
+            MatchPattern { pattern: String::from("^PARENT$"), ingredient: reference("parents") },
+        ], None).unwrap();
+        let recipes = recipes(
+            recipe(vec![("favorite_id", Ingredient::Match(Box::new(matcher)))]),
+            recipe(vec![("parent_id", reference("parents"))]),
+        );
+        let parent = row(vec![
+            ("id", FieldValue::Int(1)),
+            ("favorite_type", FieldValue::String(String::from("CHILD"))),
+            ("favorite_id", FieldValue::Int(2)),
+        ]);
+        let child = row(vec![("id", FieldValue::Int(2)), ("parent_id", FieldValue::Int(1))]);
+
+        let mut s = Sorter::new(&recipes);
+        s.add(&children, &child)
+            .add(&parents, &parent);
+
+        let sorting = s.sort().unwrap();
+
+        assert_eq!(&[1, 0], sorting.order());
+        assert!(sorting.is_deferred(1, "favorite_id"));
+    }
+}
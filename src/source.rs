@@ -0,0 +1,351 @@
+//! Reading trees of rows out of a live database
+
+use contracts::*;
+use recipe::{RecipeSet, Recipe, PrimaryKey, Ingredient};
+use ingredients::ingredient::Ingredient as _;
+use tools::row_digest;
+use error::{Error, Result};
+use std::vec::Vec;
+use std::collections::HashSet;
+
+/// Somewhere rows can be read from, such as a database
+pub trait RowSource {
+    /// Fetch the row of the given type whose primary key holds the given id
+    fn fetch(&self, etype: &EntityType, primary_key: &str, id: &Id) -> Result<Option<Row>>;
+
+    /// Fetch every row of the given type whose fields hold the given values, such as the rows
+    /// referring to another row through a foreign key
+    fn fetch_children(&self, etype: &EntityType, foreign_keys: &Row) -> Result<Vec<Row>>;
+}
+
+/// Collect the rows to snapshot, starting from a root row
+///
+/// Walks down to every row referring to the root through a `Reference` or a `Morph`, and to
+/// every row referring to those, and so on. The rows referred to by any collected row are
+/// collected too, without walking down from them, so that every reference can be resolved.
+pub fn collect(recipes: &RecipeSet, source: &dyn RowSource, etype: EntityType, id: Id) -> Result<Vec<(EntityType, Row)>> {
+    let mut collector = Collector {
+        recipes,
+        source,
+        rows: vec![],
+        seen: HashSet::new(),
+        keyless: HashSet::new(),
+    };
+
+    let root = collector.fetch(&etype, &id)?
+        .ok_or_else(|| Error::MissingRow(etype.clone(), id))?;
+
+    collector.add(etype, root)?;
+
+    let mut i = 0;
+    while i < collector.rows.len() {
+        let (etype, row) = collector.rows[i].clone();
+
+        for (child_type, child) in collector.children(&etype, &row)? {
+            collector.add(child_type, child)?;
+        }
+
+        i += 1;
+    }
+
+    let mut i = 0;
+    while i < collector.rows.len() {
+        let (etype, row) = collector.rows[i].clone();
+
+        for (dep_type, dep_id) in collector.deps(&etype, &row)? {
+            if collector.seen.contains(&(dep_type.clone(), dep_id.clone())) {
+                continue;
+            }
+
+            let dep = collector.fetch(&dep_type, &dep_id)?
+                .ok_or_else(|| Error::DanglingReference(dep_type.clone(), dep_id))?;
+
+            collector.add(dep_type, dep)?;
+        }
+
+        i += 1;
+    }
+
+    Ok(collector.rows)
+}
+
+struct Collector<'a> {
+    recipes: &'a RecipeSet,
+    source: &'a dyn RowSource,
+    rows: Vec<(EntityType, Row)>,
+    /// The rows collected so far, by id
+    seen: HashSet<Dep>,
+    /// The rows without a primary key collected so far, by a digest of their content
+    keyless: HashSet<[u8; 32]>,
+}
+
+impl<'a> Collector<'a> {
+    fn recipe(&self, etype: &EntityType) -> Result<&'a Recipe> {
+        self.recipes.get(etype).ok_or_else(|| Error::MissingRecipe(etype.clone()))
+    }
+
+    fn fetch(&self, etype: &EntityType, id: &Id) -> Result<Option<Row>> {
+        let primary_key = self.recipe(etype)?.primary_key();
+
+        match primary_key {
+            PrimaryKey::String(pk) => self.source.fetch(etype, pk, id),
+            PrimaryKey::Composite(_) => match primary_key.key_row(id) {
+                Some(key) => Ok(self.source.fetch_children(etype, &key)?.into_iter().next()),
+                None => Err(Error::UnresolvableId(etype.clone(), id.clone())),
+            },
+            PrimaryKey::Null => Ok(None),
+        }
+    }
+
+    /// Add a row unless it has been collected already
+    fn add(&mut self, etype: EntityType, row: Row) -> Result<()> {
+        let recipe = self.recipe(&etype)?;
+
+        let new = match recipe.primary_key() {
+            PrimaryKey::Null => self.keyless.insert(row_digest(&etype, &row)),
+            _ => {
+                let id = self.recipes.row_id(&etype, &row)?
+                    .ok_or_else(|| Error::MissingPrimaryKey(etype.clone()))?;
+
+                self.seen.insert((etype.clone(), id))
+            },
+        };
+
+        if new {
+            self.rows.push((etype, row));
+        }
+
+        Ok(())
+    }
+
+    /// Fetch the rows referring to the given row through a `Reference` or a `Morph`
+    fn children(&self, etype: &EntityType, row: &Row) -> Result<Vec<(EntityType, Row)>> {
+        let key: Vec<FieldValue> = self.recipe(etype)?.primary_key()
+            .columns()
+            .into_iter()
+            .map(|pk| row.get(pk).cloned().unwrap_or(FieldValue::Null))
+            .collect();
+
+        // Nothing refers to rows without a primary key
+        if key.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut children = vec![];
+
+        for child_type in self.recipes.entity_types() {
+            let recipe = self.recipe(child_type)?;
+            let mut fields: Vec<&String> = recipe.ingredients().keys().collect();
+            fields.sort();
+
+            for field in fields {
+                let mut foreign_keys = vec![];
+
+                match recipe.ingredient(field) {
+                    Some(Ingredient::Ref(r)) if r.entity_type() == etype && r.key_fields().len() + 1 == key.len() => {
+                        let mut keys = Row::new();
+                        keys.insert(field.clone(), key[0].clone());
+                        for (key_field, value) in r.key_fields().iter().zip(&key[1..]) {
+                            keys.insert(key_field.clone(), value.clone());
+                        }
+                        foreign_keys.push(keys);
+                    },
+                    // Morphs only refer to rows keyed by a single column
+                    Some(Ingredient::Morph(m)) if key.len() == 1 => {
+                        for morph_type in m.morph_types(etype) {
+                            let mut keys = Row::new();
+                            keys.insert(field.clone(), key[0].clone());
+                            keys.insert(m.field().to_string(), morph_type.clone());
+                            foreign_keys.push(keys);
+                        }
+                    },
+                    _ => {},
+                }
+
+                for keys in foreign_keys {
+                    for child in self.source.fetch_children(child_type, &keys)? {
+                        children.push((child_type.clone(), child));
+                    }
+                }
+            }
+        }
+
+        Ok(children)
+    }
+
+    /// The rows the given row refers to, with and without its circular fields deferred
+    fn deps(&self, etype: &EntityType, row: &Row) -> Result<Vec<Dep>> {
+        let mut deps = vec![];
+
+        for (field, ingredient) in self.recipe(etype)?.ingredients() {
+            let value = row.get(field).cloned().unwrap_or(FieldValue::Null);
+
+            deps.extend(ingredient.get_deps(&value, row, self.recipes, true));
+            deps.extend(ingredient.get_deps(&value, row, self.recipes, false));
+        }
+
+        Ok(deps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tools::try_field_value_to_id;
+    use test_support::row;
+    use ingredients::value::Value;
+    use ingredients::reference::Reference;
+    use ingredients::morph::Morph;
+    use std::collections::HashMap;
+
+    /// Tables of rows, all with the primary key `id`
+    struct RowSourceMock {
+        tables: HashMap<EntityType, Vec<Row>>,
+    }
+
+    impl RowSource for RowSourceMock {
+        fn fetch(&self, etype: &EntityType, primary_key: &str, id: &Id) -> Result<Option<Row>> {
+            Ok(self.tables[etype].iter()
+                .find(|row| try_field_value_to_id(&row[primary_key]) == Ok(Some(id.clone())))
+                .cloned())
+        }
+
+        fn fetch_children(&self, etype: &EntityType, foreign_keys: &Row) -> Result<Vec<Row>> {
+            Ok(self.tables[etype].iter()
+                .filter(|row| foreign_keys.iter().all(|(field, value)| row.get(field) == Some(value)))
+                .cloned()
+                .collect())
+        }
+    }
+
+    fn recipes() -> RecipeSet {
+        let mut post_ingredients = HashMap::new();
+        post_ingredients.insert(String::from("author_id"), Ingredient::Ref(Reference::new(String::from("users"), vec![])));
+
+        let mut comment_ingredients = HashMap::new();
+        comment_ingredients.insert(String::from("post_id"), Ingredient::Ref(Reference::new(String::from("posts"), vec![])));
+
+        let mut morph_map = HashMap::new();
+        morph_map.insert(FieldValue::String(String::from("post")), String::from("posts"));
+        morph_map.insert(FieldValue::String(String::from("comment")), String::from("comments"));
+
+        let mut like_ingredients = HashMap::new();
+        like_ingredients.insert(String::from("likeable_id"), Ingredient::Morph(Morph::new(String::from("likeable_type"), morph_map, vec![])));
+        like_ingredients.insert(String::from("likeable_type"), Ingredient::Value(Value::new()));
+
+        let mut recipes = RecipeSet::new();
+        recipes
+            .add(String::from("users"), Recipe::new(PrimaryKey::String(String::from("id")), HashMap::new()))
+            .add(String::from("posts"), Recipe::new(PrimaryKey::String(String::from("id")), post_ingredients))
+            .add(String::from("comments"), Recipe::new(PrimaryKey::String(String::from("id")), comment_ingredients))
+            .add(String::from("likes"), Recipe::new(PrimaryKey::String(String::from("id")), like_ingredients));
+
+        recipes
+    }
+
+    fn source() -> RowSourceMock {
+        let mut tables = HashMap::new();
+
+        tables.insert(String::from("users"), vec![
+            row(vec![("id", FieldValue::Int(1))]),
+        ]);
+        tables.insert(String::from("posts"), vec![
+            row(vec![("id", FieldValue::Int(1)), ("author_id", FieldValue::Int(1))]),
+            row(vec![("id", FieldValue::Int(2)), ("author_id", FieldValue::Int(1))]),
+        ]);
+        tables.insert(String::from("comments"), vec![
+            row(vec![("id", FieldValue::Int(1)), ("post_id", FieldValue::Int(1))]),
+            row(vec![("id", FieldValue::Int(2)), ("post_id", FieldValue::Int(2))]),
+        ]);
+        tables.insert(String::from("likes"), vec![
+            row(vec![("id", FieldValue::Int(1)), ("likeable_id", FieldValue::Int(1)), ("likeable_type", FieldValue::String(String::from("comment")))]),
+            row(vec![("id", FieldValue::Int(2)), ("likeable_id", FieldValue::Int(1)), ("likeable_type", FieldValue::String(String::from("post")))]),
+            row(vec![("id", FieldValue::Int(3)), ("likeable_id", FieldValue::Int(2)), ("likeable_type", FieldValue::String(String::from("post")))]),
+        ]);
+
+        RowSourceMock { tables }
+    }
+
+    fn ids(rows: &[(EntityType, Row)]) -> Vec<String> {
+        let mut ids: Vec<String> = rows.iter()
+            .map(|(etype, row)| format!("{} {:?}", etype, row["id"]))
+            .collect();
+        ids.sort();
+
+        ids
+    }
+
+    #[test]
+    fn it_collects_the_subtree_of_a_row_and_what_it_refers_to() {
+        let rows = collect(&recipes(), &source(), String::from("posts"), Id::Int(1)).unwrap();
+
+        assert_eq!(vec![
+            "comments Int(1)",
+            "likes Int(1)",
+            "likes Int(2)",
+            "posts Int(1)",
+            "users Int(1)",
+        ], ids(&rows));
+    }
+
+    #[test]
+    fn it_doesnt_walk_down_from_referred_rows() {
+        let rows = collect(&recipes(), &source(), String::from("comments"), Id::Int(2)).unwrap();
+
+        assert_eq!(vec!["comments Int(2)", "posts Int(2)", "users Int(1)"], ids(&rows));
+    }
+
+    #[test]
+    fn it_walks_down_from_rows_keyed_by_several_columns() {
+        let mut recipes = recipes();
+
+        let mut member_ingredients = HashMap::new();
+        member_ingredients.insert(String::from("user_id"), Ingredient::Ref(Reference::new(String::from("users"), vec![])));
+        member_ingredients.insert(String::from("group_id"), Ingredient::Value(Value::new()));
+
+        let mut member_ref = Reference::new(String::from("members"), vec![]);
+        member_ref.composite(vec![String::from("member_group_id")]);
+
+        let mut note_ingredients = HashMap::new();
+        note_ingredients.insert(String::from("member_user_id"), Ingredient::Ref(member_ref));
+        note_ingredients.insert(String::from("member_group_id"), Ingredient::Value(Value::new()));
+
+        recipes
+            .add(String::from("members"), Recipe::new(PrimaryKey::Composite(vec![String::from("user_id"), String::from("group_id")]), member_ingredients))
+            .add(String::from("notes"), Recipe::new(PrimaryKey::String(String::from("id")), note_ingredients));
+
+        let mut source = source();
+        source.tables.insert(String::from("members"), vec![
+            row(vec![("user_id", FieldValue::Int(1)), ("group_id", FieldValue::Int(7))]),
+            row(vec![("user_id", FieldValue::Int(1)), ("group_id", FieldValue::Int(8))]),
+        ]);
+        source.tables.insert(String::from("notes"), vec![
+            row(vec![("id", FieldValue::Int(1)), ("member_user_id", FieldValue::Int(1)), ("member_group_id", FieldValue::Int(7))]),
+            row(vec![("id", FieldValue::Int(2)), ("member_user_id", FieldValue::Int(1)), ("member_group_id", FieldValue::Int(8))]),
+        ]);
+
+        let rows = collect(&recipes, &source, String::from("members"), Id::Composite(vec![Id::Int(1), Id::Int(7)])).unwrap();
+
+        assert_eq!(3, rows.len());
+        assert_eq!((String::from("members"), source.tables["members"][0].clone()), rows[0]);
+        assert_eq!((String::from("notes"), source.tables["notes"][0].clone()), rows[1]);
+        assert_eq!((String::from("users"), source.tables["users"][0].clone()), rows[2]);
+    }
+
+    #[test]
+    fn it_fails_on_missing_rows() {
+        let mut source = source();
+
+        assert_eq!(
+            Err(Error::MissingRow(String::from("posts"), Id::Int(3))),
+            collect(&recipes(), &source, String::from("posts"), Id::Int(3))
+        );
+
+        source.tables.get_mut("users").unwrap().clear();
+
+        assert_eq!(
+            Err(Error::DanglingReference(String::from("users"), Id::Int(1))),
+            collect(&recipes(), &source, String::from("posts"), Id::Int(1))
+        );
+    }
+}
@@ -0,0 +1,592 @@
+use contracts::*;
+use book_keeper::*;
+use recipe::{RecipeSet, Recipe, PrimaryKey};
+use ingredients::ingredient::Ingredient;
+use serializer::resolve_primary_key;
+use deserializer::Deserializer;
+use snapshot::{FORMAT_VERSION, check_version};
+use tools::row_digest;
+use error::{Error, Result};
+use std::vec::Vec;
+use std::string::String;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{BufRead, Lines, Write};
+use serde::Serialize;
+use serde_json;
+
+/// The first line of a streamed snapshot
+#[derive(Serialize, Deserialize)]
+struct Header<T> {
+    version: u64,
+    recipes: T,
+}
+
+/// Write a snapshot as JSON Lines, one operation at a time
+///
+/// The first line holds the format version and the recipes, every following line an operation.
+pub struct SnapshotWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    /// Start a snapshot by writing its header
+    pub fn new(writer: W, recipes: &RecipeSet) -> Result<SnapshotWriter<W>> {
+        let mut w = SnapshotWriter { writer };
+
+        w.write_line(&Header { version: FORMAT_VERSION, recipes })?;
+
+        Ok(w)
+    }
+
+    pub fn write(&mut self, op: &Operation) -> Result<()> {
+        self.write_line(op)
+    }
+
+    /// Flush everything written and hand back the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush().map_err(Error::from)?;
+
+        Ok(self.writer)
+    }
+
+    /// Write a value on a line of its own, with sorted keys
+    fn write_line<T: Serialize>(&mut self, value: &T) -> Result<()> {
+        let json = serde_json::to_value(value).map_err(|e| Error::InvalidSnapshot(e.to_string()))?;
+
+        serde_json::to_writer(&mut self.writer, &json).map_err(|e| Error::Io(e.to_string()))?;
+        self.writer.write_all(b"\n").map_err(Error::from)
+    }
+}
+
+/// Read a snapshot written by `SnapshotWriter`, one operation at a time
+pub struct SnapshotReader<R: BufRead> {
+    recipes: RecipeSet,
+    lines: Lines<R>,
+}
+
+impl<R: BufRead> SnapshotReader<R> {
+    /// Read the header, refusing any other format version than the current one
+    pub fn new(reader: R) -> Result<SnapshotReader<R>> {
+        let mut lines = reader.lines();
+
+        let json: serde_json::Value = match next_line(&mut lines)? {
+            Some(line) => serde_json::from_str(&line).map_err(|e| Error::InvalidSnapshot(e.to_string()))?,
+            None => return Err(Error::InvalidSnapshot(String::from("missing header"))),
+        };
+
+        check_version(json.get("version").and_then(|v| v.as_u64()))?;
+
+        let header: Header<RecipeSet> = serde_json::from_value(json).map_err(|e| Error::InvalidSnapshot(e.to_string()))?;
+
+        Ok(SnapshotReader { recipes: header.recipes, lines })
+    }
+
+    pub fn recipes(&self) -> &RecipeSet { &self.recipes }
+
+    pub fn into_recipes(self) -> RecipeSet { self.recipes }
+}
+
+impl<R: BufRead> Iterator for SnapshotReader<R> {
+    type Item = Result<Operation>;
+
+    fn next(&mut self) -> Option<Result<Operation>> {
+        match next_line(&mut self.lines) {
+            Ok(Some(line)) => Some(serde_json::from_str(&line).map_err(|e| Error::InvalidSnapshot(e.to_string()))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Get the next line that isn't blank
+fn next_line<R: BufRead>(lines: &mut Lines<R>) -> Result<Option<String>> {
+    for line in lines {
+        let line = line.map_err(Error::from)?;
+
+        if !line.trim().is_empty() {
+            return Ok(Some(line));
+        }
+    }
+
+    Ok(None)
+}
+
+/// A row or an UPDATE held back until the rows it depends on have been written
+enum Parked {
+    Insert(EntityType, Row),
+    /// The row, its id, the deferred fields and the values of the fields required to update them
+    Update(EntityType, Id, Row, Vec<String>, Row),
+}
+
+struct Waiting {
+    parked: Parked,
+    /// The dependencies not yet written
+    missing: Vec<Dep>,
+}
+
+/// Serialize rows as they arrive, writing each operation as soon as the rows it depends on
+/// have been written
+///
+/// Only rows waiting for a dependency are held in memory. A circular field whose dependency
+/// hasn't been written yet is inserted with its fallback right away and set in an UPDATE once
+/// the dependency has been written. The books are the record of what has been written.
+///
+/// Memory still grows with the stream: the books map the id of every row written, and a digest
+/// is kept of every distinct row without a primary key, 32 bytes each.
+pub struct StreamSerializer<'a, W: Write> {
+    recipes: &'a RecipeSet,
+    books: &'a dyn BookKeeper,
+    writer: SnapshotWriter<W>,
+    waiting: HashMap<usize, Waiting>,
+    /// Waiting rows and UPDATEs by the dependencies they wait for
+    dependents: HashMap<Dep, Vec<usize>>,
+    /// Digests of the contents of the rows without a primary key added so far, written only once
+    keyless: HashSet<[u8; 32]>,
+    next_slot: usize,
+}
+
+impl<'a, W: Write> StreamSerializer<'a, W> {
+    pub fn new(recipes: &'a RecipeSet, books: &'a dyn BookKeeper, writer: W) -> Result<StreamSerializer<'a, W>> {
+        Ok(StreamSerializer {
+            recipes,
+            books,
+            writer: SnapshotWriter::new(writer, recipes)?,
+            waiting: HashMap::new(),
+            dependents: HashMap::new(),
+            keyless: HashSet::new(),
+            next_slot: 0,
+        })
+    }
+
+    /// Serialize a row, or hold on to it until its dependencies have been written
+    pub fn add(&mut self, etype: EntityType, row: Row) -> Result<()> {
+        let recipe = self.recipe(&etype)?;
+
+        if let PrimaryKey::Null = recipe.primary_key() {
+            if !self.keyless.insert(row_digest(&etype, &row)) {
+                return Ok(());
+            }
+        }
+
+        let mut missing = vec![];
+
+        for (field, ingredient) in recipe.ingredients() {
+            let value = row.get(field).cloned().unwrap_or(FieldValue::Null);
+
+            let deps = if self.defers(recipe, ingredient, &value, &row) {
+                ingredient.get_deps(&value, &row, self.recipes, false)
+            } else {
+                ingredient.get_deps(&value, &row, self.recipes, true)
+            };
+
+            for dep in deps {
+                if !self.is_written(&dep) && !missing.contains(&dep) {
+                    missing.push(dep);
+                }
+            }
+        }
+
+        self.park(Parked::Insert(etype, row), missing)
+    }
+
+    /// Fail if anything is still waiting, flush and hand back the underlying writer
+    pub fn finish(self) -> Result<W> {
+        let first = self.waiting.keys().min().map(|slot| &self.waiting[slot]);
+
+        if let Some(w) = first {
+            return Err(self.explain(w));
+        }
+
+        self.writer.finish()
+    }
+
+    /// Write the operation if nothing is missing, otherwise wait for the missing dependencies
+    fn park(&mut self, parked: Parked, missing: Vec<Dep>) -> Result<()> {
+        if missing.is_empty() {
+            return self.write(parked);
+        }
+
+        let slot = self.next_slot;
+        self.next_slot += 1;
+
+        for dep in &missing {
+            self.dependents.entry(dep.clone()).or_default().push(slot);
+        }
+
+        self.waiting.insert(slot, Waiting { parked, missing });
+
+        Ok(())
+    }
+
+    /// Write the operation and everything waiting for it
+    fn write(&mut self, parked: Parked) -> Result<()> {
+        let mut ready = VecDeque::new();
+        ready.push_back(parked);
+
+        while let Some(parked) = ready.pop_front() {
+            let written = match parked {
+                Parked::Insert(etype, row) => self.write_insert(etype, row)?,
+                Parked::Update(etype, id, row, fields, extra) => {
+                    self.write_update(etype, id, row, fields, extra)?;
+                    None
+                },
+            };
+
+            if let Some(dep) = written {
+                for slot in self.dependents.remove(&dep).unwrap_or_default() {
+                    let done = match self.waiting.get_mut(&slot) {
+                        Some(w) => {
+                            w.missing.retain(|d| *d != dep);
+                            w.missing.is_empty()
+                        },
+                        None => false,
+                    };
+
+                    if done {
+                        ready.push_back(self.waiting.remove(&slot).unwrap().parked);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the INSERT for a row, parking an UPDATE for its deferred fields
+    ///
+    /// Returns the identity of the row written, if it has one.
+    fn write_insert(&mut self, etype: EntityType, row: Row) -> Result<Option<Dep>> {
+        let recipe = self.recipe(&etype)?;
+
+        // Decided before the row's own id is in the books, so that fields referring to their own
+        // row are deferred as well
+        let deferred: Vec<String> = recipe.ingredients()
+            .iter()
+            .filter(|(field, ingredient)| {
+                let value = row.get(*field).cloned().unwrap_or(FieldValue::Null);
+
+                self.defers(recipe, ingredient, &value, &row)
+            })
+            .map(|(field, _)| field.clone())
+            .collect();
+
+        let id = resolve_primary_key(&etype, self.recipes, &row, self.books)?;
+        let mut insert = Row::new();
+        let mut extra_fields = vec![];
+        let mut missing = vec![];
+
+        for (field, ingredient) in recipe.ingredients() {
+            let value = row.get(field).cloned().unwrap_or(FieldValue::Null);
+
+            if deferred.contains(field) {
+                extra_fields.extend(ingredient.get_required_extra_fields());
+                insert.insert(field.clone(), ingredient.snapper_serialize(&value, &row, self.recipes, self.books, false)?);
+
+                for dep in ingredient.get_deps(&value, &row, self.recipes, true) {
+                    if !self.is_written(&dep) && !missing.contains(&dep) {
+                        missing.push(dep);
+                    }
+                }
+            } else {
+                insert.insert(field.clone(), ingredient.snapper_serialize(&value, &row, self.recipes, self.books, true)?);
+            }
+        }
+
+        let (key, id) = match id.as_ref().and_then(|id| recipe.primary_key().key_row(id)) {
+            Some(key) => (key, id.unwrap()),
+            None => {
+                self.writer.write(&Operation::new(OperationKind::Insert, etype, id, insert))?;
+
+                return Ok(None);
+            },
+        };
+
+        insert.extend(key);
+
+        let mut extra = Row::new();
+        for field in extra_fields {
+            if let Some(value) = insert.get(&field) {
+                extra.insert(field, value.clone());
+            }
+        }
+
+        self.writer.write(&Operation::new(OperationKind::Insert, etype.clone(), Some(id.clone()), insert).defer(deferred.clone()))?;
+
+        let original = self.recipes.row_id(&etype, &row)?
+            .ok_or_else(|| Error::MissingPrimaryKey(etype.clone()))?;
+
+        if !deferred.is_empty() {
+            self.park(Parked::Update(etype.clone(), id, row, deferred, extra), missing)?;
+        }
+
+        Ok(Some((etype, original)))
+    }
+
+    /// Write the UPDATE setting the real values of deferred fields
+    fn write_update(&mut self, etype: EntityType, id: Id, row: Row, fields: Vec<String>, extra: Row) -> Result<()> {
+        let recipe = self.recipe(&etype)?;
+        let mut update = extra;
+
+        for field in fields {
+            let value = row.get(&field).cloned().unwrap_or(FieldValue::Null);
+            let serialized = recipe.ingredient(&field).unwrap().snapper_serialize(&value, &row, self.recipes, self.books, true)?;
+
+            update.insert(field, serialized);
+        }
+
+        if let Some(key) = recipe.primary_key().key_row(&id) {
+            update.extend(key);
+        }
+
+        self.writer.write(&Operation::new(OperationKind::Update, etype, Some(id), update))
+    }
+
+    /// Whether a circular field has to be inserted with its fallback, because a dependency only
+    /// the real value has hasn't been written yet
+    fn defers(&self, recipe: &Recipe, ingredient: &::recipe::Ingredient, value: &FieldValue, row: &Row) -> bool {
+        if !ingredient.is_circular() || matches!(recipe.primary_key(), PrimaryKey::Null) {
+            return false;
+        }
+
+        let fallback_deps = ingredient.get_deps(value, row, self.recipes, false);
+
+        ingredient.get_deps(value, row, self.recipes, true)
+            .iter()
+            .any(|dep| !fallback_deps.contains(dep) && !self.is_written(dep))
+    }
+
+    fn is_written(&self, dep: &Dep) -> bool {
+        self.books.resolve_id(dep.0.clone(), dep.1.clone(), false).is_some()
+    }
+
+    /// Get the recipe for the given type
+    fn recipe(&self, etype: &EntityType) -> Result<&'a Recipe> {
+        self.recipes.get(etype).ok_or_else(|| Error::MissingRecipe(etype.clone()))
+    }
+
+    /// Explain why something is still waiting at the end of the stream
+    fn explain(&self, w: &Waiting) -> Error {
+        // Follow rows waiting for rows until one repeats or the chain leads out of the stream
+        let mut path: Vec<Dep> = vec![];
+        let mut current = w;
+
+        loop {
+            let dep = current.missing[0].clone();
+
+            if let Some(start) = path.iter().position(|d| *d == dep) {
+                return Error::Cycle(path[start..].to_vec());
+            }
+
+            let next = self.waiting.values().find(|other| match &other.parked {
+                Parked::Insert(etype, row) => match self.recipes.row_id(etype, row) {
+                    Ok(Some(id)) => (etype.clone(), id) == dep,
+                    _ => false,
+                },
+                Parked::Update(..) => false,
+            });
+
+            match next {
+                Some(next) => {
+                    path.push(dep);
+                    current = next;
+                },
+                None => return Error::DanglingReference(dep.0, dep.1),
+            }
+        }
+    }
+}
+
+/// Deserialize a streamed snapshot one operation at a time, using the recipes it was written with
+pub struct StreamDeserializer<'a, R: BufRead> {
+    reader: SnapshotReader<R>,
+    books: &'a dyn BookKeeper,
+}
+
+impl<'a, R: BufRead> StreamDeserializer<'a, R> {
+    pub fn new(reader: SnapshotReader<R>, books: &'a dyn BookKeeper) -> StreamDeserializer<'a, R> {
+        StreamDeserializer {
+            reader,
+            books,
+        }
+    }
+}
+
+impl<'a, R: BufRead> Iterator for StreamDeserializer<'a, R> {
+    type Item = Result<Operation>;
+
+    fn next(&mut self) -> Option<Result<Operation>> {
+        self.reader.next().map(|op| {
+            op.and_then(|op| Deserializer::new(&self.reader.recipes).deserialize_operation(&op, self.books))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::{recipes, row};
+    use recipe::Ingredient;
+    use ingredients::reference::Reference;
+
+    #[test]
+    fn it_streams_rows_through_a_snapshot() {
+        let recipes = recipes();
+        let books = MemoryBookKeeper::new();
+        let mut s = StreamSerializer::new(&recipes, &books, vec![]).unwrap();
+
+        s.add(String::from("children"), row(vec![("id", FieldValue::Int(2)), ("parent_id", FieldValue::Int(1))])).unwrap();
+        s.add(String::from("parents"), row(vec![
+            ("id", FieldValue::Int(1)),
+            ("name", FieldValue::String(String::from("Foo"))),
+            ("favorite_child_id", FieldValue::Int(2)),
+        ])).unwrap();
+
+        let written = s.finish().unwrap();
+        let reader = SnapshotReader::new(&written[..]).unwrap();
+
+        assert!(reader.recipes().contains("parents"));
+
+        let ops: Vec<Operation> = reader.map(|op| op.unwrap()).collect();
+
+        assert_eq!(3, ops.len());
+        assert_eq!((OperationKind::Insert, "parents"), (ops[0].op(), &ops[0].type_()[..]));
+        assert_eq!(Some(&FieldValue::Null), ops[0].row().get("favorite_child_id"));
+        assert_eq!((OperationKind::Insert, "children"), (ops[1].op(), &ops[1].type_()[..]));
+        assert_eq!(ops[0].row().get("id"), ops[1].row().get("parent_id"));
+        assert_eq!((OperationKind::Update, ops[0].id()), (ops[2].op(), ops[2].id()));
+        assert_eq!(ops[1].row().get("id"), ops[2].row().get("favorite_child_id"));
+
+        let persisted_books = MemoryBookKeeper::new();
+        let persisted: Vec<Operation> = StreamDeserializer::new(SnapshotReader::new(&written[..]).unwrap(), &persisted_books)
+            .map(|op| op.unwrap())
+            .collect();
+
+        assert_eq!(3, persisted.len());
+        assert_eq!(persisted[0].row().get("id"), persisted[1].row().get("parent_id"));
+        assert_eq!(persisted[1].row().get("id"), persisted[2].row().get("favorite_child_id"));
+    }
+
+    #[test]
+    fn it_only_holds_rows_waiting_for_dependencies() {
+        let recipes = recipes();
+        let books = MemoryBookKeeper::new();
+        let mut s = StreamSerializer::new(&recipes, &books, vec![]).unwrap();
+
+        for i in 1..100 {
+            s.add(String::from("parents"), row(vec![("id", FieldValue::Int(i)), ("favorite_child_id", FieldValue::Int(i + 1000))])).unwrap();
+            s.add(String::from("children"), row(vec![("id", FieldValue::Int(i + 1000)), ("parent_id", FieldValue::Int(i))])).unwrap();
+
+            assert!(s.waiting.is_empty());
+        }
+
+        s.add(String::from("children"), row(vec![("id", FieldValue::Int(5000)), ("parent_id", FieldValue::Int(500))])).unwrap();
+
+        assert_eq!(1, s.waiting.len());
+    }
+
+    #[test]
+    fn it_writes_keyless_rows_once_their_references_are_written() {
+        let mut recipes = recipes();
+        let mut visit_ingredients = HashMap::new();
+        visit_ingredients.insert(String::from("child_id"), Ingredient::Ref(Reference::new(String::from("children"), vec![])));
+        recipes.add(String::from("visits"), Recipe::new(PrimaryKey::Null, visit_ingredients));
+
+        let books = MemoryBookKeeper::new();
+        let mut s = StreamSerializer::new(&recipes, &books, vec![]).unwrap();
+
+        s.add(String::from("visits"), row(vec![("child_id", FieldValue::Int(2))])).unwrap();
+        s.add(String::from("visits"), row(vec![("child_id", FieldValue::Int(2))])).unwrap();
+        s.add(String::from("parents"), row(vec![("id", FieldValue::Int(1))])).unwrap();
+        s.add(String::from("children"), row(vec![("id", FieldValue::Int(2)), ("parent_id", FieldValue::Int(1))])).unwrap();
+
+        let written = s.finish().unwrap();
+        let ops: Vec<Operation> = SnapshotReader::new(&written[..]).unwrap().map(|op| op.unwrap()).collect();
+
+        assert_eq!(vec!["parents", "children", "visits"], ops.iter().map(|op| op.type_().as_str()).collect::<Vec<&str>>());
+        assert_eq!(None, ops[2].id());
+    }
+
+    #[test]
+    fn it_streams_rows_with_negative_signed_ids() {
+        let mut recipes = recipes();
+        let mut parents = Recipe::new(PrimaryKey::String(String::from("id")), HashMap::new());
+        parents.typed(IdKind::SignedInt);
+        recipes.add(String::from("parents"), parents);
+
+        let books = MemoryBookKeeper::new();
+        let mut s = StreamSerializer::new(&recipes, &books, vec![]).unwrap();
+
+        s.add(String::from("parents"), row(vec![("id", FieldValue::Int(-5))])).unwrap();
+
+        let written = s.finish().unwrap();
+        let ops: Vec<Operation> = SnapshotReader::new(&written[..]).unwrap().map(|op| op.unwrap()).collect();
+
+        assert_eq!(1, ops.len());
+        assert_eq!(books.resolve_id(String::from("parents"), Id::Signed(-5), false), ops[0].id());
+    }
+
+    #[test]
+    fn it_defers_fields_referring_to_their_own_row() {
+        use ingredients::raw::Raw;
+        use ingredients::circular::{Circular, CircularIngredient};
+
+        let mut node_ingredients = HashMap::new();
+        node_ingredients.insert(String::from("next_id"), Ingredient::Circular(Circular::new(
+            CircularIngredient::Ref(Reference::new(String::from("nodes"), vec![])),
+            CircularIngredient::Raw(Raw::new(FieldValue::Null)),
+        )));
+
+        let mut recipes = RecipeSet::new();
+        recipes.add(String::from("nodes"), Recipe::new(PrimaryKey::String(String::from("id")), node_ingredients));
+
+        let books = MemoryBookKeeper::new();
+        let mut s = StreamSerializer::new(&recipes, &books, vec![]).unwrap();
+
+        s.add(String::from("nodes"), row(vec![("id", FieldValue::Int(1)), ("next_id", FieldValue::Int(1))])).unwrap();
+
+        let written = s.finish().unwrap();
+        let ops: Vec<Operation> = SnapshotReader::new(&written[..]).unwrap().map(|op| op.unwrap()).collect();
+
+        assert_eq!(2, ops.len());
+        assert_eq!(Some(&FieldValue::Null), ops[0].row().get("next_id"));
+        assert_eq!((OperationKind::Update, ops[0].id()), (ops[1].op(), ops[1].id()));
+    }
+
+    #[test]
+    fn it_fails_on_rows_still_waiting_at_the_end() {
+        let recipes = recipes();
+        let books = MemoryBookKeeper::new();
+        let mut s = StreamSerializer::new(&recipes, &books, vec![]).unwrap();
+
+        s.add(String::from("children"), row(vec![("id", FieldValue::Int(2)), ("parent_id", FieldValue::Int(1))])).unwrap();
+
+        assert_eq!(Some(Error::DanglingReference(String::from("parents"), Id::Int(1))), s.finish().err());
+    }
+
+    #[test]
+    fn it_fails_on_cycles_without_circular_fields() {
+        let mut ingredients = HashMap::new();
+        ingredients.insert(String::from("next_id"), Ingredient::Ref(Reference::new(String::from("nodes"), vec![])));
+
+        let mut recipes = RecipeSet::new();
+        recipes.add(String::from("nodes"), Recipe::new(PrimaryKey::String(String::from("id")), ingredients));
+
+        let books = MemoryBookKeeper::new();
+        let mut s = StreamSerializer::new(&recipes, &books, vec![]).unwrap();
+
+        s.add(String::from("nodes"), row(vec![("id", FieldValue::Int(1)), ("next_id", FieldValue::Int(2))])).unwrap();
+        s.add(String::from("nodes"), row(vec![("id", FieldValue::Int(2)), ("next_id", FieldValue::Int(1))])).unwrap();
+
+        assert_eq!(Some(Error::Cycle(vec![
+            (String::from("nodes"), Id::Int(2)),
+            (String::from("nodes"), Id::Int(1)),
+        ])), s.finish().err());
+    }
+
+    #[test]
+    fn it_refuses_other_versions() {
+        let written = "{\"recipes\":{},\"version\":2}\n";
+
+        assert_eq!(Some(Error::UnsupportedVersion(2)), SnapshotReader::new(written.as_bytes()).err());
+    }
+}
@@ -0,0 +1,33 @@
+//! Fixtures shared by the tests of several modules
+
+use contracts::*;
+use recipe::{RecipeSet, Recipe, PrimaryKey, Ingredient};
+use ingredients::value::Value;
+use ingredients::raw::Raw;
+use ingredients::reference::Reference;
+use ingredients::circular::{Circular, CircularIngredient};
+use std::collections::HashMap;
+
+/// Parents with a circular reference to their favorite child, and children referring to their parent
+pub fn recipes() -> RecipeSet {
+    let mut parent_ingredients = HashMap::new();
+    parent_ingredients.insert(String::from("name"), Ingredient::Value(Value::new()));
+    parent_ingredients.insert(String::from("favorite_child_id"), Ingredient::Circular(Circular::new(
+        CircularIngredient::Ref(Reference::new(String::from("children"), vec![FieldValue::Null])),
+        CircularIngredient::Raw(Raw::new(FieldValue::Null)),
+    )));
+
+    let mut child_ingredients = HashMap::new();
+    child_ingredients.insert(String::from("parent_id"), Ingredient::Ref(Reference::new(String::from("parents"), vec![])));
+
+    let mut recipes = RecipeSet::new();
+    recipes
+        .add(String::from("parents"), Recipe::new(PrimaryKey::String(String::from("id")), parent_ingredients))
+        .add(String::from("children"), Recipe::new(PrimaryKey::String(String::from("id")), child_ingredients));
+
+    recipes
+}
+
+pub fn row(fields: Vec<(&str, FieldValue)>) -> Row {
+    fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect()
+}
@@ -1,50 +1,127 @@
 extern crate serde_json;
 
 use contracts::*;
+use error::{Error, Result};
+use serde::Deserialize;
+use chrono::SecondsFormat;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use sha2::{Sha256, Digest};
 
 pub fn field_value_to_string(val: &FieldValue) -> String {
     match val {
-        &FieldValue::Null => "".to_string(),
-        &FieldValue::Int(v) => v.to_string(),
-        &FieldValue::String(ref s) => s.clone(),
+        FieldValue::Null => "".to_string(),
+        FieldValue::Int(v) => v.to_string(),
+        FieldValue::String(s) => s.clone(),
+        FieldValue::Bool(v) => v.to_string(),
+        FieldValue::Float(v) => v.to_string(),
+        FieldValue::Decimal(s) => s.clone(),
+        FieldValue::Binary(v) => BASE64.encode(v),
+        FieldValue::Timestamp(v) => v.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        FieldValue::Json(v) => v.to_string(),
     }
 }
 
+/// Read a value as an id, `None` if the value is null or can't be one
 pub fn field_value_to_id(val: &FieldValue) -> Option<Id> {
+    try_field_value_to_id(val).ok().and_then(|id| id)
+}
+
+/// Read a value as an id, `None` only if the value is null
+///
+/// Textual ids are classified by their format, see `Id::from_text`. Negative integers are
+/// refused, they are only ids of recipes declaring them, see `try_field_value_to_typed_id`.
+pub fn try_field_value_to_id(val: &FieldValue) -> Result<Option<Id>> {
+    match val {
+        FieldValue::Null => Ok(None),
+        FieldValue::Int(v) if *v < 0 => Err(Error::NegativeId(*v)),
+        FieldValue::Int(v) => Ok(Some(Id::Int(*v as u64))),
+        FieldValue::String(s) => Ok(Some(Id::from_text(s.clone()))),
+        // Integers beyond i64 arrive as decimals
+        FieldValue::Decimal(s) => s.parse::<u64>()
+            .map(|v| Some(Id::Int(v)))
+            .map_err(|_| Error::InvalidId(val.clone())),
+        _ => Err(Error::InvalidId(val.clone())),
+    }
+}
+
+/// Read a value as an id of the given kind, `None` only if the value is null
+pub fn try_field_value_to_typed_id(val: &FieldValue, kind: IdKind) -> Result<Option<Id>> {
+    match (kind, val) {
+        (IdKind::SignedInt, FieldValue::Int(v)) if *v < 0 => Ok(Some(Id::Signed(*v))),
+        _ => match try_field_value_to_id(val)? {
+            Some(ref id) if !kind.accepts(id) => Err(Error::WrongIdKind(kind, val.clone())),
+            id => Ok(id),
+        },
+    }
+}
+
+/// Read a value of a snapshot as an id, `None` only if the value is null
+///
+/// Snapshots hold the ids the books gave out, which are negative where a recipe declaring
+/// signed ids had them preserved.
+pub fn try_snapshot_value_to_id(val: &FieldValue) -> Result<Option<Id>> {
     match val {
-        &FieldValue::Null => None,
-        &FieldValue::Int(v) => Some(Id::Int(v as u64)),
-        &FieldValue::String(ref s) => Some(Id::Uuid(s.clone()))
+        FieldValue::Int(v) if *v < 0 => Ok(Some(Id::Signed(*v))),
+        _ => try_field_value_to_id(val),
     }
 }
 
 pub fn id_to_field_value(id: Id) -> FieldValue {
     match id {
+        Id::Int(v) if v > i64::MAX as u64 => FieldValue::Decimal(v.to_string()),
         Id::Int(v) => FieldValue::Int(v as i64),
-        Id::Uuid(s) => FieldValue::String(s.clone())
+        Id::Signed(v) => FieldValue::Int(v),
+        Id::Uuid(s) | Id::Ulid(s) | Id::String(s) => FieldValue::String(s),
+        // Composite ids are spread over their columns by `PrimaryKey::key_row`, this only shows them
+        Id::Composite(ids) => FieldValue::Json(serde_json::Value::Array(ids.into_iter()
+            .map(|id| field_value_to_serde_value(&id_to_field_value(id)))
+            .collect())),
     }
 }
 
-pub fn field_value_to_serde_value(val: &FieldValue) -> serde_json::Value {
-    match val {
-        &FieldValue::Null => serde_json::Value::Null,
-        &FieldValue::Int(v) => serde_json::Value::from(v),
-        &FieldValue::String(ref s) => serde_json::Value::String(s.clone()),
+/// Write an id of the given kind as a value, failing if the id isn't of that kind
+pub fn typed_id_to_field_value(id: Id, kind: IdKind) -> Result<FieldValue> {
+    if kind.accepts(&id) {
+        Ok(id_to_field_value(id))
+    } else {
+        Err(Error::WrongIdKind(kind, id_to_field_value(id)))
     }
 }
 
+/// A SHA-256 digest of the type and the fields of a row sorted by name, telling apart rows
+/// without a primary key by their content without holding on to it
+pub fn row_digest(etype: &EntityType, row: &Row) -> [u8; 32] {
+    let mut content: Vec<(&String, &FieldValue)> = row.iter().collect();
+    content.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut digest = Sha256::new();
+    digest.update(serde_json::to_vec(&(etype, content)).unwrap_or_default());
+
+    digest.finalize().into()
+}
+
+/// Convert into JSON, using the tagged form for values JSON can't hold as-is
+pub fn field_value_to_serde_value(val: &FieldValue) -> serde_json::Value {
+    serde_json::to_value(val).unwrap_or(serde_json::Value::Null)
+}
+
+/// Convert from JSON, the reverse of `field_value_to_serde_value`
+///
+/// Malformed tagged values are kept as plain JSON.
 pub fn serde_value_to_field_value(val: &serde_json::Value) -> FieldValue {
-    match val {
-        &serde_json::Value::Null => FieldValue::Null,
-        &serde_json::Value::Number(ref v) => FieldValue::Int(v.as_i64().unwrap_or(0 as i64)),
-        &serde_json::Value::String(ref s) => FieldValue::String(s.clone()),
-        _ => FieldValue::Null,
-    }
+    try_serde_value_to_field_value(val).unwrap_or_else(|_| FieldValue::Json(val.clone()))
+}
+
+/// Convert from JSON, failing on malformed tagged values
+pub fn try_serde_value_to_field_value(val: &serde_json::Value) -> Result<FieldValue> {
+    FieldValue::deserialize(val).map_err(|e| Error::InvalidValue(e.to_string()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::DateTime;
 
     #[test]
     fn field_value_to_id_converts_null_to_none() {
@@ -57,8 +134,16 @@ mod tests {
     }
 
     #[test]
-    fn field_value_to_id_converts_string_to_uuid() {
-        assert_eq!(Some(Id::Uuid(String::from("Foo"))), field_value_to_id(&FieldValue::String(String::from("Foo"))))
+    fn field_value_to_id_classifies_strings_by_format() {
+        // Ids are equal by value whatever their variant, so match the variant itself
+        assert!(matches!(
+            field_value_to_id(&FieldValue::String(String::from("Foo"))),
+            Some(Id::String(ref s)) if s == "Foo"
+        ));
+        assert!(matches!(
+            field_value_to_id(&FieldValue::String(String::from("67e55044-10b1-426f-9247-bb680e5fe0c8"))),
+            Some(Id::Uuid(ref s)) if s == "67e55044-10b1-426f-9247-bb680e5fe0c8"
+        ));
     }
 
     #[test]
@@ -100,4 +185,145 @@ mod tests {
     fn serde_value_to_field_value_converts_string_to_string() {
         assert_eq!(FieldValue::String(String::from("Foo")), serde_value_to_field_value(&serde_json::Value::String(String::from("Foo"))));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn field_value_to_string_converts_other_values() {
+        assert_eq!("true", field_value_to_string(&FieldValue::Bool(true)));
+        assert_eq!("1.5", field_value_to_string(&FieldValue::Float(1.5)));
+        assert_eq!("12.50", field_value_to_string(&FieldValue::Decimal(String::from("12.50"))));
+        assert_eq!("AQI=", field_value_to_string(&FieldValue::Binary(vec![1, 2])));
+        assert_eq!("[1]", field_value_to_string(&FieldValue::Json(serde_json::json!([1]))));
+    }
+
+    #[test]
+    fn field_value_to_id_converts_big_decimals_to_int() {
+        assert_eq!(Some(Id::Int(u64::MAX)), field_value_to_id(&FieldValue::Decimal(u64::MAX.to_string())));
+        assert_eq!(None, field_value_to_id(&FieldValue::Decimal(String::from("1.5"))));
+        assert_eq!(None, field_value_to_id(&FieldValue::Bool(true)));
+    }
+
+    #[test]
+    fn try_field_value_to_id_refuses_json() {
+        let value = FieldValue::Json(serde_json::json!([1, 2]));
+
+        assert_eq!(Err(Error::InvalidId(value.clone())), try_field_value_to_id(&value));
+    }
+
+    #[test]
+    fn row_digest_tells_rows_apart_by_content() {
+        let row = |fields: Vec<(&str, FieldValue)>| -> Row { fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect() };
+        let foos = String::from("foos");
+        let digest = row_digest(&foos, &row(vec![("a", FieldValue::Int(1)), ("b", FieldValue::Null)]));
+
+        assert_eq!(digest, row_digest(&foos, &row(vec![("b", FieldValue::Null), ("a", FieldValue::Int(1))])));
+        assert_ne!(digest, row_digest(&String::from("bars"), &row(vec![("a", FieldValue::Int(1)), ("b", FieldValue::Null)])));
+        assert_ne!(digest, row_digest(&foos, &row(vec![("a", FieldValue::String(String::from("1"))), ("b", FieldValue::Null)])));
+        assert_ne!(digest, row_digest(&foos, &row(vec![("a", FieldValue::Int(1))])));
+    }
+
+    #[test]
+    fn id_to_field_value_keeps_big_ints_exact() {
+        assert_eq!(FieldValue::Decimal(u64::MAX.to_string()), id_to_field_value(Id::Int(u64::MAX)));
+    }
+
+    #[test]
+    fn serde_value_conversions_are_lossless() {
+        let values = vec![
+            FieldValue::Null,
+            FieldValue::Int(-5),
+            FieldValue::String(String::from("Foo")),
+            FieldValue::Bool(false),
+            FieldValue::Float(1.5),
+            FieldValue::Float(f64::NAN),
+            FieldValue::Float(f64::NEG_INFINITY),
+            FieldValue::Decimal(String::from("12.50")),
+            FieldValue::Binary(vec![0, 255]),
+            FieldValue::Timestamp(DateTime::parse_from_rfc3339("2018-01-02T03:04:05.123+02:00").unwrap()),
+            FieldValue::Json(serde_json::json!({ "a": [1, null] })),
+        ];
+
+        for value in values {
+            assert_eq!(value, serde_value_to_field_value(&field_value_to_serde_value(&value)));
+        }
+    }
+
+    #[test]
+    fn serde_value_to_field_value_converts_other_json() {
+        assert_eq!(FieldValue::Float(1.5), serde_value_to_field_value(&serde_json::json!(1.5)));
+        assert_eq!(FieldValue::Bool(true), serde_value_to_field_value(&serde_json::json!(true)));
+        assert_eq!(FieldValue::Json(serde_json::json!({ "a": 1 })), serde_value_to_field_value(&serde_json::json!({ "a": 1 })));
+    }
+
+    #[test]
+    fn try_field_value_to_id_refuses_negative_ints() {
+        assert_eq!(Err(Error::NegativeId(-1)), try_field_value_to_id(&FieldValue::Int(-1)));
+        assert_eq!(None, field_value_to_id(&FieldValue::Int(-1)));
+    }
+
+    #[test]
+    fn try_field_value_to_typed_id_refuses_negative_ints_unless_signed() {
+        assert_eq!(Err(Error::NegativeId(-1)), try_field_value_to_typed_id(&FieldValue::Int(-1), IdKind::Int));
+        assert_eq!(Ok(Some(Id::Signed(-1))), try_field_value_to_typed_id(&FieldValue::Int(-1), IdKind::SignedInt));
+        assert_eq!(
+            Err(Error::WrongIdKind(IdKind::SignedInt, FieldValue::Decimal(u64::MAX.to_string()))),
+            try_field_value_to_typed_id(&FieldValue::Decimal(u64::MAX.to_string()), IdKind::SignedInt)
+        );
+    }
+
+    #[test]
+    fn textual_ids_are_classified_by_format() {
+        let uuid = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        let ulid = "01ARZ3NDEKTSV4RRFFQ69G5FAV";
+
+        assert_eq!(Some(Id::Uuid(uuid.to_string())), field_value_to_id(&FieldValue::String(uuid.to_string())));
+        assert_eq!(Some(Id::Ulid(ulid.to_string())), field_value_to_id(&FieldValue::String(ulid.to_string())));
+        assert_eq!(Some(Id::String(String::from("my-slug"))), field_value_to_id(&FieldValue::String(String::from("my-slug"))));
+        assert_eq!(Some(Id::String(uuid.replace('-', ""))), field_value_to_id(&FieldValue::String(uuid.replace('-', ""))));
+        assert_eq!(Some(Id::String(ulid.to_lowercase())), field_value_to_id(&FieldValue::String(ulid.to_lowercase())));
+    }
+
+    #[test]
+    fn typed_ids_round_trip() {
+        let values = vec![
+            (IdKind::Int, FieldValue::Int(1)),
+            (IdKind::Int, FieldValue::Decimal(u64::MAX.to_string())),
+            (IdKind::SignedInt, FieldValue::Int(i64::MIN)),
+            (IdKind::Uuid, FieldValue::String(String::from("67e55044-10b1-426f-9247-bb680e5fe0c8"))),
+            (IdKind::Ulid, FieldValue::String(String::from("01ARZ3NDEKTSV4RRFFQ69G5FAV"))),
+            (IdKind::String, FieldValue::String(String::from("my-slug"))),
+            (IdKind::String, FieldValue::String(String::from("01ARZ3NDEKTSV4RRFFQ69G5FAV"))),
+        ];
+
+        for (kind, value) in values {
+            let id = try_field_value_to_typed_id(&value, kind).unwrap().unwrap();
+
+            assert_eq!(Ok(value), typed_id_to_field_value(id, kind));
+        }
+    }
+
+    #[test]
+    fn typed_ids_refuse_other_kinds() {
+        let slug = FieldValue::String(String::from("my-slug"));
+
+        assert_eq!(Err(Error::WrongIdKind(IdKind::Uuid, slug.clone())), try_field_value_to_typed_id(&slug, IdKind::Uuid));
+        assert_eq!(Err(Error::WrongIdKind(IdKind::Ulid, slug.clone())), try_field_value_to_typed_id(&slug, IdKind::Ulid));
+        assert_eq!(Err(Error::WrongIdKind(IdKind::Int, slug.clone())), try_field_value_to_typed_id(&slug, IdKind::Int));
+        assert_eq!(Err(Error::WrongIdKind(IdKind::String, FieldValue::Int(1))), try_field_value_to_typed_id(&FieldValue::Int(1), IdKind::String));
+        assert_eq!(Err(Error::WrongIdKind(IdKind::Int, slug)), typed_id_to_field_value(Id::String(String::from("my-slug")), IdKind::Int));
+        assert_eq!(Ok(None), try_field_value_to_typed_id(&FieldValue::Null, IdKind::Uuid));
+    }
+
+    #[test]
+    fn try_field_value_to_id_tells_null_from_invalid() {
+        assert_eq!(Ok(None), try_field_value_to_id(&FieldValue::Null));
+        assert_eq!(Err(Error::InvalidId(FieldValue::Bool(true))), try_field_value_to_id(&FieldValue::Bool(true)));
+    }
+
+    #[test]
+    fn try_serde_value_to_field_value_refuses_malformed_tagged_values() {
+        let malformed = serde_json::json!({ "$timestamp": "yesterday" });
+
+        assert!(try_serde_value_to_field_value(&malformed).is_err());
+        assert_eq!(FieldValue::Json(malformed.clone()), serde_value_to_field_value(&malformed));
+    }
+}
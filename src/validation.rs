@@ -0,0 +1,385 @@
+use contracts::*;
+use recipe::{Recipe, RecipeSet, PrimaryKey, Ingredient};
+use ingredients::reference::Reference;
+use ingredients::morph::Morph;
+use ingredients::matcher::Matcher;
+use std::vec::Vec;
+use std::string::String;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum Severity {
+    /// The recipe will misbehave at runtime
+    #[serde(rename="error")]
+    Error,
+    /// The recipe works, but probably not as intended
+    #[serde(rename="warning")]
+    Warning,
+}
+
+/// A problem found in a recipe
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub entity: EntityType,
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+
+        match &self.field {
+            Some(field) => write!(f, "{}: {}.{}: {}", severity, self.entity, field, self.message),
+            None => write!(f, "{}: {}: {}", severity, self.entity, self.message),
+        }
+    }
+}
+
+impl Recipe {
+    /// Check the recipe for the given type on its own
+    ///
+    /// References to other types are not checked, see `RecipeSet::validate`. Neither are the
+    /// patterns of a `MATCH`, a recipe with a pattern that isn't a valid regex already fails to
+    /// load with an `Invalid pattern` error naming the field, as `Matcher::new` refuses it.
+    pub fn validate(&self, etype: &str) -> Vec<Diagnostic> {
+        Validator::new(etype, self, None).validate()
+    }
+}
+
+impl RecipeSet {
+    /// Check all recipes, including that every referenced type has a recipe
+    ///
+    /// Invalid `MATCH` patterns are refused when the recipes are loaded, see `Recipe::validate`.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        self.entity_types()
+            .into_iter()
+            .flat_map(|etype| Validator::new(etype, self.get(etype).unwrap(), Some(self)).validate())
+            .collect()
+    }
+}
+
+struct Validator<'a> {
+    etype: &'a str,
+    recipe: &'a Recipe,
+    recipes: Option<&'a RecipeSet>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Validator<'a> {
+    fn new(etype: &'a str, recipe: &'a Recipe, recipes: Option<&'a RecipeSet>) -> Validator<'a> {
+        Validator {
+            etype,
+            recipe,
+            recipes,
+            diagnostics: vec![],
+        }
+    }
+
+    fn validate(mut self) -> Vec<Diagnostic> {
+        match self.recipe.primary_key() {
+            PrimaryKey::String(pk) if pk.is_empty() => {
+                self.report(Severity::Error, None, String::from("The primary key is empty"));
+            },
+            PrimaryKey::String(pk) => self.key_column(pk),
+            PrimaryKey::Composite(pks) => self.composite_key(pks),
+            PrimaryKey::Null => {},
+        }
+
+        if let (Some(kind), PrimaryKey::Null) | (Some(kind), PrimaryKey::Composite(_)) = (self.recipe.id_kind(), self.recipe.primary_key()) {
+            self.report(Severity::Warning, None, format!("The id kind `{}` is ignored, as it only applies to single-column primary keys", kind));
+        }
+
+        let mut fields: Vec<&String> = self.recipe.ingredients().keys().collect();
+        fields.sort();
+
+        for field in fields {
+            self.ingredient(field, self.recipe.ingredient(field).unwrap());
+        }
+
+        self.diagnostics
+    }
+
+    /// Check that a primary key column is one of the ingredients
+    /// Key columns outside the ingredients still work, they are read from the row as they are
+    fn key_column(&mut self, pk: &str) {
+        if self.recipe.ingredient(pk).is_none() {
+            self.report(Severity::Warning, Some(pk), format!("The primary key `{}` is not one of the ingredients", pk));
+        }
+    }
+
+    fn composite_key(&mut self, pks: &[String]) {
+        if pks.is_empty() || pks.iter().any(|pk| pk.is_empty()) {
+            self.report(Severity::Error, None, String::from("The primary key is empty"));
+        }
+
+        for (i, pk) in pks.iter().enumerate() {
+            if pks[..i].contains(pk) {
+                self.report(Severity::Error, Some(pk), format!("The primary key lists `{}` more than once", pk));
+            } else if !pk.is_empty() {
+                self.key_column(pk);
+            }
+
+            if let Some(Ingredient::Circular(_)) = self.recipe.ingredient(pk) {
+                self.report(Severity::Error, Some(pk), format!("The key column `{}` can't be circular, as the key must be known on insert", pk));
+            }
+        }
+    }
+
+    fn ingredient(&mut self, field: &str, ingredient: &Ingredient) {
+        match ingredient {
+            Ingredient::Value(_) | Ingredient::Raw(_) => {},
+            Ingredient::Ref(r) => self.reference(field, r),
+            Ingredient::Morph(m) => self.morph(field, m),
+            Ingredient::Match(m) => self.matcher(field, m),
+            Ingredient::Circular(c) => {
+                self.ingredient(field, c.ingredient());
+                self.ingredient(field, c.fallback());
+            },
+        }
+    }
+
+    fn reference(&mut self, field: &str, reference: &Reference) {
+        self.entity_type(field, reference.entity_type());
+
+        for key_field in reference.key_fields() {
+            self.sibling(field, key_field, "key");
+        }
+
+        let columns = self.recipes
+            .and_then(|recipes| recipes.get(reference.entity_type()))
+            .map(|recipe| recipe.primary_key().columns().len());
+
+        match columns {
+            Some(columns) if columns > 0 && columns != reference.key_fields().len() + 1 => self.report(Severity::Error, Some(field), format!(
+                "Refers to `{}` keyed by {} column(s), but its key is held by {} field(s)",
+                reference.entity_type(), columns, reference.key_fields().len() + 1
+            )),
+            _ => {},
+        }
+    }
+
+    fn morph(&mut self, field: &str, morph: &Morph) {
+        self.sibling(field, morph.field(), "morph type");
+
+        let mut etypes = morph.entity_types();
+        etypes.sort();
+        etypes.dedup();
+
+        for etype in etypes {
+            self.entity_type(field, etype);
+        }
+    }
+
+    fn matcher(&mut self, field: &str, matcher: &Matcher) {
+        self.sibling(field, matcher.field(), "match");
+
+        if matcher.field() != matcher.config_field() {
+            self.report(Severity::Warning, Some(field), format!("Matches on `{}` but lists `{}` as required for updates", matcher.field(), matcher.config_field()));
+        }
+
+        for branch in matcher.branches() {
+            self.ingredient(field, branch);
+        }
+    }
+
+    /// Check that a field the ingredient reads from is part of the recipe
+    fn sibling(&mut self, field: &str, sibling: &str, what: &str) {
+        if self.recipe.ingredient(sibling).is_none() {
+            self.report(Severity::Error, Some(field), format!("The {} field `{}` is not in the recipe", what, sibling));
+        }
+    }
+
+    /// Check that a referenced type has a recipe with a primary key
+    fn entity_type(&mut self, field: &str, etype: &str) {
+        if let Some(recipes) = self.recipes {
+            match recipes.get(etype).map(|recipe| recipe.primary_key()) {
+                None => self.report(Severity::Error, Some(field), format!("Refers to `{}` which has no recipe", etype)),
+                Some(PrimaryKey::Null) => self.report(Severity::Error, Some(field), format!("Refers to `{}` which has no primary key", etype)),
+                Some(_) => {},
+            }
+        }
+    }
+
+    fn report(&mut self, severity: Severity, field: Option<&str>, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            entity: self.etype.to_string(),
+            field: field.map(|f| f.to_string()),
+            message,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+    use test_support;
+    use std::collections::HashMap;
+
+    fn recipes() -> RecipeSet {
+        serde_json::from_str(r#"{
+            "foos": {
+                "primary_key": "id",
+                "ingredients": {
+                    "id": { "type": "VALUE", "config": {} },
+                    "bar_id": { "type": "REF", "config": { "type": "bars" } },
+                    "qux_id": { "type": "CIRCULAR", "config": {
+                        "ingredient": { "type": "REF", "config": { "type": "quxes" } },
+                        "fallback": { "type": "RAW", "config": { "value": null } }
+                    } },
+                    "thing_id": { "type": "MORPH", "config": {
+                        "field": "thing_type",
+                        "morph_mapper": { "morph_map": { "BAR": "bars", "BAZ": "bazes" } }
+                    } }
+                }
+            },
+            "bars": {
+                "primary_key": "id",
+                "ingredients": {
+                    "id": { "type": "VALUE", "config": {} },
+                    "kind": { "type": "VALUE", "config": {} },
+                    "kind_id": { "type": "MATCH", "config": {
+                        "field": "kind",
+                        "matcher": {
+                            "field": "kind",
+                            "on": {},
+                            "patterns": {
+                                "^foo$": { "type": "REF", "config": { "type": "foos" } },
+                                "^nope$": { "type": "REF", "config": { "type": "nopes" } }
+                            },
+                            "default": null
+                        }
+                    } }
+                }
+            }
+        }"#).unwrap()
+    }
+
+    fn diagnostic(severity: Severity, entity: &str, field: &str, message: &str) -> Diagnostic {
+        Diagnostic {
+            severity,
+            entity: entity.to_string(),
+            field: Some(field.to_string()),
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn it_validates_a_recipe_on_its_own() {
+        let recipes = recipes();
+
+        assert_eq!(vec![
+            diagnostic(Severity::Error, "foos", "thing_id", "The morph type field `thing_type` is not in the recipe"),
+        ], recipes.get("foos").unwrap().validate("foos"));
+    }
+
+    #[test]
+    fn it_warns_of_primary_keys_outside_the_ingredients() {
+        let single: Recipe = serde_json::from_str(r#"{ "primary_key": "id", "ingredients": { "name": { "type": "VALUE", "config": {} } } }"#).unwrap();
+        let composite: Recipe = serde_json::from_str(r#"{ "primary_key": ["a", "b"], "ingredients": { "a": { "type": "VALUE", "config": {} } } }"#).unwrap();
+
+        assert_eq!(vec![
+            diagnostic(Severity::Warning, "foos", "id", "The primary key `id` is not one of the ingredients"),
+        ], single.validate("foos"));
+        assert_eq!(vec![
+            diagnostic(Severity::Warning, "pairs", "b", "The primary key `b` is not one of the ingredients"),
+        ], composite.validate("pairs"));
+        assert!(test_support::recipes().validate().iter().all(|d| d.severity == Severity::Warning));
+    }
+
+    #[test]
+    fn it_validates_recipes_against_each_other() {
+        let diagnostics = recipes().validate();
+
+        assert_eq!(vec![
+            diagnostic(Severity::Error, "bars", "kind_id", "Refers to `nopes` which has no recipe"),
+            diagnostic(Severity::Error, "foos", "qux_id", "Refers to `quxes` which has no recipe"),
+            diagnostic(Severity::Error, "foos", "thing_id", "The morph type field `thing_type` is not in the recipe"),
+            diagnostic(Severity::Error, "foos", "thing_id", "Refers to `bazes` which has no recipe"),
+        ], diagnostics);
+    }
+
+    #[test]
+    fn it_validates_composite_keys() {
+        let recipe: Recipe = serde_json::from_str(r#"{
+            "primary_key": ["user_id", "group_id", "user_id"],
+            "ingredients": {
+                "user_id": { "type": "REF", "config": { "type": "users" } },
+                "group_id": { "type": "CIRCULAR", "config": {
+                    "ingredient": { "type": "REF", "config": { "type": "groups" } },
+                    "fallback": { "type": "RAW", "config": { "value": null } }
+                } }
+            }
+        }"#).unwrap();
+
+        assert_eq!(vec![
+            diagnostic(Severity::Error, "members", "group_id", "The key column `group_id` can't be circular, as the key must be known on insert"),
+            diagnostic(Severity::Error, "members", "user_id", "The primary key lists `user_id` more than once"),
+        ], recipe.validate("members"));
+    }
+
+    #[test]
+    fn it_validates_references_to_rows_keyed_by_several_columns() {
+        let recipes: RecipeSet = serde_json::from_str(r#"{
+            "members": {
+                "primary_key": ["user_id", "group_id"],
+                "ingredients": { "user_id": { "type": "VALUE", "config": {} }, "group_id": { "type": "VALUE", "config": {} } }
+            },
+            "logs": {
+                "primary_key": "id",
+                "ingredients": {
+                    "id": { "type": "VALUE", "config": {} },
+                    "member_user_id": { "type": "REF", "config": { "type": "members", "key_fields": ["member_group_id"] } },
+                    "member_group_id": { "type": "VALUE", "config": {} },
+                    "other_user_id": { "type": "REF", "config": { "type": "members" } },
+                    "log_id": { "type": "REF", "config": { "type": "logs", "key_fields": ["member_group_id"] } },
+                    "third_user_id": { "type": "REF", "config": { "type": "members", "key_fields": ["third_group_id"] } }
+                }
+            }
+        }"#).unwrap();
+
+        assert_eq!(vec![
+            diagnostic(Severity::Error, "logs", "log_id", "Refers to `logs` keyed by 1 column(s), but its key is held by 2 field(s)"),
+            diagnostic(Severity::Error, "logs", "other_user_id", "Refers to `members` keyed by 2 column(s), but its key is held by 1 field(s)"),
+            diagnostic(Severity::Error, "logs", "third_user_id", "The key field `third_group_id` is not in the recipe"),
+        ], recipes.validate());
+    }
+
+    #[test]
+    fn it_refuses_references_to_keyless_recipes() {
+        let mut recipes = recipes();
+        recipes.add(String::from("quxes"), Recipe::new(PrimaryKey::Null, HashMap::new()));
+
+        assert!(recipes.validate().contains(&diagnostic(Severity::Error, "foos", "qux_id", "Refers to `quxes` which has no primary key")));
+    }
+
+    #[test]
+    fn it_warns_about_id_kinds_on_multi_column_keys() {
+        let recipe: Recipe = serde_json::from_str(r#"{
+            "primary_key": ["a", "b"],
+            "id_kind": "uuid",
+            "ingredients": { "a": { "type": "VALUE", "config": {} }, "b": { "type": "VALUE", "config": {} } }
+        }"#).unwrap();
+
+        assert_eq!(vec![Diagnostic {
+            severity: Severity::Warning,
+            entity: String::from("pairs"),
+            field: None,
+            message: String::from("The id kind `uuid` is ignored, as it only applies to single-column primary keys"),
+        }], recipe.validate("pairs"));
+    }
+
+    #[test]
+    fn it_formats_diagnostics() {
+        assert_eq!(
+            "error: foos.bar_id: Refers to `bars` which has no recipe",
+            diagnostic(Severity::Error, "foos", "bar_id", "Refers to `bars` which has no recipe").to_string()
+        );
+    }
+}